@@ -1,29 +1,42 @@
 use anyhow::Result;
-use image::RgbImage;
+use clap::Parser;
 use itertools::Itertools;
 use log::*;
 use opencv::{core, imgproc, prelude::*, types};
 use tokio::sync::{broadcast, mpsc, watch};
 use tokio::task::spawn_blocking;
-use tokio::task::JoinSet;
 
-use camera::run_camera;
+use camera::{run_camera, run_camera_source};
+use common::backend::{run_drive, CameraFrame};
+use common::config::Args;
 use common::init_log;
-use common::wait_tasks;
-use muskrat::run_muskrat;
+use common::Tasks;
 use muskrat::servo::run_servo;
-use proto::Odometry;
-use rc::run_rc;
+use muskrat::{run_arm, run_muskrat};
+use proto::{Odometry, ServoPosition};
+use rc::{run_rc, stream_codecs, Robot};
+use recorder::run_recorder;
 use ros::run_ros;
+use sim::{SyntheticCamera, UnicycleDriveBase, VirtualServo};
 use ws::run_ws;
 
 #[tokio::main]
 async fn main() -> Result<()> {
     init_log();
+    let config = Args::parse().load()?;
 
     let (set_raw_angle_tx, set_raw_angle_rx) = mpsc::channel::<f64>(1);
-    let (angle_tx, angle_rx) = watch::channel(2390.0);
-    let (camera_tx, mut camera_rx) = watch::channel(RgbImage::new(640, 480));
+    let initial_position = config.servo.clamp(config.servo.initial_position);
+    let (angle_tx, angle_rx) = watch::channel(initial_position);
+    let (servo_tx, servo_rx) = watch::channel(ServoPosition {
+        position: config.servo.clamp(config.servo.start_position),
+        target: initial_position,
+    });
+    let (camera_tx, mut camera_rx) = watch::channel(CameraFrame::blank(
+        config.camera.width,
+        config.camera.height,
+    ));
+    let (still_tx, still_rx) = mpsc::channel(1);
     let (button_tx, mut button_rx) = broadcast::channel(1);
 
     let (up_tx, _) = broadcast::channel(32);
@@ -35,28 +48,86 @@ async fn main() -> Result<()> {
         theta: 0.0,
     });
     let (velocity_tx, velocity_rx) = broadcast::channel(1);
+    let (failsafe_tx, failsafe_rx) = watch::channel(false);
 
-    let mut tasks = JoinSet::<Result<()>>::new();
-    tasks.spawn(run_radio(
-        "/dev/serial/by-path/platform-fd500000.pcie-pci-0000:01:00.0-usb-0:1.2:1.0-port0",
-        radio_up_rx,
-        radio_down_tx,
-    ));
-    tasks.spawn(run_muskrat(set_raw_angle_rx, button_tx));
-    tasks.spawn(run_servo(angle_rx, set_raw_angle_tx));
-    tasks.spawn(run_ws(up_tx.clone(), down_tx));
-    tasks.spawn(run_camera(camera_tx));
-    tasks.spawn(run_rc(
-        down_rx,
-        up_tx,
+    let mut tasks = Tasks::default();
+    if config.sim.enabled {
+        info!("using simulated hardware");
+        let camera = SyntheticCamera::new(&config.camera, &config.sim, odometry_tx.subscribe());
+        tasks.spawn("camera", run_camera_source(camera, camera_tx, still_rx));
+        tasks.spawn(
+            "arm",
+            run_arm(VirtualServo::new(&config.sim), set_raw_angle_rx, button_tx),
+        );
+    } else {
+        tasks.spawn("camera", run_camera(config.camera, camera_tx, still_rx));
+        tasks.spawn(
+            "muskrat",
+            run_muskrat(config.muskrat, set_raw_angle_rx, button_tx),
+        );
+    }
+    tasks.spawn(
+        "servo",
+        run_servo(config.servo.clone(), angle_rx, set_raw_angle_tx, servo_tx),
+    );
+    let codecs = stream_codecs(&config.video)?;
+    let (codecs_tx, codecs_rx) = watch::channel(Vec::new());
+    if config.record.enabled {
+        tasks.spawn(
+            "recorder",
+            run_recorder(
+                config.record,
+                codecs[0],
+                up_tx.subscribe(),
+                down_tx.subscribe(),
+            ),
+        );
+    }
+    tasks.spawn(
+        "ws",
+        run_ws(config.ws, codecs, codecs_tx, up_tx.clone(), down_tx),
+    );
+    let robot = Robot {
         angle_tx,
-        velocity_tx.clone(),
-        odometry_tx.subscribe(),
-        camera_rx.clone(),
-    ));
-    tasks.spawn(run_ros(odometry_tx, velocity_rx));
+        servo_rx,
+        velocity_tx: velocity_tx.clone(),
+        odometry_rx: odometry_tx.subscribe(),
+        camera_rx: camera_rx.clone(),
+        still_tx,
+        failsafe_rx,
+        tasks_rx: tasks.status(),
+    };
+    tasks.spawn(
+        "rc",
+        run_rc(
+            down_rx,
+            up_tx,
+            codecs_rx,
+            robot,
+            config.servo,
+            config.video,
+            config.uplink,
+            config.health,
+        ),
+    );
+    if config.sim.enabled {
+        tasks.spawn(
+            "drive",
+            run_drive(
+                UnicycleDriveBase::new(odometry_tx),
+                config.ros.command_timeout(),
+                velocity_rx,
+                failsafe_tx,
+            ),
+        );
+    } else {
+        tasks.spawn(
+            "ros",
+            run_ros(config.ros, odometry_tx, velocity_rx, failsafe_tx),
+        );
+    }
 
-    tasks.spawn(async move {
+    tasks.spawn("autopilot", async move {
         use AutopilotStage::*;
         info!("Started autopilot");
 
@@ -65,7 +136,7 @@ async fn main() -> Result<()> {
         let mut stage = Init;
         while camera_rx.changed().await.is_ok() {
             info!("Autopilot image received");
-            let img = (*camera_rx.borrow()).clone();
+            let img = camera_rx.borrow().image.clone();
             let (w, h) = (img.width() as i32, img.height() as i32);
             let img_vec = img.as_raw().clone();
             let mut vx = 0.0f32;
@@ -116,7 +187,7 @@ async fn main() -> Result<()> {
         Ok(())
     });
 
-    wait_tasks(tasks).await;
+    tasks.wait().await;
     Ok(())
 }
 
@@ -1,29 +1,115 @@
-use anyhow::Result;
+use anyhow::{Context, Result};
 use image::io::Reader as ImageReader;
 use image::RgbImage;
+use log::*;
+use rscam::ResolutionInfo;
 use std::io::Cursor;
-use tokio::sync::watch;
+use tokio::sync::{mpsc, watch};
 use tokio::task::spawn_blocking;
 
-pub async fn run_camera(camera_tx: watch::Sender<RgbImage>) -> Result<()> {
-    let mut camera = rscam::new("/dev/video0")?;
+use common::backend::{CameraFrame, CameraSource, StillRequest};
+use common::config::CameraConfig;
 
-    camera.start(&rscam::Config {
-        interval: (1, 30),
-        resolution: (640, 480),
-        format: b"MJPG",
-        ..Default::default()
-    })?;
+const FORMAT: &[u8] = b"MJPG";
+/// Frame rate asked for stills, which common cameras support at their full
+/// resolution.
+const STILL_FPS: u32 = 5;
 
-    spawn_blocking(move || loop {
-        camera.capture()?;
-        camera.capture()?;
-        let frame = camera.capture()?;
+pub struct V4lCamera {
+    camera: rscam::Camera,
+    /// Of the stream.
+    resolution: (u32, u32),
+    fps: u32,
+}
+
+impl V4lCamera {
+    pub fn open(config: &CameraConfig) -> Result<Self> {
+        let mut camera = Self {
+            camera: rscam::new(&config.device)?,
+            resolution: (config.width, config.height),
+            fps: config.fps,
+        };
+        camera.start(camera.resolution, camera.fps)?;
+        Ok(camera)
+    }
+
+    fn start(&mut self, resolution: (u32, u32), fps: u32) -> Result<()> {
+        self.camera.start(&rscam::Config {
+            interval: (1, fps),
+            resolution,
+            format: FORMAT,
+            ..Default::default()
+        })?;
+        Ok(())
+    }
+
+    fn full_resolution(&self) -> Result<(u32, u32)> {
+        let largest = match self.camera.resolutions(FORMAT)? {
+            ResolutionInfo::Discretes(sizes) => sizes.into_iter().max_by_key(|&(w, h)| w * h),
+            ResolutionInfo::Stepwise { max, .. } => Some(max),
+        };
+        largest.context("camera reports no resolutions")
+    }
+}
+
+impl CameraSource for V4lCamera {
+    /// The camera is switched to the full resolution once for all of them.
+    fn capture_stills(&mut self, count: usize) -> Result<Vec<CameraFrame>> {
+        let capture = |camera: &mut Self| -> Result<Vec<CameraFrame>> {
+            (0..count)
+                .map(|_| camera.capture().map(CameraFrame::captured_now))
+                .collect()
+        };
+        let full = self.full_resolution()?;
+        if full == self.resolution {
+            return capture(self);
+        }
+        info!(
+            "switching camera to {}x{} for {count} stills",
+            full.0, full.1
+        );
+        self.camera.stop()?;
+        let stills = self.start(full, STILL_FPS).and_then(|()| {
+            let stills = capture(self);
+            self.camera.stop()?;
+            stills
+        });
+        self.start(self.resolution, self.fps)?;
+        stills
+    }
+
+    fn capture(&mut self) -> Result<RgbImage> {
+        self.camera.capture()?;
+        self.camera.capture()?;
+        let frame = self.camera.capture()?;
         let decoded_frame = ImageReader::new(Cursor::new(&frame[..]))
             .with_guessed_format()?
             .decode()?
             .into_rgb8();
-        let _ = camera_tx.send(decoded_frame);
+        Ok(decoded_frame)
+    }
+}
+
+pub async fn run_camera(
+    config: CameraConfig,
+    camera_tx: watch::Sender<CameraFrame>,
+    still_rx: mpsc::Receiver<StillRequest>,
+) -> Result<()> {
+    run_camera_source(V4lCamera::open(&config)?, camera_tx, still_rx).await
+}
+
+/// Streams frames to `camera_tx`, taking stills in between when asked.
+pub async fn run_camera_source(
+    mut source: impl CameraSource,
+    camera_tx: watch::Sender<CameraFrame>,
+    mut still_rx: mpsc::Receiver<StillRequest>,
+) -> Result<()> {
+    spawn_blocking(move || loop {
+        if let Ok(StillRequest { count, reply_tx }) = still_rx.try_recv() {
+            let _ = reply_tx.send(source.capture_stills(count));
+        }
+        let frame = CameraFrame::captured_now(source.capture()?);
+        let _ = camera_tx.send(frame);
     })
     .await?
 }
@@ -0,0 +1,212 @@
+//! Interfaces to the robot hardware, implemented both by the real drivers
+//! and by the simulator in the `sim` crate.
+
+use anyhow::Result;
+use image::RgbImage;
+use log::*;
+use std::future::Future;
+use tokio::sync::{broadcast, oneshot, watch};
+use tokio::time::{interval, Duration, Instant};
+
+use proto::{now_millis, Velocity};
+
+/// Camera picture stamped with the time it was captured.
+#[derive(Clone)]
+pub struct CameraFrame {
+    pub image: RgbImage,
+    /// Milliseconds since unix epoch, see [`proto::now_millis`].
+    pub captured_at: u64,
+}
+
+impl CameraFrame {
+    /// Black picture to start with before the camera delivers anything.
+    pub fn blank(width: u32, height: u32) -> Self {
+        Self {
+            image: RgbImage::new(width, height),
+            captured_at: 0,
+        }
+    }
+
+    /// Stamped now, just after [`CameraSource::capture`] returned. Also counts
+    /// JPEG decoding, which takes a few ms on the Raspberry Pi.
+    pub fn captured_now(image: RgbImage) -> Self {
+        Self {
+            image,
+            captured_at: now_millis(),
+        }
+    }
+}
+
+/// Asks the camera for `count` pictures at its full resolution.
+pub struct StillRequest {
+    pub count: usize,
+    pub reply_tx: oneshot::Sender<Result<Vec<CameraFrame>>>,
+}
+
+pub trait CameraSource: Send + 'static {
+    /// Blocks until the next frame is available.
+    fn capture(&mut self) -> Result<RgbImage>;
+
+    /// Pictures at the largest resolution the camera supports, each from a
+    /// new frame. The stream must continue at its own resolution afterwards.
+    fn capture_stills(&mut self, count: usize) -> Result<Vec<CameraFrame>> {
+        (0..count)
+            .map(|_| self.capture().map(CameraFrame::captured_now))
+            .collect()
+    }
+}
+
+/// Motors and odometry. Odometry is published by the implementation itself,
+/// usually to a channel passed to its constructor.
+pub trait DriveBase: Send + 'static {
+    /// Applies velocity, called at [`DRIVE_RATE`]. Must not block.
+    fn drive(&mut self, velocity: &Velocity) -> impl Future<Output = Result<()>> + Send;
+}
+
+/// Claw servo and the start button.
+pub trait ArmActuator: Send + 'static {
+    /// Sets servo pulse width in microseconds.
+    fn set_raw_angle(&mut self, angle: f64) -> impl Future<Output = Result<()>> + Send;
+
+    /// Resolves on every button press. Must be cancel safe.
+    fn button(&mut self) -> impl Future<Output = Result<()>> + Send;
+}
+
+/// How often velocity is sent to the drive base, Hz.
+pub const DRIVE_RATE: f64 = 10.0;
+/// Deceleration used to stop the robot when commands stop coming, m/s^2.
+const LINEAR_DECEL: f64 = 0.1;
+/// Same for rotation, rad/s^2.
+const ANGULAR_DECEL: f64 = 0.2;
+
+/// Feeds velocities from `velocity_rx` to the drive base.
+///
+/// If no velocity arrives for `command_timeout` the robot is smoothly
+/// stopped and `failsafe_tx` is set until the next command. Errors of the
+/// drive base are logged and don't stop the loop, so that the next ticks
+/// can still stop the robot.
+pub async fn run_drive(
+    mut base: impl DriveBase,
+    command_timeout: Duration,
+    mut velocity_rx: broadcast::Receiver<Velocity>,
+    failsafe_tx: watch::Sender<bool>,
+) -> Result<()> {
+    let mut rate = interval(Duration::from_secs_f64(1.0 / DRIVE_RATE));
+    let mut velocity = Velocity {
+        linear: 0.0,
+        angular: 0.0,
+    };
+    let mut last_command = Instant::now();
+    let mut tripped = false;
+    let mut failing = false;
+    loop {
+        tokio::select! {
+            v = velocity_rx.recv() => {
+                match v {
+                    Ok(v) => velocity = v,
+                    Err(broadcast::error::RecvError::Lagged(l)) => {
+                        warn!("lagged for {l} velocity");
+                        continue;
+                    }
+                    Err(broadcast::error::RecvError::Closed) => return Ok(()),
+                }
+                last_command = Instant::now();
+                if tripped {
+                    info!("velocity commands resumed");
+                    tripped = false;
+                    let _ = failsafe_tx.send(false);
+                }
+            }
+            _ = rate.tick() => {
+                if last_command.elapsed() > command_timeout
+                    && (velocity.linear != 0.0 || velocity.angular != 0.0)
+                {
+                    if !tripped {
+                        warn!("no velocity command for {command_timeout:?}, stopping");
+                        tripped = true;
+                        let _ = failsafe_tx.send(true);
+                    }
+                    velocity.linear = approach_zero(velocity.linear, LINEAR_DECEL / DRIVE_RATE);
+                    velocity.angular = approach_zero(velocity.angular, ANGULAR_DECEL / DRIVE_RATE);
+                }
+                match base.drive(&velocity).await {
+                    Ok(()) if failing => {
+                        info!("drive base works again");
+                        failing = false;
+                    }
+                    Ok(()) => {}
+                    Err(e) if !failing => {
+                        error!("drive base failed: {e:#}");
+                        failing = true;
+                    }
+                    Err(_) => {}
+                }
+            }
+        }
+    }
+}
+
+fn approach_zero(value: f64, step: f64) -> f64 {
+    if value.abs() <= step {
+        0.0
+    } else {
+        value - step * value.signum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::bail;
+    use std::sync::{Arc, Mutex};
+
+    /// Fails every other call, remembers the linear velocities it applied.
+    struct FlakyBase {
+        calls: u32,
+        driven: Arc<Mutex<Vec<f64>>>,
+    }
+
+    impl DriveBase for FlakyBase {
+        async fn drive(&mut self, velocity: &Velocity) -> Result<()> {
+            self.calls += 1;
+            if self.calls.is_multiple_of(2) {
+                bail!("bus error");
+            }
+            self.driven.lock().unwrap().push(velocity.linear);
+            Ok(())
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn failsafe_stops_through_drive_errors() {
+        let (velocity_tx, velocity_rx) = broadcast::channel(1);
+        let (failsafe_tx, failsafe_rx) = watch::channel(false);
+        let driven = Arc::new(Mutex::new(Vec::new()));
+        let base = FlakyBase {
+            calls: 0,
+            driven: driven.clone(),
+        };
+        let drive = tokio::spawn(run_drive(
+            base,
+            Duration::from_millis(300),
+            velocity_rx,
+            failsafe_tx,
+        ));
+        velocity_tx
+            .send(Velocity {
+                linear: 0.05,
+                angular: 0.0,
+            })
+            .unwrap();
+        tokio::time::sleep(Duration::from_secs(3)).await;
+
+        assert!(!drive.is_finished());
+        assert!(*failsafe_rx.borrow());
+        let driven = driven.lock().unwrap();
+        let moving = driven.iter().position(|v| *v > 0.0).unwrap();
+        assert!(driven[moving..].windows(2).all(|v| v[1] <= v[0]));
+        assert!(driven[moving..].iter().any(|v| *v > 0.0 && *v < 0.05));
+        assert_eq!(driven.last(), Some(&0.0));
+        drive.abort();
+    }
+}
@@ -0,0 +1,742 @@
+use anyhow::{bail, Context, Result};
+use clap::Parser;
+use log::*;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::io::ErrorKind;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use proto::VideoCodec;
+
+/// Robot configuration. Every field has a default matching the competition
+/// robot, so the config file only needs to contain what differs.
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub muskrat: MuskratConfig,
+    pub camera: CameraConfig,
+    pub ws: WsConfig,
+    pub ros: RosConfig,
+    pub servo: ServoConfig,
+    pub sim: SimConfig,
+    pub video: VideoConfig,
+    pub record: RecordConfig,
+    pub uplink: UplinkConfig,
+    pub health: HealthConfig,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct MuskratConfig {
+    pub port: String,
+    pub baud_rate: u32,
+}
+
+impl Default for MuskratConfig {
+    fn default() -> Self {
+        Self {
+            port:
+                "/dev/serial/by-path/platform-fd500000.pcie-pci-0000:01:00.0-usb-0:1.4.1:1.0-port0"
+                    .to_string(),
+            baud_rate: 115200,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct CameraConfig {
+    pub device: String,
+    pub width: u32,
+    pub height: u32,
+    pub fps: u32,
+}
+
+impl Default for CameraConfig {
+    fn default() -> Self {
+        Self {
+            device: "/dev/video0".to_string(),
+            width: 640,
+            height: 480,
+            fps: 30,
+        }
+    }
+}
+
+impl CameraConfig {
+    /// Frames are timed by `fps` and encoders can't take empty pictures.
+    pub fn check(&self) -> Result<()> {
+        if self.fps == 0 {
+            bail!("fps must be above 0");
+        }
+        if self.width == 0 || self.height == 0 {
+            bail!("{}x{} is no picture size", self.width, self.height);
+        }
+        Ok(())
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct WsConfig {
+    pub bind: SocketAddr,
+    /// Clients must send it as a bearer token. Anyone can connect if unset.
+    pub token: Option<String>,
+    /// Serve over TLS when set.
+    pub tls: Option<TlsConfig>,
+}
+
+impl Default for WsConfig {
+    fn default() -> Self {
+        Self {
+            bind: SocketAddr::from(([0, 0, 0, 0], 8264)),
+            token: None,
+            tls: None,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct TlsConfig {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+    /// Host names and addresses put into the self-signed certificate, which
+    /// is generated when `cert` doesn't exist.
+    pub names: Vec<String>,
+}
+
+impl Default for TlsConfig {
+    fn default() -> Self {
+        Self {
+            cert: PathBuf::from("capybara.crt"),
+            key: PathBuf::from("capybara.key"),
+            names: vec!["localhost".to_string(), "127.0.0.1".to_string()],
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct RosConfig {
+    pub node_name: String,
+    pub cmd_vel_topic: String,
+    pub odometry_topic: String,
+    /// Robot is stopped if no velocity command arrives for this long.
+    pub command_timeout_ms: u64,
+}
+
+impl RosConfig {
+    pub fn command_timeout(&self) -> Duration {
+        Duration::from_millis(self.command_timeout_ms)
+    }
+}
+
+impl Default for RosConfig {
+    fn default() -> Self {
+        Self {
+            node_name: "capybara".to_string(),
+            cmd_vel_topic: "cmd_vel".to_string(),
+            odometry_topic: "odom_pose2d".to_string(),
+            command_timeout_ms: 500,
+        }
+    }
+}
+
+/// Claw servo. Positions are normalized through the calibration, 0 is open
+/// and 1 is closed.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServoConfig {
+    /// Written by `rchost calibrate`, defaults are used if it doesn't exist.
+    pub calibration_file: PathBuf,
+    #[serde(skip)]
+    pub calibration: ServoCalibration,
+    /// Where the servo is driven on startup, before it moves to
+    /// `initial_position`.
+    pub start_position: f64,
+    pub initial_position: f64,
+    /// Travel from open to closed per second.
+    pub max_speed: f64,
+    /// Per second squared, 0 starts and stops at full speed.
+    pub acceleration: f64,
+    /// Named positions clients can move to.
+    pub presets: BTreeMap<String, f64>,
+}
+
+impl ServoConfig {
+    /// Limits the position to the calibrated range.
+    pub fn clamp(&self, position: f64) -> f64 {
+        let (min, max) = self.calibration.range();
+        position.clamp(min, max)
+    }
+
+    pub fn preset(&self, name: &str) -> Option<f64> {
+        self.presets.get(name).map(|&position| self.clamp(position))
+    }
+
+    /// The motion profile can't move at zero speed or brake with a negative
+    /// acceleration.
+    pub fn check(&self) -> Result<()> {
+        self.calibration.check()?;
+        if !(self.max_speed.is_finite() && self.max_speed > 0.0) {
+            bail!("max_speed {} must be above 0", self.max_speed);
+        }
+        if !(self.acceleration.is_finite() && self.acceleration >= 0.0) {
+            bail!("acceleration {} must not be negative", self.acceleration);
+        }
+        let positions = [
+            ("start_position", self.start_position),
+            ("initial_position", self.initial_position),
+        ];
+        let presets = self.presets.iter().map(|(name, &p)| (name.as_str(), p));
+        for (name, position) in positions.into_iter().chain(presets) {
+            if !position.is_finite() {
+                bail!("{name} {position} is not a position");
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Default for ServoConfig {
+    fn default() -> Self {
+        Self {
+            calibration_file: PathBuf::from("servo-calibration.toml"),
+            calibration: ServoCalibration::default(),
+            start_position: 0.5,
+            initial_position: 0.45,
+            max_speed: 0.25,
+            acceleration: 0.5,
+            presets: BTreeMap::from([("open".to_string(), 0.0), ("closed".to_string(), 1.0)]),
+        }
+    }
+}
+
+/// Servo pulse widths in microseconds, found with `rchost calibrate`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct ServoCalibration {
+    pub open: f64,
+    pub closed: f64,
+    /// The servo is never moved outside these.
+    pub min: f64,
+    pub max: f64,
+}
+
+impl ServoCalibration {
+    /// Reads the calibration, falling back to the defaults if the file is
+    /// missing.
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = match std::fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                warn!("no servo calibration {}, using defaults", path.display());
+                return Ok(Self::default());
+            }
+            Err(e) => {
+                return Err(e)
+                    .with_context(|| format!("can't read servo calibration {}", path.display()))
+            }
+        };
+        let calibration: Self = toml::from_str(&text)
+            .with_context(|| format!("invalid servo calibration {}", path.display()))?;
+        calibration
+            .check()
+            .with_context(|| format!("invalid servo calibration {}", path.display()))?;
+        Ok(calibration)
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        self.check()?;
+        let text = format!(
+            "# Written by `rchost calibrate`, pulse widths in microseconds\n{}",
+            toml::to_string(self)?
+        );
+        std::fs::write(path, text)
+            .with_context(|| format!("can't write servo calibration {}", path.display()))
+    }
+
+    pub fn check(&self) -> Result<()> {
+        for (name, value) in [
+            ("open", self.open),
+            ("closed", self.closed),
+            ("min", self.min),
+            ("max", self.max),
+        ] {
+            if !value.is_finite() {
+                bail!("{name} {value} is not a pulse width");
+            }
+        }
+        if self.open == self.closed {
+            bail!("open and closed are both {}", self.open);
+        }
+        if self.min > self.max {
+            bail!("min {} is above max {}", self.min, self.max);
+        }
+        for (name, value) in [("open", self.open), ("closed", self.closed)] {
+            if value < self.min || value > self.max {
+                bail!("{name} {value} is outside {}..{}", self.min, self.max);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn to_raw(&self, position: f64) -> f64 {
+        self.open + position * (self.closed - self.open)
+    }
+
+    pub fn to_position(&self, raw: f64) -> f64 {
+        (raw - self.open) / (self.closed - self.open)
+    }
+
+    /// Normalized positions of the limits, lowest first.
+    pub fn range(&self) -> (f64, f64) {
+        let (a, b) = (self.to_position(self.min), self.to_position(self.max));
+        (a.min(b), a.max(b))
+    }
+}
+
+impl Default for ServoCalibration {
+    fn default() -> Self {
+        Self {
+            open: 2300.0,
+            closed: 2500.0,
+            min: 2300.0,
+            max: 2500.0,
+        }
+    }
+}
+
+/// Simulated hardware, lets rchost and autopilot run without the robot.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct SimConfig {
+    pub enabled: bool,
+    /// Position of the coloured target rendered by the fake camera, m.
+    pub target_x: f64,
+    pub target_y: f64,
+    /// Press the start button this long after start.
+    pub button_delay_ms: Option<u64>,
+}
+
+impl Default for SimConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            target_x: 1.0,
+            target_y: 0.2,
+            button_delay_ms: Some(1000),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct VideoConfig {
+    /// Follow link stats reported by control, otherwise stay at `level`.
+    pub adaptive: bool,
+    /// Starting quality level, 0 is the lowest. Level 1 fits the radio.
+    pub level: usize,
+    /// Stream codec, the best one built in if unset.
+    pub codec: Option<VideoCodec>,
+}
+
+impl Default for VideoConfig {
+    fn default() -> Self {
+        Self {
+            adaptive: true,
+            level: 1,
+            codec: None,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct RecordConfig {
+    /// Record every packet to and from the clients for replaying later.
+    pub enabled: bool,
+    /// A new file is created there for every run.
+    pub dir: PathBuf,
+}
+
+impl Default for RecordConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            dir: PathBuf::from("recordings"),
+        }
+    }
+}
+
+/// Scheduling of what the robot sends, so that video can't crowd out
+/// telemetry on the radio. Streams are sent in the order of the fields, after
+/// their shares of the link.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct UplinkConfig {
+    /// Bytes per second for all streams together, a bit below what the link
+    /// manages. The default of 2 Mbit/s suits Wi-Fi and the simulator, the
+    /// radio needs about 5000.
+    pub rate: u32,
+    pub telemetry: StreamConfig,
+    pub acks: StreamConfig,
+    pub video: StreamConfig,
+    pub photos: StreamConfig,
+}
+
+impl Default for UplinkConfig {
+    fn default() -> Self {
+        Self {
+            rate: 250_000,
+            telemetry: StreamConfig {
+                budget: None,
+                share: None,
+                queue: 8,
+                drop: DropPolicy::Oldest,
+            },
+            // Control retransmits commands that weren't answered
+            acks: StreamConfig {
+                budget: None,
+                share: None,
+                queue: 64,
+                drop: DropPolicy::Newest,
+            },
+            // The decoder asks for a keyframe when frames go missing
+            video: StreamConfig {
+                budget: None,
+                share: None,
+                queue: 8,
+                drop: DropPolicy::Oldest,
+            },
+            // Chunks are resent until acked. Video alone can fill the link, so
+            // photos get a share of it
+            photos: StreamConfig {
+                budget: None,
+                share: Some(0.2),
+                queue: 8,
+                drop: DropPolicy::Newest,
+            },
+        }
+    }
+}
+
+impl UplinkConfig {
+    /// Nothing would ever be sent with a zero rate, budget or queue, and
+    /// shares can't add up to more than the link.
+    pub fn check(&self) -> Result<()> {
+        if self.rate == 0 {
+            bail!("rate must be above 0");
+        }
+        let mut shares = 0.0;
+        for (name, stream) in [
+            ("telemetry", &self.telemetry),
+            ("acks", &self.acks),
+            ("video", &self.video),
+            ("photos", &self.photos),
+        ] {
+            if let Some(share) = stream.share {
+                if !(share.is_finite() && share > 0.0 && share <= 1.0) {
+                    bail!("{name} share must be above 0 and at most 1");
+                }
+                shares += share;
+            }
+            if stream.budget == Some(0) {
+                bail!("{name} budget must be above 0, leave it out for no limit");
+            }
+            if stream.queue == 0 {
+                bail!("{name} queue must hold at least 1 packet");
+            }
+        }
+        if shares > 1.0 {
+            bail!("shares add up to {shares}, more than the whole link");
+        }
+        Ok(())
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct StreamConfig {
+    /// Bytes per second the stream may use even when the link is idle,
+    /// unlimited if unset.
+    pub budget: Option<u32>,
+    /// Part of `rate` the stream gets ahead of higher priorities while it has
+    /// packets waiting, so that they can't starve it. Within its budget.
+    pub share: Option<f64>,
+    /// Packets waiting to be sent, `drop` decides what goes when it's full.
+    pub queue: usize,
+    pub drop: DropPolicy,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum DropPolicy {
+    /// Keeps the freshest packets.
+    Oldest,
+    /// Keeps the queue in order, new packets are refused.
+    Newest,
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct HealthConfig {
+    /// Power supply in /sys/class/power_supply that reports the battery
+    /// voltage, like "BAT0". No voltage is reported if unset.
+    pub battery: Option<String>,
+}
+
+/// Command line arguments, each can also be set with an environment variable.
+/// They override values from the config file.
+#[derive(Parser, Debug)]
+pub struct Args {
+    /// Path to TOML config file
+    #[arg(short, long, env = "CAPYBARA_CONFIG")]
+    pub config: Option<PathBuf>,
+    /// Serial port of the Arduino
+    #[arg(long, env = "CAPYBARA_MUSKRAT_PORT")]
+    pub muskrat_port: Option<String>,
+    /// V4L camera device
+    #[arg(long, env = "CAPYBARA_CAMERA_DEVICE")]
+    pub camera_device: Option<String>,
+    /// Websocket server address
+    #[arg(long, env = "CAPYBARA_WS_BIND")]
+    pub ws_bind: Option<SocketAddr>,
+    /// Token websocket clients must authenticate with
+    #[arg(long, env = "CAPYBARA_WS_TOKEN", hide_env_values = true)]
+    pub ws_token: Option<String>,
+    /// Topic to publish velocity commands to
+    #[arg(long, env = "CAPYBARA_CMD_VEL_TOPIC")]
+    pub cmd_vel_topic: Option<String>,
+    /// Topic to read Pose2D odometry from
+    #[arg(long, env = "CAPYBARA_ODOMETRY_TOPIC")]
+    pub odometry_topic: Option<String>,
+    /// Use simulated camera, drive and arm instead of the hardware
+    #[arg(long, env = "CAPYBARA_SIM")]
+    pub sim: bool,
+    /// Record the session, see the replay tool
+    #[arg(long, env = "CAPYBARA_RECORD")]
+    pub record: bool,
+    /// Bytes per second the link to the clients can carry
+    #[arg(long, env = "CAPYBARA_UPLINK_RATE")]
+    pub uplink_rate: Option<u32>,
+}
+
+impl Args {
+    /// Loads the config file (if any) and the servo calibration, with the
+    /// overrides applied.
+    pub fn load(self) -> Result<Config> {
+        let mut config = self.load_without_calibration()?;
+        config.servo.calibration = ServoCalibration::load(&config.servo.calibration_file)?;
+        config.camera.check().context("invalid camera config")?;
+        config.servo.check().context("invalid servo config")?;
+        config.uplink.check().context("invalid uplink config")?;
+        Ok(config)
+    }
+
+    /// Same as [`Args::load`], but leaves the default calibration, for
+    /// `rchost calibrate` which makes a new one.
+    pub fn load_without_calibration(self) -> Result<Config> {
+        let mut config = match &self.config {
+            Some(path) => {
+                let text = std::fs::read_to_string(path)
+                    .with_context(|| format!("can't read config {}", path.display()))?;
+                toml::from_str(&text)
+                    .with_context(|| format!("invalid config {}", path.display()))?
+            }
+            None => Config::default(),
+        };
+        self.apply(&mut config);
+        Ok(config)
+    }
+
+    fn apply(self, config: &mut Config) {
+        if let Some(port) = self.muskrat_port {
+            config.muskrat.port = port;
+        }
+        if let Some(device) = self.camera_device {
+            config.camera.device = device;
+        }
+        if let Some(bind) = self.ws_bind {
+            config.ws.bind = bind;
+        }
+        if let Some(token) = self.ws_token {
+            config.ws.token = Some(token);
+        }
+        if let Some(topic) = self.cmd_vel_topic {
+            config.ros.cmd_vel_topic = topic;
+        }
+        if let Some(topic) = self.odometry_topic {
+            config.ros.odometry_topic = topic;
+        }
+        if self.sim {
+            config.sim.enabled = true;
+        }
+        if self.record {
+            config.record.enabled = true;
+        }
+        if let Some(rate) = self.uplink_rate {
+            config.uplink.rate = rate;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Closing means shorter pulses on some servos.
+    fn reversed() -> ServoCalibration {
+        ServoCalibration {
+            open: 2000.0,
+            closed: 1000.0,
+            min: 900.0,
+            max: 2100.0,
+        }
+    }
+
+    #[test]
+    fn positions() {
+        let calibration = ServoCalibration::default();
+        assert_eq!(calibration.to_raw(0.0), 2300.0);
+        assert_eq!(calibration.to_raw(1.0), 2500.0);
+        assert_eq!(calibration.to_position(2400.0), 0.5);
+
+        let calibration = reversed();
+        assert_eq!(calibration.to_raw(0.25), 1750.0);
+        assert_eq!(calibration.to_position(1750.0), 0.25);
+    }
+
+    #[test]
+    fn range() {
+        assert_eq!(ServoCalibration::default().range(), (0.0, 1.0));
+        let (min, max) = reversed().range();
+        assert!((min + 0.1).abs() < 1e-9);
+        assert!((max - 1.1).abs() < 1e-9);
+    }
+
+    #[test]
+    fn check() {
+        assert!(ServoCalibration::default().check().is_ok());
+        assert!(reversed().check().is_ok());
+
+        let bad = [
+            ServoCalibration {
+                closed: 2000.0,
+                ..reversed()
+            },
+            ServoCalibration {
+                min: 2200.0,
+                ..reversed()
+            },
+            ServoCalibration {
+                max: 1500.0,
+                ..reversed()
+            },
+            ServoCalibration {
+                open: f64::NAN,
+                ..reversed()
+            },
+            ServoCalibration {
+                max: f64::INFINITY,
+                ..reversed()
+            },
+        ];
+        for calibration in bad {
+            assert!(calibration.check().is_err(), "{calibration:?}");
+        }
+    }
+
+    #[test]
+    fn servo_config() {
+        assert!(ServoConfig::default().check().is_ok());
+        let bad = [
+            ServoConfig {
+                max_speed: 0.0,
+                ..Default::default()
+            },
+            ServoConfig {
+                acceleration: -1.0,
+                ..Default::default()
+            },
+            ServoConfig {
+                initial_position: f64::NAN,
+                ..Default::default()
+            },
+        ];
+        for config in bad {
+            assert!(config.check().is_err(), "{config:?}");
+        }
+    }
+
+    #[test]
+    fn camera_config() {
+        let config = CameraConfig::default();
+        assert!(config.check().is_ok());
+        let bad = [
+            CameraConfig {
+                fps: 0,
+                ..config.clone()
+            },
+            CameraConfig {
+                height: 0,
+                ..config
+            },
+        ];
+        for config in bad {
+            assert!(config.check().is_err(), "{config:?}");
+        }
+    }
+
+    #[test]
+    fn uplink_config() {
+        let config = UplinkConfig::default();
+        assert!(config.check().is_ok());
+        let stream = StreamConfig {
+            budget: None,
+            share: None,
+            queue: 8,
+            drop: DropPolicy::Oldest,
+        };
+        let bad = [
+            UplinkConfig {
+                rate: 0,
+                ..config.clone()
+            },
+            UplinkConfig {
+                photos: StreamConfig {
+                    budget: Some(0),
+                    ..stream.clone()
+                },
+                ..config.clone()
+            },
+            UplinkConfig {
+                video: StreamConfig {
+                    queue: 0,
+                    ..stream.clone()
+                },
+                ..config.clone()
+            },
+            UplinkConfig {
+                video: StreamConfig {
+                    share: Some(f64::NAN),
+                    ..stream.clone()
+                },
+                ..config.clone()
+            },
+            UplinkConfig {
+                video: StreamConfig {
+                    share: Some(0.9),
+                    ..stream
+                },
+                ..config
+            },
+        ];
+        for config in bad {
+            assert!(config.check().is_err(), "{config:?}");
+        }
+    }
+}
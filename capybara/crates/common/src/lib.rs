@@ -1,10 +1,14 @@
 use anyhow::bail;
 use anyhow::Result;
 use log::*;
+use std::future::Future;
 use tokio::sync::{broadcast, watch};
-use tokio::task::JoinSet;
+use tokio::task::{spawn, AbortHandle, JoinSet};
 
-use proto;
+use proto::TaskStatus;
+
+pub mod backend;
+pub mod config;
 
 pub const VIDEO_WIDTH: u32 = 320;
 pub const VIDEO_HEIGHT: u32 = 240;
@@ -14,11 +18,84 @@ pub fn init_log() {
     log_panics::init();
 }
 
-pub async fn wait_tasks(mut tasks: JoinSet<Result<()>>) {
-    while let Some(res) = tasks.join_next().await {
-        match res {
-            Ok(_) => error!("task exited"),
-            Err(e) => error!("task failed: {e}"),
+/// Tasks that are meant to run as long as the program, named so that the
+/// ones that stopped can be reported. They are aborted on drop, like the
+/// tasks of a [`JoinSet`].
+pub struct Tasks {
+    set: JoinSet<(&'static str, Result<()>)>,
+    abort_handles: Vec<AbortHandle>,
+    status_tx: watch::Sender<Vec<TaskStatus>>,
+}
+
+impl Default for Tasks {
+    fn default() -> Self {
+        Self {
+            set: JoinSet::new(),
+            abort_handles: Vec::new(),
+            status_tx: watch::channel(Vec::new()).0,
+        }
+    }
+}
+
+impl Tasks {
+    pub fn spawn<F>(&mut self, name: &'static str, task: F)
+    where
+        F: Future<Output = Result<()>> + Send + 'static,
+    {
+        self.status_tx.send_modify(|tasks| {
+            tasks.push(TaskStatus {
+                name: name.to_string(),
+                alive: true,
+                error: None,
+            })
+        });
+        // Spawned separately, so that a panic doesn't lose the name
+        let task = spawn(task);
+        self.abort_handles.push(task.abort_handle());
+        self.set.spawn(async move {
+            let res = match task.await {
+                Ok(res) => res,
+                Err(e) => Err(e.into()),
+            };
+            (name, res)
+        });
+    }
+
+    /// Every task spawned so far and whether it still runs.
+    pub fn status(&self) -> watch::Receiver<Vec<TaskStatus>> {
+        self.status_tx.subscribe()
+    }
+
+    /// Logs tasks as they stop, returns when all have.
+    pub async fn wait(mut self) {
+        while let Some(res) = self.set.join_next().await {
+            let Ok((name, res)) = res else {
+                continue;
+            };
+            let error = match res {
+                Ok(()) => {
+                    error!("task {name} exited");
+                    None
+                }
+                Err(e) => {
+                    error!("task {name} failed: {e:#}");
+                    Some(format!("{e:#}"))
+                }
+            };
+            self.status_tx.send_modify(|tasks| {
+                if let Some(task) = tasks.iter_mut().find(|t| t.name == name && t.alive) {
+                    task.alive = false;
+                    task.error = error;
+                }
+            });
+        }
+    }
+}
+
+impl Drop for Tasks {
+    fn drop(&mut self) {
+        for handle in &self.abort_handles {
+            handle.abort();
         }
     }
 }
@@ -46,6 +123,16 @@ pub async fn drive_distance(
         if pos.distance_to(&last_pos) > distance - 0.005 {
             break;
         }
+        // Keep the velocity watchdog fed
+        if velocity_tx
+            .send(proto::Velocity {
+                linear: 0.01,
+                angular: 0.0,
+            })
+            .is_err()
+        {
+            bail!("");
+        }
     }
 
     if velocity_tx
@@ -59,3 +146,22 @@ pub async fn drive_distance(
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::sync::oneshot;
+
+    #[tokio::test]
+    async fn dropping_aborts_tasks() {
+        let (tx, rx) = oneshot::channel::<()>();
+        let mut tasks = Tasks::default();
+        tasks.spawn("forever", async move {
+            let _tx = tx;
+            std::future::pending().await
+        });
+        drop(tasks);
+        // The sender goes with the aborted task
+        assert!(rx.await.is_err());
+    }
+}
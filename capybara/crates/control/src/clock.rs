@@ -0,0 +1,91 @@
+//! Offset of the robot's clock from ours, so that times the robot puts on
+//! video frames can be compared with local time.
+
+use std::time::{Duration, Instant};
+
+/// Clocks drift, so the best sample is only trusted for this long.
+const SAMPLE_TTL: Duration = Duration::from_secs(30);
+
+struct Sample {
+    round_trip: u64,
+    offset: i64,
+    taken: Instant,
+}
+
+/// Estimated the way NTP does: the robot stamps its reply somewhere within
+/// the round trip, so the error is at most half of it and samples with the
+/// shortest round trip are the best.
+#[derive(Default)]
+pub struct ClockOffset {
+    best: Option<Sample>,
+}
+
+impl ClockOffset {
+    /// Request sent at `sent_at` was answered at `remote_at` by the robot's
+    /// clock and the answer came at `received_at`, all in milliseconds.
+    /// Returns true if the estimate changed.
+    pub fn sample(&mut self, sent_at: u64, remote_at: u64, received_at: u64) -> bool {
+        let round_trip = received_at.saturating_sub(sent_at);
+        let offset = remote_at as i64 - (sent_at + round_trip / 2) as i64;
+        let better = match &self.best {
+            Some(best) => round_trip <= best.round_trip || best.taken.elapsed() > SAMPLE_TTL,
+            None => true,
+        };
+        if better {
+            self.best = Some(Sample {
+                round_trip,
+                offset,
+                taken: Instant::now(),
+            });
+        }
+        better
+    }
+
+    /// Robot time minus local time in milliseconds.
+    pub fn offset(&self) -> Option<i64> {
+        self.best.as_ref().map(|s| s.offset)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_shortest_round_trip() {
+        let mut clock = ClockOffset::default();
+        assert_eq!(clock.offset(), None);
+
+        // 100 ms round trip, robot stamped it 1000 ms ahead of the midpoint
+        assert!(clock.sample(0, 1050, 100));
+        assert_eq!(clock.offset(), Some(1000));
+
+        // A slower sample says little and is ignored
+        assert!(!clock.sample(200, 1500, 500));
+        assert_eq!(clock.offset(), Some(1000));
+
+        // A faster one replaces it
+        assert!(clock.sample(1000, 1990, 1020));
+        assert_eq!(clock.offset(), Some(980));
+    }
+
+    #[test]
+    fn robot_behind_us() {
+        let mut clock = ClockOffset::default();
+        assert!(clock.sample(5000, 4010, 5020));
+        assert_eq!(clock.offset(), Some(-1000));
+    }
+
+    #[test]
+    fn stale_sample_is_replaced() {
+        let mut clock = ClockOffset::default();
+        assert!(clock.sample(0, 1005, 10));
+        let Some(taken) = Instant::now().checked_sub(SAMPLE_TTL * 2) else {
+            return;
+        };
+        clock.best.as_mut().unwrap().taken = taken;
+
+        assert!(clock.sample(1000, 2600, 1200));
+        assert_eq!(clock.offset(), Some(1500));
+    }
+}
@@ -0,0 +1,176 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use proto::{now_millis, Command, PacketToSlave};
+
+const RETRY_TIMEOUT: Duration = Duration::from_millis(300);
+const MAX_ATTEMPTS: u32 = 5;
+/// The robot acks a photo command once the whole burst is taken, which can
+/// take this long per photo with the camera switching resolution.
+const PHOTO_TIMEOUT: Duration = Duration::from_secs(2);
+
+struct Pending {
+    packet: PacketToSlave,
+    attempts: u32,
+    deadline: Instant,
+    /// First transmission, see [`now_millis`].
+    sent_at: u64,
+}
+
+/// Assigns ids to outgoing commands and retransmits them with exponential
+/// backoff until the robot acknowledges them.
+#[derive(Default)]
+pub struct CommandTracker {
+    next_id: u32,
+    pending: HashMap<u32, Pending>,
+}
+
+impl CommandTracker {
+    pub fn issue(&mut self, command: Command) -> PacketToSlave {
+        // Retrying an old velocity or angle after a newer one was sent would
+        // only move the robot backwards in time.
+        self.pending
+            .retain(|_, p| !supersedes(&command, &p.packet.command));
+
+        let packet = PacketToSlave {
+            id: self.next_id,
+            command,
+        };
+        self.next_id = self.next_id.wrapping_add(1);
+        self.pending.insert(
+            packet.id,
+            Pending {
+                packet: packet.clone(),
+                attempts: 1,
+                deadline: Instant::now() + retry_timeout(&packet.command),
+                sent_at: now_millis(),
+            },
+        );
+        packet
+    }
+
+    /// For commands the robot doesn't answer, which are repeated anyway if
+    /// they get lost.
+    pub fn send_once(&mut self, command: Command) -> PacketToSlave {
+        let packet = PacketToSlave {
+            id: self.next_id,
+            command,
+        };
+        self.next_id = self.next_id.wrapping_add(1);
+        packet
+    }
+
+    /// Returns the acknowledged command, or `None` for unknown and duplicate acks.
+    /// Also returns when the command was sent if it was sent only once, so
+    /// the ack certainly answers that transmission.
+    pub fn ack(&mut self, id: u32) -> Option<(Command, Option<u64>)> {
+        self.pending
+            .remove(&id)
+            .map(|p| (p.packet.command, (p.attempts == 1).then_some(p.sent_at)))
+    }
+
+    /// Returns packets to retransmit and commands that ran out of attempts.
+    pub fn poll(&mut self, now: Instant) -> (Vec<PacketToSlave>, Vec<Command>) {
+        let mut resend = Vec::new();
+        let mut failed = Vec::new();
+        self.pending.retain(|_, p| {
+            if p.deadline > now {
+                return true;
+            }
+            if p.attempts >= MAX_ATTEMPTS {
+                failed.push(p.packet.command.clone());
+                return false;
+            }
+            p.deadline = now + retry_timeout(&p.packet.command) * 2u32.pow(p.attempts);
+            p.attempts += 1;
+            resend.push(p.packet.clone());
+            true
+        });
+        (resend, failed)
+    }
+}
+
+fn retry_timeout(command: &Command) -> Duration {
+    match command {
+        Command::TakePhoto(options) => RETRY_TIMEOUT + PHOTO_TIMEOUT * options.burst as u32,
+        _ => RETRY_TIMEOUT,
+    }
+}
+
+fn supersedes(new: &Command, old: &Command) -> bool {
+    matches!(
+        (new, old),
+        (Command::SetVelocity(_), Command::SetVelocity(_))
+            // Jogs add up, only an absolute target replaces them
+            | (
+                Command::SetAngle(_) | Command::ServoPreset(_),
+                Command::SetAngle(_) | Command::ServoPreset(_) | Command::JogAngle(_)
+            )
+            | (Command::ReportLinkStats(_), Command::ReportLinkStats(_))
+            | (Command::RequestKeyframe, Command::RequestKeyframe)
+            | (
+                Command::TakeControl | Command::ReleaseControl,
+                Command::TakeControl | Command::ReleaseControl
+            )
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proto::PhotoOptions;
+
+    #[test]
+    fn only_single_transmissions_time_the_link() {
+        let mut tracker = CommandTracker::default();
+        let once = tracker.issue(Command::TakeControl);
+        let retried = tracker.issue(Command::RequestKeyframe);
+
+        let (command, sent_at) = tracker.ack(once.id).unwrap();
+        assert_eq!(command, Command::TakeControl);
+        assert!(sent_at.is_some());
+
+        let (resend, failed) = tracker.poll(Instant::now() + RETRY_TIMEOUT);
+        assert_eq!(resend.len(), 1);
+        assert!(failed.is_empty());
+        let (_, sent_at) = tracker.ack(retried.id).unwrap();
+        assert_eq!(sent_at, None);
+    }
+
+    #[test]
+    fn bursts_wait_longer() {
+        let mut tracker = CommandTracker::default();
+        let options = PhotoOptions {
+            burst: 3,
+            ..Default::default()
+        };
+        tracker.issue(Command::TakePhoto(options));
+        tracker.issue(Command::TakeControl);
+
+        let (resend, _) = tracker.poll(Instant::now() + RETRY_TIMEOUT);
+        assert_eq!(resend.len(), 1);
+        assert_eq!(resend[0].command, Command::TakeControl);
+        let (resend, _) = tracker.poll(Instant::now() + RETRY_TIMEOUT + PHOTO_TIMEOUT * 3);
+        assert!(resend
+            .iter()
+            .any(|p| matches!(p.command, Command::TakePhoto(_))));
+    }
+
+    #[test]
+    fn unknown_and_duplicate_acks() {
+        let mut tracker = CommandTracker::default();
+        let packet = tracker.issue(Command::TakeControl);
+        assert!(tracker.ack(packet.id + 1).is_none());
+        assert!(tracker.ack(packet.id).is_some());
+        assert!(tracker.ack(packet.id).is_none());
+    }
+
+    #[test]
+    fn superseded_commands_are_not_acked() {
+        let mut tracker = CommandTracker::default();
+        let old = tracker.issue(Command::RequestKeyframe);
+        let new = tracker.issue(Command::RequestKeyframe);
+        assert!(tracker.ack(old.id).is_none());
+        assert!(tracker.ack(new.id).is_some());
+    }
+}
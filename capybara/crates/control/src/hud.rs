@@ -0,0 +1,246 @@
+//! Text and a crosshair over the video, the status panel under it and help
+//! for the keys handled by `move_system`.
+
+use bevy::{prelude::*, sprite::Anchor};
+
+use common::{VIDEO_HEIGHT, VIDEO_WIDTH};
+use proto::{Health, UplinkStats};
+
+use crate::{Map, RemoteControl, VideoMeter, GAP, MAP_SIZE, MAP_X, VIDEO_X};
+
+/// Bundled, Bevy has no default font.
+const FONT: &[u8] = include_bytes!("../assets/DejaVuSansMono.ttf");
+const FONT_SIZE: f32 = 14.0;
+const OVERLAY_COLOR: Color = Color::rgb(1.0, 1.0, 0.3);
+const CROSSHAIR_COLOR: Color = Color::rgba(0.3, 1.0, 0.3, 0.7);
+
+/// The Raspberry Pi starts throttling at 80 degrees.
+const HOT_CPU: f32 = 75.0;
+/// 3.5 V per cell of the 3S battery.
+const LOW_BATTERY: f32 = 10.5;
+const FULL_MEMORY: f64 = 0.9;
+/// The encoder drops frames beyond this.
+const ENCODER_BACKLOG: u32 = 2;
+
+const HELP: &str = "\
+W/S  drive forward/back (hold)
+A/D  turn left/right (hold)
+Q/E  open/close claw
+R/F  jog claw open/closed
+P    take a photo
+C/V  take/release control
+H    hide/show this help
+
+Gamepad:
+left stick   drive
+right stick  turn
+triggers     claw open/closed
+d-pad        jog claw
+A            take a photo";
+
+#[derive(Component)]
+pub struct Overlay;
+
+#[derive(Component)]
+pub struct StatusPanel;
+
+#[derive(Component)]
+pub struct HelpPanel;
+
+pub fn setup_hud(mut commands: Commands, mut fonts: ResMut<Assets<Font>>) {
+    let font = fonts.add(Font::try_from_bytes(FONT.to_vec()).expect("bundled font is valid"));
+    let style = TextStyle {
+        font,
+        font_size: FONT_SIZE,
+        color: Color::WHITE,
+    };
+    let video_left = VIDEO_X - VIDEO_WIDTH as f32 / 2.0;
+    let top = VIDEO_HEIGHT as f32 / 2.0;
+
+    // Above the video
+    commands.spawn((
+        Text2dBundle {
+            text: Text::from_section(
+                "",
+                TextStyle {
+                    color: OVERLAY_COLOR,
+                    ..style.clone()
+                },
+            ),
+            text_anchor: Anchor::TopLeft,
+            transform: Transform::from_xyz(video_left + 4.0, top - 4.0, 1.0),
+            ..default()
+        },
+        Overlay,
+    ));
+    for size in [Vec2::new(24.0, 2.0), Vec2::new(2.0, 24.0)] {
+        commands.spawn(SpriteBundle {
+            sprite: Sprite {
+                color: CROSSHAIR_COLOR,
+                custom_size: Some(size),
+                ..default()
+            },
+            transform: Transform::from_xyz(VIDEO_X, 0.0, 1.0),
+            ..default()
+        });
+    }
+
+    commands.spawn((
+        Text2dBundle {
+            text: Text::from_section("", style.clone()),
+            text_anchor: Anchor::TopLeft,
+            transform: Transform::from_xyz(video_left, -top - GAP, 0.0),
+            ..default()
+        },
+        StatusPanel,
+    ));
+    commands.spawn((
+        Text2dBundle {
+            text: Text::from_section(HELP, style),
+            text_anchor: Anchor::TopLeft,
+            transform: Transform::from_xyz(MAP_X + MAP_SIZE as f32 / 2.0 + GAP, top, 0.0),
+            ..default()
+        },
+        HelpPanel,
+    ));
+}
+
+pub fn hud_system(
+    rc: Res<RemoteControl>,
+    mut meter: ResMut<VideoMeter>,
+    map: Res<Map>,
+    mut overlay: Query<&mut Text, (With<Overlay>, Without<StatusPanel>)>,
+    mut status: Query<&mut Text, (With<StatusPanel>, Without<Overlay>)>,
+) {
+    meter.tick(&rc.decoder_stats.borrow());
+    let setpoint = rc.setpoint.borrow().clone();
+    let claw = match *rc.servo.borrow() {
+        Some(s) if s.position == s.target => format!("{:.0}% closed", s.position * 100.0),
+        Some(s) => format!(
+            "{:.0}% -> {:.0}% closed",
+            s.position * 100.0,
+            s.target * 100.0
+        ),
+        None => "?".to_string(),
+    };
+    let health = rc.health.borrow().clone();
+    if let Ok(mut text) = overlay.get_single_mut() {
+        let mut value = format!(
+            "{:+.2} m/s {:+.2} rad/s\nclaw {claw}\n{:.1} fps",
+            setpoint.linear, setpoint.angular, meter.fps
+        );
+        for warning in health.as_ref().map(health_warnings).unwrap_or_default() {
+            value += &format!("\n! {warning}");
+        }
+        set_text(&mut text, value);
+    }
+    if let Ok(mut text) = status.get_single_mut() {
+        set_text(
+            &mut text,
+            format!(
+                "{}\nvideo: {}\nlink: {:.0} kbit/s\nuplink kB/s: {}\nrobot: {}\npose: {}",
+                *rc.link.borrow(),
+                meter.summary,
+                *rc.bitrate.borrow() as f64 / 1000.0,
+                uplink_summary(rc.uplink.borrow().as_ref()),
+                health.as_ref().map_or("?".to_string(), health_summary),
+                map.trajectory.summary()
+            ),
+        );
+    }
+}
+
+/// Rate of every stream the robot sends in kB/s, with what it had to drop or hold
+/// back.
+fn uplink_summary(stats: Option<&UplinkStats>) -> String {
+    let Some(stats) = stats else {
+        return "?".to_string();
+    };
+    let seconds = stats.period_ms.max(1) as f64 / 1000.0;
+    let streams: Vec<String> = stats
+        .streams
+        .iter()
+        .map(|s| {
+            let mut summary = format!("{} {:.1}", s.stream, s.bytes as f64 / seconds / 1000.0);
+            if s.dropped > 0 {
+                summary += &format!(" ({} dropped)", s.dropped);
+            }
+            if s.queued > 0 {
+                summary += &format!(" ({} queued)", s.queued);
+            }
+            summary
+        })
+        .collect();
+    streams.join(", ")
+}
+
+fn health_summary(health: &Health) -> String {
+    let mut parts = Vec::new();
+    if let Some(temp) = health.cpu_temp {
+        parts.push(format!("{temp:.0}°C"));
+    }
+    if let Some(load) = health.load {
+        parts.push(format!("load {load:.1}/{}", health.cores));
+    }
+    if let (Some(used), Some(total)) = (health.mem_used, health.mem_total) {
+        parts.push(format!("mem {}/{} MB", used >> 20, total >> 20));
+    }
+    if let Some(battery) = health.battery {
+        parts.push(format!("battery {battery:.1} V"));
+    }
+    parts.push(format!("encoder queue {}", health.encoder_queue));
+    let alive = health.tasks.iter().filter(|t| t.alive).count();
+    parts.push(format!("{alive}/{} tasks", health.tasks.len()));
+    parts.join(", ")
+}
+
+/// What the operator should know about before the robot fails them.
+fn health_warnings(health: &Health) -> Vec<String> {
+    let mut warnings = Vec::new();
+    for task in health.tasks.iter().filter(|t| !t.alive) {
+        warnings.push(match &task.error {
+            Some(e) => format!("{} failed: {e}", task.name),
+            None => format!("{} stopped", task.name),
+        });
+    }
+    if let Some(temp) = health.cpu_temp.filter(|&t| t >= HOT_CPU) {
+        warnings.push(format!("CPU at {temp:.0}°C"));
+    }
+    if let Some(load) = health.load.filter(|&l| l > health.cores as f32) {
+        warnings.push(format!("load {load:.1} on {} cores", health.cores));
+    }
+    if let (Some(used), Some(total)) = (health.mem_used, health.mem_total) {
+        if used as f64 >= total as f64 * FULL_MEMORY {
+            warnings.push(format!("memory {}/{} MB", used >> 20, total >> 20));
+        }
+    }
+    if let Some(battery) = health.battery.filter(|&b| b <= LOW_BATTERY) {
+        warnings.push(format!("battery at {battery:.1} V"));
+    }
+    if health.encoder_queue >= ENCODER_BACKLOG {
+        warnings.push(format!("encoder behind by {} frames", health.encoder_queue));
+    }
+    warnings
+}
+
+/// Only touches the text when it differs, so it isn't laid out every frame.
+fn set_text(text: &mut Mut<Text>, value: String) {
+    if text.sections[0].value != value {
+        text.sections[0].value = value;
+    }
+}
+
+pub fn help_system(
+    key_input: Res<Input<KeyCode>>,
+    mut help: Query<&mut Visibility, With<HelpPanel>>,
+) {
+    if !key_input.just_pressed(KeyCode::H) {
+        return;
+    }
+    for mut visibility in help.iter_mut() {
+        *visibility = match *visibility {
+            Visibility::Hidden => Visibility::Inherited,
+            _ => Visibility::Hidden,
+        };
+    }
+}
@@ -0,0 +1,207 @@
+//! Speeds for the keyboard, and gamepad driving proportional to how far the
+//! sticks and triggers are pushed.
+
+use bevy::prelude::*;
+use clap::Args;
+use std::time::{Duration, Instant};
+
+use crate::{Arm, CommandFromUI, RemoteControl};
+
+/// Claw position change of a key or D-pad press, of the travel from open to
+/// closed.
+pub const JOG_STEP: f64 = 0.05;
+
+/// Smaller changes of a stick or a trigger are not sent.
+const MIN_CHANGE: f64 = 0.01;
+/// Every change becomes commands to the robot, a moving stick changes on
+/// every frame.
+const SEND_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Speeds and stick shaping, for the keyboard and the gamepad.
+#[derive(Args, Resource, Clone, Debug)]
+pub struct Controls {
+    /// Top speed in m/s, full stick or a held key
+    #[arg(long, env = "CAPYBARA_MAX_LINEAR", default_value_t = 0.05)]
+    pub max_linear: f64,
+    /// Top turn rate in rad/s
+    #[arg(long, env = "CAPYBARA_MAX_ANGULAR", default_value_t = 0.1)]
+    pub max_angular: f64,
+    /// Fraction of stick travel ignored around the center
+    #[arg(long, env = "CAPYBARA_STICK_DEADZONE", default_value_t = 0.1,
+          value_parser = deadzone_fraction)]
+    pub stick_deadzone: f64,
+    /// Fraction of trigger travel ignored when released
+    #[arg(long, env = "CAPYBARA_TRIGGER_DEADZONE", default_value_t = 0.05,
+          value_parser = deadzone_fraction)]
+    pub trigger_deadzone: f64,
+    /// 0 is linear, 1 is cubic for fine control near the center
+    #[arg(long, env = "CAPYBARA_EXPO", default_value_t = 0.3, value_parser = expo)]
+    pub expo: f64,
+}
+
+impl Controls {
+    /// Maps a stick axis in -1..=1 to -1..=1.
+    fn stick(&self, value: f32) -> f64 {
+        let value = deadzone(value as f64, self.stick_deadzone);
+        (1.0 - self.expo) * value + self.expo * value.powi(3)
+    }
+}
+
+/// A deadzone of the whole travel would leave nothing to scale the rest to.
+fn deadzone_fraction(s: &str) -> Result<f64, String> {
+    let value = s.parse::<f64>().map_err(|e| e.to_string())?;
+    if (0.0..1.0).contains(&value) {
+        Ok(value)
+    } else {
+        Err("must be at least 0 and less than 1".to_string())
+    }
+}
+
+fn expo(s: &str) -> Result<f64, String> {
+    let value = s.parse::<f64>().map_err(|e| e.to_string())?;
+    if (0.0..=1.0).contains(&value) {
+        Ok(value)
+    } else {
+        Err("must be from 0 to 1".to_string())
+    }
+}
+
+fn deadzone(value: f64, deadzone: f64) -> f64 {
+    if value.abs() <= deadzone {
+        0.0
+    } else {
+        value.signum() * ((value.abs() - deadzone) / (1.0 - deadzone)).min(1.0)
+    }
+}
+
+/// Last values sent, so a resting gamepad doesn't override the keyboard.
+#[derive(Default)]
+pub struct GamepadState {
+    drive: f64,
+    turn: f64,
+    claw: f64,
+    sent_at: Option<Instant>,
+}
+
+fn changed(last: &mut f64, value: f64) -> Option<f64> {
+    // Always send reaching the rest position
+    if (value - *last).abs() < MIN_CHANGE && !(value == 0.0 && *last != 0.0) {
+        return None;
+    }
+    *last = value;
+    Some(value)
+}
+
+/// Left stick drives, right stick turns, the right trigger closes the claw
+/// and the left one opens it. Released triggers leave the claw where it is,
+/// so that they don't undo the keyboard. D-pad up and down jog the claw,
+/// South button takes a photo.
+pub fn gamepad_system(
+    rc: Res<RemoteControl>,
+    controls: Res<Controls>,
+    gamepads: Res<Gamepads>,
+    axes: Res<Axis<GamepadAxis>>,
+    button_axes: Res<Axis<GamepadButton>>,
+    buttons: Res<Input<GamepadButton>>,
+    mut state: Local<GamepadState>,
+) {
+    let Some(gamepad) = gamepads.iter().next() else {
+        return;
+    };
+    // Button presses last a single frame
+    let pressed = |kind| buttons.just_pressed(GamepadButton::new(gamepad, kind));
+    let photo = pressed(GamepadButtonType::South).then_some(());
+    let jog = if pressed(GamepadButtonType::DPadUp) {
+        Some(Arm::Jog(-JOG_STEP))
+    } else if pressed(GamepadButtonType::DPadDown) {
+        Some(Arm::Jog(JOG_STEP))
+    } else {
+        None
+    };
+    if state.sent_at.is_some_and(|t| t.elapsed() < SEND_INTERVAL) {
+        rc.send(CommandFromUI {
+            arm: jog,
+            photo,
+            ..default()
+        });
+        return;
+    }
+
+    let axis = |kind| axes.get(GamepadAxis::new(gamepad, kind)).unwrap_or(0.0);
+    let trigger = |kind| {
+        let value = button_axes
+            .get(GamepadButton::new(gamepad, kind))
+            .unwrap_or(0.0);
+        deadzone(value as f64, controls.trigger_deadzone)
+    };
+    let drive = controls.stick(axis(GamepadAxisType::LeftStickY));
+    // Stick right turns clockwise
+    let turn = -controls.stick(axis(GamepadAxisType::RightStickX));
+    let claw = trigger(GamepadButtonType::RightTrigger2) - trigger(GamepadButtonType::LeftTrigger2);
+
+    let command = CommandFromUI {
+        linear: changed(&mut state.drive, drive).map(|d| d * controls.max_linear),
+        angular: changed(&mut state.turn, turn).map(|t| t * controls.max_angular),
+        arm: jog.or_else(|| {
+            changed(&mut state.claw, claw)
+                .filter(|&c| c != 0.0)
+                .map(|c| Arm::Angle(0.5 + c / 2.0))
+        }),
+        photo,
+        lease: None,
+    };
+    if rc.send(command) {
+        state.sent_at = Some(Instant::now());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn controls(stick_deadzone: f64, expo: f64) -> Controls {
+        Controls {
+            max_linear: 1.0,
+            max_angular: 1.0,
+            stick_deadzone,
+            trigger_deadzone: 0.0,
+            expo,
+        }
+    }
+
+    #[test]
+    fn deadzone_rescales_the_rest() {
+        assert_eq!(deadzone(0.05, 0.1), 0.0);
+        assert_eq!(deadzone(-0.1, 0.1), 0.0);
+        assert!((deadzone(0.55, 0.1) - 0.5).abs() < 1e-9);
+        assert!((deadzone(-0.55, 0.1) + 0.5).abs() < 1e-9);
+        assert_eq!(deadzone(1.0, 0.1), 1.0);
+        // Some gamepads go a little past full travel
+        assert_eq!(deadzone(-1.02, 0.1), -1.0);
+        assert_eq!(deadzone(0.3, 0.0), 0.3);
+    }
+
+    #[test]
+    fn stick_expo() {
+        let linear = controls(0.0, 0.0);
+        assert_eq!(linear.stick(0.5), 0.5);
+        let cubic = controls(0.0, 1.0);
+        assert_eq!(cubic.stick(0.5), 0.125);
+        assert_eq!(cubic.stick(-1.0), -1.0);
+
+        let shaped = controls(0.2, 0.5);
+        assert_eq!(shaped.stick(0.1), 0.0);
+        assert_eq!(shaped.stick(1.0), 1.0);
+        assert!((shaped.stick(0.6) - (0.25 + 0.5 * 0.125)).abs() < 1e-6);
+    }
+
+    #[test]
+    fn ranges() {
+        assert_eq!(deadzone_fraction("0.1"), Ok(0.1));
+        assert!(deadzone_fraction("1").is_err());
+        assert!(deadzone_fraction("-0.1").is_err());
+        assert_eq!(expo("1"), Ok(1.0));
+        assert!(expo("1.5").is_err());
+        assert!(expo("NaN").is_err());
+    }
+}
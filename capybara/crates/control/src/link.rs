@@ -0,0 +1,760 @@
+//! Connection to the robot. It is kept up for the whole life of the control
+//! station: when it drops we reconnect with exponential backoff and resend
+//! the operator's velocity and arm target.
+
+use anyhow::{bail, Context, Result};
+use bevy::log::{debug, error, info, warn};
+use futures::{SinkExt, StreamExt};
+use std::fmt;
+use std::time::{Duration, Instant};
+use tokio::sync::{broadcast, mpsc, watch};
+use tokio::time::{interval, sleep, timeout};
+use tokio_tungstenite::tungstenite::{
+    client::IntoClientRequest,
+    http::{header::AUTHORIZATION, HeaderValue},
+    protocol::Message,
+};
+use tokio_tungstenite::{connect_async_tls_with_config, Connector};
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
+
+use proto::{now_millis, Envelope, Framer, Hello, HelloAck, ProtoError, VideoCodec};
+use proto::{
+    Command, CommandResult, Health, LinkStats, Odometry, PacketToMaster, PacketToSlave, Photo,
+    PhotoOptions, ServoPosition, UplinkStats, Velocity, VideoFrame,
+};
+
+use decoder::DecoderStats;
+
+use crate::clock::ClockOffset;
+use crate::commands::CommandTracker;
+use crate::photos::PhotoAssembler;
+use crate::{Arm, CommandFromUI, Lease};
+
+type WsStream = WebSocketStream<MaybeTlsStream<tokio::net::TcpStream>>;
+
+const MIN_BACKOFF: Duration = Duration::from_millis(500);
+const MAX_BACKOFF: Duration = Duration::from_secs(10);
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+/// The robot streams video and odometry all the time, so silence this long
+/// means the link is dead even if TCP hasn't noticed yet.
+const LINK_TIMEOUT: Duration = Duration::from_secs(3);
+const STATS_PERIOD: Duration = Duration::from_secs(1);
+/// Video frames delayed this much more than the fastest ones count as late.
+const LATE_FRAME_MS: i64 = 300;
+/// The decoder asks on every packet it has to drop, a keyframe takes about
+/// a round trip to arrive.
+const KEYFRAME_REQUEST_INTERVAL: Duration = Duration::from_millis(500);
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum LinkState {
+    Connecting,
+    Connected {
+        session: u32,
+        controller: Option<u32>,
+    },
+    Disconnected {
+        error: String,
+        retry_in: Duration,
+    },
+}
+
+impl fmt::Display for LinkState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Connecting => write!(f, "connecting"),
+            Self::Connected {
+                session,
+                controller,
+            } => match controller {
+                Some(c) if c == session => write!(f, "connected, in control"),
+                Some(c) => write!(f, "connected, watching session {c}"),
+                None => write!(f, "connected, nobody in control"),
+            },
+            Self::Disconnected { error, retry_in } => write!(
+                f,
+                "disconnected ({error}), retrying in {:.1}s",
+                retry_in.as_secs_f64()
+            ),
+        }
+    }
+}
+
+/// Where and how to connect.
+pub struct Endpoint {
+    pub url: String,
+    pub token: Option<String>,
+    pub connector: Option<Connector>,
+}
+
+impl Endpoint {
+    async fn connect(&self) -> Result<(WsStream, Framer, HelloAck)> {
+        let mut request = self.url.as_str().into_client_request()?;
+        if let Some(token) = &self.token {
+            let value = HeaderValue::from_str(&format!("Bearer {token}"))?;
+            request.headers_mut().insert(AUTHORIZATION, value);
+        }
+        let connect = connect_async_tls_with_config(request, None, self.connector.clone());
+        let (mut ws_stream, _) = timeout(CONNECT_TIMEOUT, connect)
+            .await
+            .context("timed out")??;
+        let mut framer = Framer::default();
+        let ack = handshake(&mut ws_stream, &mut framer).await?;
+        Ok((ws_stream, framer, ack))
+    }
+}
+
+/// What the operator asked for. Survives reconnects so it can be resent.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Setpoint {
+    /// Metres per second.
+    pub linear: f64,
+    /// Radians per second.
+    pub angular: f64,
+    /// Last claw target we sent, `None` until the operator moves it.
+    pub arm: Option<ArmSetpoint>,
+    pub want_control: bool,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum ArmSetpoint {
+    /// 0 is open, 1 is closed.
+    Angle(f64),
+    /// Replaced by its angle once the robot reports it.
+    Preset(&'static str),
+}
+
+impl Default for Setpoint {
+    fn default() -> Self {
+        Self {
+            linear: 0.0,
+            angular: 0.0,
+            arm: None,
+            want_control: true,
+        }
+    }
+}
+
+impl Setpoint {
+    fn apply(&mut self, movecmd: &CommandFromUI) {
+        if let Some(linear) = movecmd.linear {
+            self.linear = linear;
+        }
+        if let Some(angular) = movecmd.angular {
+            self.angular = angular;
+        }
+    }
+
+    /// Jogs are relative, so they only move a known angle. The robot's servo
+    /// reports correct it anyway.
+    fn apply_arm(&mut self, arm: &Arm) {
+        self.arm = match (arm, &self.arm) {
+            (Arm::Angle(a), _) => Some(ArmSetpoint::Angle(*a)),
+            (Arm::Preset(name), _) => Some(ArmSetpoint::Preset(*name)),
+            (Arm::Jog(delta), Some(ArmSetpoint::Angle(a))) => {
+                Some(ArmSetpoint::Angle((a + delta).clamp(0.0, 1.0)))
+            }
+            (Arm::Jog(_), arm) => arm.clone(),
+        };
+    }
+
+    fn arm_command(&self) -> Option<Command> {
+        self.arm.as_ref().map(|arm| match arm {
+            ArmSetpoint::Angle(a) => Command::SetAngle(*a),
+            ArmSetpoint::Preset(name) => Command::ServoPreset(name.to_string()),
+        })
+    }
+
+    /// Lets the HUD show it.
+    fn publish(&self, setpoint_tx: &watch::Sender<Setpoint>) {
+        setpoint_tx.send_if_modified(|shown| {
+            let changed = shown != self;
+            if changed {
+                *shown = self.clone();
+            }
+            changed
+        });
+    }
+
+    fn velocity(&self) -> Velocity {
+        Velocity {
+            linear: self.linear,
+            angular: self.angular,
+        }
+    }
+}
+
+/// What the robot reports about itself, for the map.
+#[derive(Clone, Debug)]
+pub enum Telemetry {
+    /// `at` is the robot's time of sending it.
+    Odometry { pose: Odometry, at: u64 },
+    /// A photo arrived, taken by us or another client, at `pose`.
+    Photo { pose: Odometry },
+}
+
+/// Where the link delivers what it receives.
+pub struct Sinks {
+    pub encoder_tx: broadcast::Sender<VideoFrame>,
+    pub photo_data_tx: broadcast::Sender<Photo>,
+    pub state_tx: watch::Sender<LinkState>,
+    /// Video codec agreed with the robot.
+    pub codec_tx: watch::Sender<Option<VideoCodec>>,
+    /// Decode errors are reported to the robot.
+    pub decoder_stats_rx: watch::Receiver<DecoderStats>,
+    /// Robot time minus local time in milliseconds.
+    pub clock_tx: watch::Sender<Option<i64>>,
+    pub telemetry_tx: broadcast::Sender<Telemetry>,
+    pub setpoint_tx: watch::Sender<Setpoint>,
+    /// Claw servo as last reported by the robot.
+    pub servo_tx: watch::Sender<Option<ServoPosition>>,
+    /// Bits per second received from the robot.
+    pub bitrate_tx: watch::Sender<u64>,
+    /// What the robot's uplink scheduler sent and dropped.
+    pub uplink_tx: watch::Sender<Option<UplinkStats>>,
+    pub health_tx: watch::Sender<Option<Health>>,
+}
+
+/// Video reception since the last report.
+#[derive(Default)]
+struct VideoStats {
+    since: Option<Instant>,
+    bytes: u32,
+    frames: u32,
+    late_frames: u32,
+    /// Clocks of the robot and the laptop are not synchronized, so lateness
+    /// is measured against the smallest delay seen during the session.
+    min_delay: Option<i64>,
+    decode_errors: u64,
+}
+
+impl VideoStats {
+    fn frame(&mut self, len: usize, sent_at: u64) {
+        let delay = now_millis() as i64 - sent_at as i64;
+        let min_delay = self.min_delay.get_or_insert(delay);
+        *min_delay = (*min_delay).min(delay);
+        if delay - *min_delay > LATE_FRAME_MS {
+            self.late_frames += 1;
+        }
+        self.bytes = self.bytes.saturating_add(len as u32);
+        self.frames += 1;
+    }
+
+    fn report(&mut self, decode_errors: u64) -> LinkStats {
+        let since = self.since.replace(Instant::now());
+        let stats = LinkStats {
+            period_ms: since.map_or(0, |s| s.elapsed().as_millis() as u32),
+            bytes: self.bytes,
+            frames: self.frames,
+            late_frames: self.late_frames,
+            decode_errors: decode_errors.saturating_sub(self.decode_errors) as u32,
+        };
+        self.bytes = 0;
+        self.frames = 0;
+        self.late_frames = 0;
+        self.decode_errors = decode_errors;
+        stats
+    }
+}
+
+/// Keeps the robot connected until the UI goes away.
+pub async fn run_link(
+    endpoint: Endpoint,
+    photo_options: PhotoOptions,
+    mut movecmd_rx: mpsc::Receiver<CommandFromUI>,
+    mut keyframe_rx: mpsc::Receiver<()>,
+    sinks: Sinks,
+) -> Result<()> {
+    let mut setpoint = Setpoint::default();
+    let mut photos = PhotoAssembler::default();
+    let mut backoff = MIN_BACKOFF;
+    loop {
+        let _ = sinks.state_tx.send(LinkState::Connecting);
+        let error = match endpoint.connect().await {
+            Ok((ws_stream, framer, ack)) => {
+                info!(
+                    "connected to {} as session {}, receiving {} video",
+                    endpoint.url, ack.session, ack.codec
+                );
+                backoff = MIN_BACKOFF;
+                let _ = sinks.codec_tx.send(Some(ack.codec));
+                let session = Session {
+                    session: ack.session,
+                    framer,
+                    controller: None,
+                    commands: CommandTracker::default(),
+                    stats: VideoStats::default(),
+                    last_keyframe_request: None,
+                    clock: ClockOffset::default(),
+                    photo_options: photo_options.clone(),
+                };
+                match session
+                    .run(
+                        ws_stream,
+                        &mut setpoint,
+                        &mut photos,
+                        &mut movecmd_rx,
+                        &mut keyframe_rx,
+                        &sinks,
+                    )
+                    .await
+                {
+                    Ok(()) => return Ok(()),
+                    Err(e) => e,
+                }
+            }
+            Err(e) => e,
+        };
+
+        warn!("no link to {}: {error}", endpoint.url);
+        let _ = sinks.bitrate_tx.send(0);
+        let _ = sinks.uplink_tx.send(None);
+        let _ = sinks.health_tx.send(None);
+        let _ = sinks.state_tx.send(LinkState::Disconnected {
+            error: error.to_string(),
+            retry_in: backoff,
+        });
+        // Keep following the operator, so that a key released during the
+        // outage doesn't get replayed after reconnecting.
+        let retry = sleep(backoff);
+        tokio::pin!(retry);
+        loop {
+            tokio::select! {
+                _ = &mut retry => break,
+                movecmd = movecmd_rx.recv() => match movecmd {
+                    Some(mc) => {
+                        if mc.photo.is_some() {
+                            warn!("not connected, photo not taken");
+                        }
+                        if mc.arm.is_some() {
+                            warn!("not connected, arm not moved");
+                        }
+                        if let Some(lease) = &mc.lease {
+                            setpoint.want_control = matches!(lease, Lease::Take);
+                        }
+                        setpoint.apply(&mc);
+                        setpoint.publish(&sinks.setpoint_tx);
+                    }
+                    None => return Ok(()),
+                },
+            }
+        }
+        backoff = (backoff * 2).min(MAX_BACKOFF);
+    }
+}
+
+struct Session {
+    session: u32,
+    framer: Framer,
+    controller: Option<u32>,
+    commands: CommandTracker,
+    stats: VideoStats,
+    last_keyframe_request: Option<Instant>,
+    clock: ClockOffset,
+    /// Sent with every photo request.
+    photo_options: PhotoOptions,
+}
+
+impl Session {
+    fn in_control(&self) -> bool {
+        self.controller == Some(self.session)
+    }
+
+    /// Returns `Ok` when the UI is closed and an error when the link is lost.
+    async fn run(
+        mut self,
+        ws_stream: WsStream,
+        setpoint: &mut Setpoint,
+        photos: &mut PhotoAssembler,
+        movecmd_rx: &mut mpsc::Receiver<CommandFromUI>,
+        keyframe_rx: &mut mpsc::Receiver<()>,
+        sinks: &Sinks,
+    ) -> Result<()> {
+        let (mut sender, mut receiver) = ws_stream.split();
+        let mut retry_interval = interval(Duration::from_millis(50));
+        // Robot stops by itself if velocity commands stop coming
+        let mut heartbeat_interval = interval(Duration::from_millis(200));
+        // Lease of a dead previous session may still be held for a while
+        let mut lease_interval = interval(Duration::from_secs(1));
+        let mut stats_interval = interval(STATS_PERIOD);
+        let mut last_received = Instant::now();
+        let mut received_bytes = 0u64;
+        let mut received_since = Instant::now();
+        let mut outgoing = Vec::new();
+        loop {
+            setpoint.publish(&sinks.setpoint_tx);
+            for pkt in outgoing.drain(..) {
+                let msg = Message::Binary(self.framer.frame_packet(&pkt)?);
+                sender.send(msg).await?;
+            }
+            tokio::select! {
+                movecmd = movecmd_rx.recv() => {
+                    let movecmd: CommandFromUI = match movecmd {
+                        Some(mc) => mc,
+                        None => return Ok(()),
+                    };
+                    if let Some(lease) = &movecmd.lease {
+                        setpoint.want_control = matches!(lease, Lease::Take);
+                        let command = match lease {
+                            Lease::Take => Command::TakeControl,
+                            Lease::Release => Command::ReleaseControl,
+                        };
+                        outgoing.push(self.commands.issue(command));
+                    }
+                    if !self.in_control() {
+                        continue;
+                    }
+                    setpoint.apply(&movecmd);
+                    if movecmd.photo.is_some() {
+                        let command = Command::TakePhoto(self.photo_options.clone());
+                        outgoing.push(self.commands.issue(command));
+                    }
+                    outgoing.push(self.commands.issue(Command::SetVelocity(setpoint.velocity())));
+                    if let Some(arm) = &movecmd.arm {
+                        setpoint.apply_arm(arm);
+                        let command = match arm {
+                            Arm::Angle(a) => Command::SetAngle(*a),
+                            Arm::Jog(delta) => Command::JogAngle(*delta),
+                            Arm::Preset(name) => Command::ServoPreset(name.to_string()),
+                        };
+                        outgoing.push(self.commands.issue(command));
+                    }
+                }
+                msg = receiver.next() => {
+                    let b = match msg {
+                        Some(Ok(Message::Binary(b))) => b,
+                        Some(Ok(Message::Close(_))) | None => bail!("connection closed"),
+                        Some(Ok(_)) => continue,
+                        Some(Err(e)) => bail!(e),
+                    };
+                    last_received = Instant::now();
+                    received_bytes += b.len() as u64;
+                    let envelope = Envelope::decode(&b)?;
+                    let pkt: PacketToMaster = envelope.packet()?;
+                    if let PacketToMaster::Video(vf) = &pkt {
+                        self.stats.frame(vf.data.len(), envelope.timestamp);
+                    }
+                    self.handle(pkt, envelope.timestamp, setpoint, photos, &mut outgoing, sinks);
+                }
+                // Encoder settings belong to the controller as well
+                Some(()) = keyframe_rx.recv() => {
+                    let recent = self
+                        .last_keyframe_request
+                        .is_some_and(|t| t.elapsed() < KEYFRAME_REQUEST_INTERVAL);
+                    if self.in_control() && !recent {
+                        self.last_keyframe_request = Some(Instant::now());
+                        outgoing.push(self.commands.issue(Command::RequestKeyframe));
+                    }
+                }
+                _ = heartbeat_interval.tick(), if self.in_control() => {
+                    outgoing.push(self.commands.issue(Command::SetVelocity(setpoint.velocity())));
+                }
+                _ = lease_interval.tick(), if setpoint.want_control && !self.in_control() => {
+                    outgoing.push(self.commands.issue(Command::TakeControl));
+                }
+                _ = stats_interval.tick() => {
+                    let elapsed = received_since.elapsed().as_millis().max(1) as u64;
+                    let _ = sinks.bitrate_tx.send(received_bytes * 8 * 1000 / elapsed);
+                    received_bytes = 0;
+                    received_since = Instant::now();
+                    // Only the controller's link quality matters to the robot
+                    if !self.in_control() {
+                        continue;
+                    }
+                    let stats = self.stats.report(sinks.decoder_stats_rx.borrow().errors);
+                    if stats.period_ms > 0 {
+                        outgoing.push(self.commands.issue(Command::ReportLinkStats(stats)));
+                    }
+                }
+                _ = retry_interval.tick() => {
+                    if last_received.elapsed() > LINK_TIMEOUT {
+                        bail!("robot stopped responding");
+                    }
+                    let (resend, failed) = self.commands.poll(Instant::now());
+                    for command in failed {
+                        error!("robot didn't acknowledge {command:?}");
+                    }
+                    outgoing = resend;
+                }
+            }
+        }
+    }
+
+    /// `sent_at` is the robot's time of sending the packet.
+    fn handle(
+        &mut self,
+        pkt: PacketToMaster,
+        sent_at: u64,
+        setpoint: &mut Setpoint,
+        photos: &mut PhotoAssembler,
+        outgoing: &mut Vec<PacketToSlave>,
+        sinks: &Sinks,
+    ) {
+        match pkt {
+            PacketToMaster::Video(vf) => {
+                let _ = sinks.encoder_tx.send(vf);
+            }
+            PacketToMaster::PhotoChunk(chunk) => {
+                debug!(
+                    "got chunk {}/{} of photo transfer {}",
+                    chunk.index + 1,
+                    chunk.count,
+                    chunk.transfer
+                );
+                // The robot resends chunks until the controller acks them,
+                // observers take what passes by
+                if self.in_control() {
+                    let command = Command::AckPhotoChunk {
+                        transfer: chunk.transfer,
+                        index: chunk.index,
+                    };
+                    outgoing.push(self.commands.send_once(command));
+                }
+                match photos.chunk(chunk) {
+                    Ok(Some(photo)) => {
+                        info!("got photo {}", photo.id);
+                        let _ = sinks.telemetry_tx.send(Telemetry::Photo {
+                            pose: photo.pose.clone(),
+                        });
+                        let _ = sinks.photo_data_tx.send(photo);
+                    }
+                    Ok(None) => {}
+                    Err(e) => error!("bad photo chunk: {e:#}"),
+                }
+            }
+            PacketToMaster::Odometry(o) => {
+                debug!("got odometry x = {}, y = {}, theta = {}", o.x, o.y, o.theta);
+                let _ = sinks.telemetry_tx.send(Telemetry::Odometry {
+                    pose: o,
+                    at: sent_at,
+                });
+            }
+            PacketToMaster::Servo(position) => {
+                // Another controller's target is not ours to resend
+                if self.in_control() {
+                    setpoint.arm = Some(ArmSetpoint::Angle(position.target));
+                }
+                let _ = sinks.servo_tx.send(Some(position));
+            }
+            PacketToMaster::Ack { id, result } => {
+                if let Some((command, command_sent_at)) = self.commands.ack(id) {
+                    if let Some(command_sent_at) = command_sent_at {
+                        if self.clock.sample(command_sent_at, sent_at, now_millis()) {
+                            let _ = sinks.clock_tx.send(self.clock.offset());
+                        }
+                    }
+                    match result {
+                        CommandResult::Done => match command {
+                            Command::TakePhoto(_) => info!("photo taken"),
+                            _ => debug!("{command:?} done"),
+                        },
+                        CommandResult::Busy => match command {
+                            Command::TakeControl => debug!("robot is controlled by another client"),
+                            _ => warn!("robot is busy, {command:?} ignored"),
+                        },
+                        CommandResult::Failed(e) => error!("{command:?} failed: {e}"),
+                    }
+                }
+            }
+            PacketToMaster::UplinkStats(stats) => {
+                for s in stats.streams.iter().filter(|s| s.dropped > 0) {
+                    debug!("robot dropped {} {} packets", s.dropped, s.stream);
+                }
+                let _ = sinks.uplink_tx.send(Some(stats));
+            }
+            PacketToMaster::Health(health) => {
+                let was_dead = |name: &str| {
+                    sinks
+                        .health_tx
+                        .borrow()
+                        .as_ref()
+                        .is_some_and(|h| h.tasks.iter().any(|t| t.name == name && !t.alive))
+                };
+                for task in health.tasks.iter().filter(|t| !t.alive) {
+                    if !was_dead(&task.name) {
+                        match &task.error {
+                            Some(e) => error!("robot task {} failed: {e}", task.name),
+                            None => error!("robot task {} exited", task.name),
+                        }
+                    }
+                }
+                let _ = sinks.health_tx.send(Some(health));
+            }
+            PacketToMaster::Failsafe { tripped } => {
+                if tripped {
+                    warn!("robot lost velocity commands and stopped");
+                } else {
+                    info!("robot receives velocity commands again");
+                }
+            }
+            PacketToMaster::ControlChanged { controller } => {
+                let was_in_control = self.in_control();
+                self.controller = controller;
+                match controller {
+                    Some(s) if s == self.session => info!("we are in control"),
+                    Some(s) => info!("session {s} is in control, watching"),
+                    None => info!("nobody is in control"),
+                }
+                if self.in_control() && !was_in_control {
+                    // Resync what the operator holds, possibly from before
+                    // the link dropped
+                    outgoing.push(
+                        self.commands
+                            .issue(Command::SetVelocity(setpoint.velocity())),
+                    );
+                    if let Some(command) = setpoint.arm_command() {
+                        outgoing.push(self.commands.issue(command));
+                    }
+                } else if was_in_control && !self.in_control() {
+                    setpoint.linear = 0.0;
+                    setpoint.angular = 0.0;
+                    setpoint.arm = None;
+                }
+                let _ = sinks.state_tx.send(LinkState::Connected {
+                    session: self.session,
+                    controller,
+                });
+            }
+        }
+    }
+}
+
+/// Introduces us to the robot, failing if it speaks another protocol version
+/// or streams video we can't decode.
+async fn handshake(ws_stream: &mut WsStream, framer: &mut Framer) -> Result<HelloAck> {
+    let codecs = decoder::supported_codecs();
+    let hello = Hello {
+        name: "control".to_string(),
+        codecs: codecs.clone(),
+    };
+    ws_stream
+        .send(Message::Binary(framer.frame_packet(&hello)?))
+        .await?;
+
+    let ack: HelloAck = match ws_stream.next().await {
+        Some(Ok(Message::Binary(b))) => Envelope::decode(&b)?.packet()?,
+        Some(Ok(msg)) => bail!("expected hello ack, got {msg:?}"),
+        Some(Err(e)) => bail!(e),
+        None => bail!("connection closed during handshake"),
+    };
+    if !ack.accepted {
+        bail!(ProtoError::HandshakeRejected);
+    }
+    if !codecs.contains(&ack.codec) {
+        bail!("robot streams {} video, which is not built in", ack.codec);
+    }
+    Ok(ack)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn session(session: u32) -> Session {
+        Session {
+            session,
+            framer: Framer::default(),
+            controller: None,
+            commands: CommandTracker::default(),
+            stats: VideoStats::default(),
+            last_keyframe_request: None,
+            clock: ClockOffset::default(),
+            photo_options: PhotoOptions::default(),
+        }
+    }
+
+    fn sinks() -> Sinks {
+        Sinks {
+            encoder_tx: broadcast::channel(1).0,
+            photo_data_tx: broadcast::channel(1).0,
+            state_tx: watch::channel(LinkState::Connecting).0,
+            codec_tx: watch::channel(None).0,
+            decoder_stats_rx: watch::channel(DecoderStats::default()).1,
+            clock_tx: watch::channel(None).0,
+            telemetry_tx: broadcast::channel(1).0,
+            setpoint_tx: watch::channel(Setpoint::default()).0,
+            servo_tx: watch::channel(None).0,
+            bitrate_tx: watch::channel(0).0,
+            uplink_tx: watch::channel(None).0,
+            health_tx: watch::channel(None).0,
+        }
+    }
+
+    fn take_control(session: &mut Session, setpoint: &mut Setpoint) -> Vec<Command> {
+        let mut outgoing = Vec::new();
+        session.handle(
+            PacketToMaster::ControlChanged {
+                controller: Some(session.session),
+            },
+            0,
+            setpoint,
+            &mut PhotoAssembler::default(),
+            &mut outgoing,
+            &sinks(),
+        );
+        outgoing.into_iter().map(|p| p.command).collect()
+    }
+
+    #[test]
+    fn jogs_move_a_known_angle() {
+        let mut setpoint = Setpoint::default();
+        setpoint.apply_arm(&Arm::Jog(0.1));
+        assert_eq!(setpoint.arm, None);
+        setpoint.apply_arm(&Arm::Preset("open"));
+        setpoint.apply_arm(&Arm::Jog(0.1));
+        assert_eq!(setpoint.arm, Some(ArmSetpoint::Preset("open")));
+        setpoint.apply_arm(&Arm::Angle(0.95));
+        setpoint.apply_arm(&Arm::Jog(0.1));
+        assert_eq!(setpoint.arm, Some(ArmSetpoint::Angle(1.0)));
+    }
+
+    #[test]
+    fn reconnecting_resends_velocity_and_arm() {
+        let mut setpoint = Setpoint::default();
+        setpoint.linear = 0.5;
+        setpoint.apply_arm(&Arm::Preset("closed"));
+
+        let commands = take_control(&mut session(1), &mut setpoint);
+        assert_eq!(
+            commands,
+            vec![
+                Command::SetVelocity(Velocity {
+                    linear: 0.5,
+                    angular: 0.0
+                }),
+                Command::ServoPreset("closed".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn servo_reports_replace_the_arm_target() {
+        let mut setpoint = Setpoint::default();
+        setpoint.apply_arm(&Arm::Preset("closed"));
+        let mut first = session(1);
+        take_control(&mut first, &mut setpoint);
+
+        first.handle(
+            PacketToMaster::Servo(ServoPosition {
+                position: 0.2,
+                target: 0.8,
+            }),
+            0,
+            &mut setpoint,
+            &mut PhotoAssembler::default(),
+            &mut Vec::new(),
+            &sinks(),
+        );
+        assert_eq!(setpoint.arm, Some(ArmSetpoint::Angle(0.8)));
+
+        // A new session of a reconnect
+        let commands = take_control(&mut session(2), &mut setpoint);
+        assert_eq!(commands[1], Command::SetAngle(0.8));
+    }
+
+    #[test]
+    fn nothing_to_resend_for_an_untouched_arm() {
+        let commands = take_control(&mut session(1), &mut Setpoint::default());
+        assert_eq!(commands.len(), 1);
+        assert!(matches!(commands[0], Command::SetVelocity(_)));
+    }
+}
@@ -5,141 +5,172 @@ use bevy::{
         Extent3d, TextureDescriptor, TextureDimension, TextureFormat, TextureUsages,
     },
 };
-use borsh::BorshDeserialize;
-use borsh::BorshSerialize;
-use futures::SinkExt;
-use futures::StreamExt;
+use clap::Parser;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
 use tokio::sync::{
     broadcast,
     mpsc::{error::TryRecvError, Receiver, Sender},
+    watch,
 };
 use tokio::task::JoinSet;
-use tokio_tungstenite::{connect_async, tungstenite::protocol::Message};
 
-use decoder::run_decoder;
-use photosaver::run_photosaver;
-use proto::Velocity;
-use proto::{PacketToMaster, PacketToSlave};
+use decoder::{run_decoder, DecoderStats};
+use photosaver::{run_photosaver, run_videosaver};
 
 use common::{VIDEO_HEIGHT, VIDEO_WIDTH};
+use proto::{
+    now_millis, Health, PhotoFormat, PhotoOptions, PhotoResolution, ServoPosition, UplinkStats,
+};
+
+use input::{Controls, JOG_STEP};
+use link::{run_link, Endpoint, LinkState, Setpoint, Sinks, Telemetry};
+use trajectory::Trajectory;
+
+mod clock;
+mod commands;
+mod hud;
+mod input;
+mod link;
+mod photos;
+mod tls;
+mod trajectory;
+
+/// Side of the square trajectory plot, in pixels.
+const MAP_SIZE: u32 = 240;
+/// Between the video, the map and the panels, in pixels.
+const GAP: f32 = 10.0;
+/// Centers of the video and the map, side by side.
+const VIDEO_X: f32 = -(MAP_SIZE as f32 + GAP) / 2.0;
+const MAP_X: f32 = (VIDEO_WIDTH as f32 + GAP) / 2.0;
+
+/// Each option can also be set with an environment variable.
+#[derive(Parser, Debug)]
+struct Args {
+    /// Robot address, ws:// or wss://
+    #[arg(long, env = "CAPYBARA_URL", default_value = "ws://127.0.0.1:8264")]
+    url: String,
+    /// Token configured on the robot
+    #[arg(long, env = "CAPYBARA_WS_TOKEN", hide_env_values = true)]
+    token: Option<String>,
+    /// Robot's self-signed certificate, the only one trusted for wss://
+    #[arg(long, env = "CAPYBARA_CERT")]
+    cert: Option<PathBuf>,
+    /// Also save the video stream to photos/, AV1 as IVF files and MJPEG as
+    /// Matroska files
+    #[arg(long, env = "CAPYBARA_SAVE_VIDEO")]
+    save_video: bool,
+    /// Photo size, "stream" like the video or "full" for the largest the
+    /// camera can do
+    #[arg(long, env = "CAPYBARA_PHOTO_RESOLUTION", default_value = "stream")]
+    photo_resolution: PhotoResolution,
+    /// Photo encoding, "jpeg", "png" or lossless "webp"
+    #[arg(long, env = "CAPYBARA_PHOTO_FORMAT", default_value = "jpeg")]
+    photo_format: PhotoFormat,
+    /// JPEG quality of photos
+    #[arg(long, env = "CAPYBARA_PHOTO_QUALITY", default_value_t = 90,
+          value_parser = clap::value_parser!(u8).range(1..=100))]
+    photo_quality: u8,
+    /// Photos taken on every press
+    #[arg(long, env = "CAPYBARA_PHOTO_BURST", default_value_t = 1,
+          value_parser = clap::value_parser!(u8).range(1..=10))]
+    photo_burst: u8,
+    #[command(flatten)]
+    controls: Controls,
+}
 
 #[tokio::main]
 async fn main() -> Result<()> {
+    let args = Args::parse();
     let (bevyimage_tx, bevyimage_rx) = tokio::sync::mpsc::channel(1);
-    let (movecmd_tx, mut movecmd_rx) = tokio::sync::mpsc::channel::<CommandFromUI>(4);
+    let (movecmd_tx, movecmd_rx) = tokio::sync::mpsc::channel::<CommandFromUI>(4);
 
-    let ws_stream = match connect_async("ws://127.0.0.1:8264").await {
-        Ok((stream, _)) => stream,
-        Err(_) => {
-            return Ok(());
-        }
+    let connector = args
+        .cert
+        .as_deref()
+        .map(tls::pinned_connector)
+        .transpose()?;
+    let endpoint = Endpoint {
+        url: args.url,
+        token: args.token,
+        connector,
     };
-    let (mut sender, mut receiver) = ws_stream.split();
 
     let (encoder_tx, encoder_rx) = broadcast::channel(16);
     let (photo_data_tx, photo_data_rx) = broadcast::channel(32);
     let (image_tx, mut image_rx) = broadcast::channel(1);
+    let (link_tx, link_rx) = watch::channel(LinkState::Connecting);
+    let (decoder_stats_tx, decoder_stats_rx) = watch::channel(DecoderStats::default());
+    let (clock_tx, clock_rx) = watch::channel(None);
+    let (keyframe_tx, keyframe_rx) = tokio::sync::mpsc::channel(1);
+    let (codec_tx, codec_rx) = watch::channel(None);
+    let (telemetry_tx, telemetry_rx) = broadcast::channel(64);
+    let (setpoint_tx, setpoint_rx) = watch::channel(Setpoint::default());
+    let (servo_tx, servo_rx) = watch::channel(None);
+    let (bitrate_tx, bitrate_rx) = watch::channel(0);
+    let (uplink_tx, uplink_rx) = watch::channel(None);
+    let (health_tx, health_rx) = watch::channel(None);
 
     let mut tasks = JoinSet::<Result<()>>::new();
-    tasks.spawn(run_decoder(encoder_rx, image_tx));
+    if args.save_video {
+        tasks.spawn(run_videosaver(encoder_tx.subscribe()));
+    }
+    tasks.spawn(run_decoder(
+        encoder_rx,
+        codec_rx,
+        image_tx,
+        decoder_stats_tx,
+        keyframe_tx,
+    ));
     tasks.spawn(run_photosaver(photo_data_rx));
-
-    tasks.spawn(async move {
-        let mut linear = 0.0;
-        let mut angular = 0.0;
-        let mut arm = 2400.0;
-        loop {
-            let movecmd: CommandFromUI = match movecmd_rx.recv().await {
-                Some(mc) => mc,
-                None => return Ok(()),
-            };
-            if let Some(drive) = movecmd.drive {
-                match drive {
-                    Drive::Forward => linear = 0.05,
-                    Drive::Stop => linear = 0.0,
-                    Drive::Backward => linear = -0.05,
-                }
-            }
-            if let Some(rotate) = movecmd.rotate {
-                match rotate {
-                    Rotate::Left => angular = 0.1,
-                    Rotate::Stop => angular = 0.0,
-                    Rotate::Right => angular = -0.1,
-                }
-            }
-            if let Some(a) = movecmd.arm {
-                match a {
-                    Arm::Up => arm = 2300.0,
-                    Arm::Down => arm = 2500.0,
-                }
-            }
-            match movecmd.photo {
-                Some(_) => {
-                    let pkt = PacketToSlave::TakePhoto;
-                    let msg = Message::Binary(pkt.try_to_vec()?);
-                    if sender.send(msg).await.is_err() {
-                        return Ok(());
-                    };
-                }
-                None => {}
-            }
-            let velocity_cmd = Velocity { linear, angular };
-            let pkt = PacketToSlave::SetVelocity(velocity_cmd);
-            let msg = Message::Binary(pkt.try_to_vec()?);
-            if sender.send(msg).await.is_err() {
-                return Ok(());
-            };
-            if sender
-                .send(Message::Binary(PacketToSlave::SetAngle(arm).try_to_vec()?))
-                .await
-                .is_err()
-            {
-                return Ok(());
-            };
-        }
-        // loop {
-        //     tokio::time::sleep(tokio::time::Duration::from_secs(5)).await;
-        //     let pkt = PacketToSlave::TakePhoto;
-        //     let msg = Message::Binary(pkt.try_to_vec()?);
-        //     if sender.send(msg).await.is_err() {
-        //         return Ok(());
-        //     };
-        // }
-    });
-    tasks.spawn(async move {
-        while let Some(Ok(msg)) = receiver.next().await {
-            match msg {
-                Message::Binary(b) => {
-                    let cmd = PacketToMaster::try_from_slice(&b)?;
-                    match cmd {
-                        PacketToMaster::Video(vd) => {
-                            let _ = encoder_tx.send(vd);
-                        }
-                        PacketToMaster::Photo(pd) => {
-                            let _ = photo_data_tx.send(pd);
-                        }
-                        PacketToMaster::Odometry(o) => {
-                            info!("got odometry x = {}, y = {}, theta = {}", o.x, o.y, o.theta);
-                        }
-                    }
-                }
-                _ => return Ok(()),
-            }
-        }
-        Ok(())
-    });
+    let sinks = Sinks {
+        encoder_tx,
+        photo_data_tx,
+        state_tx: link_tx,
+        codec_tx,
+        decoder_stats_rx: decoder_stats_rx.clone(),
+        clock_tx,
+        telemetry_tx,
+        setpoint_tx,
+        servo_tx,
+        bitrate_tx,
+        uplink_tx,
+        health_tx,
+    };
+    let photo_options = PhotoOptions {
+        resolution: args.photo_resolution,
+        format: args.photo_format,
+        quality: args.photo_quality,
+        burst: args.photo_burst,
+    };
+    tasks.spawn(run_link(
+        endpoint,
+        photo_options,
+        movecmd_rx,
+        keyframe_rx,
+        sinks,
+    ));
     tasks.spawn(async move {
         loop {
-            let img = match image_rx.recv().await {
-                Ok(i) => i,
+            let decoded = match image_rx.recv().await {
+                Ok(d) => d,
                 Err(broadcast::error::RecvError::Lagged(l)) => {
                     error!("lagged for {l} frames");
                     continue;
                 }
                 Err(_) => return Ok(()),
             };
-            let rgba_img = image::DynamicImage::ImageRgb8(img).into_rgba8();
-            if bevyimage_tx.send(rgba_img.as_raw().clone()).await.is_err() {
+            let (width, height) = decoded.image.dimensions();
+            let rgba_img = image::DynamicImage::ImageRgb8(decoded.image).into_rgba8();
+            let picture = Picture {
+                width,
+                height,
+                rgba: rgba_img.into_raw(),
+                captured_at: clock_rx
+                    .borrow()
+                    .map(|offset| (decoded.captured_at as i64 - offset) as u64),
+            };
+            if bevyimage_tx.send(picture).await.is_err() {
                 return Ok(());
             }
         }
@@ -149,51 +180,201 @@ async fn main() -> Result<()> {
         .insert_resource(RemoteControl {
             rx: bevyimage_rx,
             tx: movecmd_tx,
+            link: link_rx,
+            decoder_stats: decoder_stats_rx,
+            setpoint: setpoint_rx,
+            servo: servo_rx,
+            bitrate: bitrate_rx,
+            uplink: uplink_rx,
+            health: health_rx,
+            image_handle: None,
+        })
+        .insert_resource(VideoMeter::new())
+        .insert_resource(args.controls)
+        .insert_resource(Map {
+            rx: telemetry_rx,
+            trajectory: Trajectory::default(),
             image_handle: None,
         })
-        .add_plugins(DefaultPlugins)
+        .add_plugins(DefaultPlugins.set(WindowPlugin {
+            primary_window: Some(Window {
+                title: "capybara control".to_string(),
+                ..default()
+            }),
+            ..default()
+        }))
         .add_startup_system(setup)
-        .add_systems((move_system, draw_system))
+        .add_startup_system(hud::setup_hud)
+        .add_systems((
+            move_system,
+            input::gamepad_system,
+            draw_system,
+            map_system,
+            hud::hud_system,
+            hud::help_system,
+        ))
         .run();
     Ok(())
 }
 
 #[derive(Resource)]
 struct RemoteControl {
-    rx: Receiver<Vec<u8>>,
+    rx: Receiver<Picture>,
     tx: Sender<CommandFromUI>,
+    link: watch::Receiver<LinkState>,
+    decoder_stats: watch::Receiver<DecoderStats>,
+    setpoint: watch::Receiver<Setpoint>,
+    servo: watch::Receiver<Option<ServoPosition>>,
+    /// Bits per second received from the robot.
+    bitrate: watch::Receiver<u64>,
+    uplink: watch::Receiver<Option<UplinkStats>>,
+    health: watch::Receiver<Option<Health>>,
     image_handle: Option<Handle<Image>>,
 }
 
+impl RemoteControl {
+    /// Returns false if there was nothing to send.
+    fn send(&self, command: CommandFromUI) -> bool {
+        if command.linear.is_none()
+            && command.angular.is_none()
+            && command.arm.is_none()
+            && command.photo.is_none()
+            && command.lease.is_none()
+        {
+            return false;
+        }
+        if let Err(err) = self.tx.blocking_send(command) {
+            warn!("Can't send MoveCommand: {}", err); // TODO: just ignore it?
+        }
+        true
+    }
+}
+
+#[derive(Resource)]
+struct Map {
+    rx: broadcast::Receiver<Telemetry>,
+    trajectory: Trajectory,
+    image_handle: Option<Handle<Image>>,
+}
+
+struct Picture {
+    width: u32,
+    height: u32,
+    rgba: Vec<u8>,
+    /// By local clock, unknown until the robot's clock offset is.
+    captured_at: Option<u64>,
+}
+
+/// Frame rate and latency of the video on screen, summarized every second.
+#[derive(Resource)]
+struct VideoMeter {
+    since: Instant,
+    frames: u32,
+    latency_sum: u64,
+    latencies: u32,
+    fps: f64,
+    summary: String,
+}
+
+impl VideoMeter {
+    fn new() -> Self {
+        Self {
+            since: Instant::now(),
+            frames: 0,
+            latency_sum: 0,
+            latencies: 0,
+            fps: 0.0,
+            summary: "no video".to_string(),
+        }
+    }
+
+    fn frame(&mut self, captured_at: Option<u64>) {
+        self.frames += 1;
+        if let Some(captured_at) = captured_at {
+            self.latency_sum += now_millis().saturating_sub(captured_at);
+            self.latencies += 1;
+        }
+    }
+
+    fn tick(&mut self, stats: &DecoderStats) {
+        let elapsed = self.since.elapsed();
+        if elapsed < Duration::from_secs(1) {
+            return;
+        }
+        self.fps = self.frames as f64 / elapsed.as_secs_f64();
+        let latency = match self.latencies {
+            0 => "?".to_string(),
+            n => (self.latency_sum / n as u64).to_string(),
+        };
+        self.summary = format!("latency {latency} ms, {} dropped", stats.dropped());
+        self.since = Instant::now();
+        self.frames = 0;
+        self.latency_sum = 0;
+        self.latencies = 0;
+    }
+}
+
+/// Changes to what the robot should do, `None` keeps the current value.
 #[derive(Default)]
-struct CommandFromUI {
-    drive: Option<Drive>,
-    rotate: Option<Rotate>,
-    arm: Option<Arm>,
-    photo: Option<()>,
+pub struct CommandFromUI {
+    /// m/s
+    pub linear: Option<f64>,
+    /// rad/s, positive turns left
+    pub angular: Option<f64>,
+    pub arm: Option<Arm>,
+    pub photo: Option<()>,
+    pub lease: Option<Lease>,
 }
 
-enum Drive {
-    Forward,
-    Backward,
-    Stop,
+pub enum Arm {
+    /// 0 is open, 1 is closed.
+    Angle(f64),
+    Jog(f64),
+    /// Named in the robot's config.
+    Preset(&'static str),
 }
 
-enum Rotate {
-    Left,
-    Right,
-    Stop,
+pub enum Lease {
+    Take,
+    Release,
 }
 
-enum Arm {
-    Up,
-    Down,
+fn setup(
+    mut commands: Commands,
+    mut images: ResMut<Assets<Image>>,
+    mut rc: ResMut<RemoteControl>,
+    mut map: ResMut<Map>,
+) {
+    let image_handle = images.add(texture(VIDEO_WIDTH, VIDEO_HEIGHT));
+    let map_handle = images.add(texture(MAP_SIZE, MAP_SIZE));
+
+    commands.spawn(Camera2dBundle::default());
+    // Video on the left, map on the right. The video takes the same place
+    // whatever the resolution the robot streams at.
+    commands.spawn(SpriteBundle {
+        sprite: Sprite {
+            custom_size: Some(Vec2::new(VIDEO_WIDTH as f32, VIDEO_HEIGHT as f32)),
+            ..default()
+        },
+        texture: image_handle.clone(),
+        transform: Transform::from_xyz(VIDEO_X, 0.0, 0.0),
+        ..default()
+    });
+    commands.spawn(SpriteBundle {
+        texture: map_handle.clone(),
+        transform: Transform::from_xyz(MAP_X, 0.0, 0.0),
+        ..default()
+    });
+
+    rc.image_handle = Some(image_handle);
+    map.image_handle = Some(map_handle);
 }
 
-fn setup(mut commands: Commands, mut images: ResMut<Assets<Image>>, mut rc: ResMut<RemoteControl>) {
+/// White RGBA texture to copy pictures into.
+fn texture(width: u32, height: u32) -> Image {
     let size = Extent3d {
-        width: VIDEO_WIDTH,
-        height: VIDEO_HEIGHT,
+        width,
+        height,
         ..default()
     };
 
@@ -217,59 +398,82 @@ fn setup(mut commands: Commands, mut images: ResMut<Assets<Image>>, mut rc: ResM
     // fill image.data with zeroes
     image.resize(size);
 
-    image.data = Vec::from([255; VIDEO_WIDTH as usize * VIDEO_HEIGHT as usize * 4]); // test data
-
-    let image_handle = images.add(image);
-
-    commands.spawn(Camera2dBundle::default());
-    commands.spawn(SpriteBundle {
-        texture: image_handle.clone(),
-        ..default()
-    });
-
-    rc.image_handle = Some(image_handle);
+    image.data = vec![255; width as usize * height as usize * 4]; // test data
+    image
 }
 
-fn draw_system(mut rc: ResMut<RemoteControl>, mut images: ResMut<Assets<Image>>) {
+fn draw_system(
+    mut rc: ResMut<RemoteControl>,
+    mut images: ResMut<Assets<Image>>,
+    mut meter: ResMut<VideoMeter>,
+) {
     match rc.rx.try_recv() {
-        Ok(data) => {
+        Ok(picture) => {
             let image_handle = rc.image_handle.as_ref().unwrap();
             let image = images.get_mut(image_handle).unwrap();
-            image.data = data;
+            let size = Extent3d {
+                width: picture.width,
+                height: picture.height,
+                ..default()
+            };
+            if image.texture_descriptor.size != size {
+                image.resize(size);
+            }
+            image.data = picture.rgba;
+            meter.frame(picture.captured_at);
         }
         Err(TryRecvError::Disconnected) => error!("Image channel is disconected."),
         _ => {}
     }
 }
 
-fn move_system(rc: Res<RemoteControl>, key_input: Res<Input<KeyCode>>) {
+fn map_system(mut map: ResMut<Map>, mut images: ResMut<Assets<Image>>) {
+    let mut changed = false;
+    loop {
+        match map.rx.try_recv() {
+            Ok(Telemetry::Odometry { pose, at }) => map.trajectory.odometry(pose, at),
+            Ok(Telemetry::Photo { pose }) => map.trajectory.photo(&pose),
+            Err(broadcast::error::TryRecvError::Lagged(l)) => {
+                error!("map lagged for {l} updates");
+                continue;
+            }
+            Err(_) => break,
+        }
+        changed = true;
+    }
+    if !changed {
+        return;
+    }
+    let plot = map.trajectory.render(MAP_SIZE);
+    let image_handle = map.image_handle.as_ref().unwrap();
+    let image = images.get_mut(image_handle).unwrap();
+    image.data = plot.into_raw();
+}
+
+fn move_system(rc: Res<RemoteControl>, controls: Res<Controls>, key_input: Res<Input<KeyCode>>) {
     let mut move_command = CommandFromUI::default();
     for key in key_input.get_just_pressed() {
         match key {
-            KeyCode::W => move_command.drive = Some(Drive::Forward),
-            KeyCode::S => move_command.drive = Some(Drive::Backward),
-            KeyCode::A => move_command.rotate = Some(Rotate::Left),
-            KeyCode::D => move_command.rotate = Some(Rotate::Right),
-            KeyCode::Q => move_command.arm = Some(Arm::Up),
-            KeyCode::E => move_command.arm = Some(Arm::Down),
+            KeyCode::W => move_command.linear = Some(controls.max_linear),
+            KeyCode::S => move_command.linear = Some(-controls.max_linear),
+            KeyCode::A => move_command.angular = Some(controls.max_angular),
+            KeyCode::D => move_command.angular = Some(-controls.max_angular),
+            KeyCode::Q => move_command.arm = Some(Arm::Preset("open")),
+            KeyCode::E => move_command.arm = Some(Arm::Preset("closed")),
+            KeyCode::R => move_command.arm = Some(Arm::Jog(-JOG_STEP)),
+            KeyCode::F => move_command.arm = Some(Arm::Jog(JOG_STEP)),
             KeyCode::P => move_command.photo = Some(()),
+            KeyCode::C => move_command.lease = Some(Lease::Take),
+            KeyCode::V => move_command.lease = Some(Lease::Release),
             _ => {}
         }
     }
     for key in key_input.get_just_released() {
         match key {
-            KeyCode::W | KeyCode::S => move_command.drive = Some(Drive::Stop),
-            KeyCode::A | KeyCode::D => move_command.rotate = Some(Rotate::Stop),
+            KeyCode::W | KeyCode::S => move_command.linear = Some(0.0),
+            KeyCode::A | KeyCode::D => move_command.angular = Some(0.0),
             _ => {}
         }
     }
-    if move_command.drive.is_some()
-        || move_command.rotate.is_some()
-        || move_command.arm.is_some()
-        || move_command.photo.is_some()
-    {
-        if let Err(err) = rc.tx.blocking_send(move_command) {
-            warn!("Can't send MoveCommand: {}", err); // TODO: just ignore it?
-        }
-    }
+    rc.send(move_command);
 }
@@ -0,0 +1,166 @@
+//! Puts photos back together from the chunks the robot sends. Partial photos
+//! outlive the connection, so a transfer cut by a reconnect is finished by
+//! the chunks the robot resends afterwards.
+
+use anyhow::{bail, Result};
+use borsh::BorshDeserialize;
+use std::collections::VecDeque;
+
+use proto::{Photo, PhotoChunk, MAX_PHOTO_SIZE, PHOTO_CHUNK_SIZE};
+
+/// Partial photos kept, the oldest are given up as the robot does.
+const MAX_TRANSFERS: usize = 16;
+/// Finished transfers remembered, so that chunks resent after a lost ack
+/// don't start them over.
+const RECENT_TRANSFERS: usize = 32;
+/// More chunks than that can't be a photo, a transfer that claims so would
+/// only take memory.
+const MAX_CHUNKS: u32 = MAX_PHOTO_SIZE.div_ceil(PHOTO_CHUNK_SIZE) as u32;
+
+struct Partial {
+    transfer: u32,
+    chunks: Vec<Option<Vec<u8>>>,
+    missing: usize,
+}
+
+#[derive(Default)]
+pub struct PhotoAssembler {
+    partial: VecDeque<Partial>,
+    finished: VecDeque<u32>,
+}
+
+impl PhotoAssembler {
+    /// Returns the photo once its last chunk arrives.
+    pub fn chunk(&mut self, chunk: PhotoChunk) -> Result<Option<Photo>> {
+        if self.finished.contains(&chunk.transfer) {
+            return Ok(None);
+        }
+        if chunk.count == 0 || chunk.count > MAX_CHUNKS {
+            bail!(
+                "transfer {} claims {} chunks, at most {MAX_CHUNKS} allowed",
+                chunk.transfer,
+                chunk.count
+            );
+        }
+        let pos = match self
+            .partial
+            .iter()
+            .position(|p| p.transfer == chunk.transfer)
+        {
+            Some(pos) => pos,
+            None => {
+                if self.partial.len() == MAX_TRANSFERS {
+                    self.partial.pop_front();
+                }
+                self.partial.push_back(Partial {
+                    transfer: chunk.transfer,
+                    chunks: vec![None; chunk.count as usize],
+                    missing: chunk.count as usize,
+                });
+                self.partial.len() - 1
+            }
+        };
+
+        let partial = &mut self.partial[pos];
+        match partial.chunks.get_mut(chunk.index as usize) {
+            Some(slot @ None) => {
+                *slot = Some(chunk.data);
+                partial.missing -= 1;
+            }
+            Some(Some(_)) => return Ok(None),
+            None => bail!(
+                "chunk {} of transfer {} is out of range",
+                chunk.index,
+                chunk.transfer
+            ),
+        }
+        if partial.missing > 0 {
+            return Ok(None);
+        }
+
+        let Some(partial) = self.partial.remove(pos) else {
+            return Ok(None);
+        };
+        if self.finished.len() == RECENT_TRANSFERS {
+            self.finished.pop_front();
+        }
+        self.finished.push_back(partial.transfer);
+        let data: Vec<u8> = partial.chunks.into_iter().flatten().flatten().collect();
+        Ok(Some(Photo::try_from_slice(&data)?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use borsh::BorshSerialize;
+    use proto::{Odometry, PhotoFormat};
+
+    fn chunks(transfer: u32) -> (Photo, Vec<PhotoChunk>) {
+        let photo = Photo {
+            id: 3,
+            captured_at: 1000,
+            pose: Odometry {
+                x: 1.0,
+                y: 2.0,
+                theta: 0.5,
+            },
+            width: 4,
+            height: 2,
+            format: PhotoFormat::Png,
+            data: (0..2 * PHOTO_CHUNK_SIZE).map(|i| i as u8).collect(),
+        };
+        let data = photo.try_to_vec().unwrap();
+        let count = data.len().div_ceil(PHOTO_CHUNK_SIZE) as u32;
+        let chunks = data
+            .chunks(PHOTO_CHUNK_SIZE)
+            .enumerate()
+            .map(|(index, data)| PhotoChunk {
+                transfer,
+                index: index as u32,
+                count,
+                data: data.to_vec(),
+            })
+            .collect();
+        (photo, chunks)
+    }
+
+    #[test]
+    fn assembles_out_of_order() {
+        let mut assembler = PhotoAssembler::default();
+        let (photo, mut chunks) = chunks(9);
+        chunks.reverse();
+        let last = chunks.pop().unwrap();
+        for chunk in chunks.iter().chain(&chunks) {
+            assert_eq!(assembler.chunk(chunk.clone()).unwrap(), None);
+        }
+        assert_eq!(assembler.chunk(last.clone()).unwrap(), Some(photo));
+        // Resent after a lost ack
+        assert_eq!(assembler.chunk(last).unwrap(), None);
+        assert!(assembler.partial.is_empty());
+    }
+
+    #[test]
+    fn rejects_bad_chunks() {
+        let mut assembler = PhotoAssembler::default();
+        let (_, chunks) = chunks(9);
+        let mut chunk = chunks[0].clone();
+        chunk.index = chunk.count;
+        assert!(assembler.chunk(chunk.clone()).is_err());
+        chunk.index = 0;
+        chunk.count = MAX_CHUNKS + 1;
+        assert!(assembler.chunk(chunk.clone()).is_err());
+        chunk.count = 0;
+        assert!(assembler.chunk(chunk).is_err());
+    }
+
+    #[test]
+    fn oldest_partial_is_given_up() {
+        let mut assembler = PhotoAssembler::default();
+        for transfer in 0..=MAX_TRANSFERS as u32 {
+            assembler.chunk(chunks(transfer).1.remove(0)).unwrap();
+        }
+        assert_eq!(assembler.partial.len(), MAX_TRANSFERS);
+        assert_eq!(assembler.partial[0].transfer, 1);
+    }
+}
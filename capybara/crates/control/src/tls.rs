@@ -0,0 +1,47 @@
+use anyhow::{bail, Context, Result};
+use rustls::client::{ServerCertVerified, ServerCertVerifier};
+use rustls::{Certificate, ClientConfig, Error, ServerName};
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::SystemTime;
+use tokio_tungstenite::Connector;
+
+/// Trusts exactly one certificate, the robot's self-signed one. Host names
+/// are not checked, so the robot can be reached by its IP address.
+struct PinnedCert(Certificate);
+
+impl ServerCertVerifier for PinnedCert {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        _server_name: &ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: SystemTime,
+    ) -> Result<ServerCertVerified, Error> {
+        if *end_entity == self.0 {
+            Ok(ServerCertVerified::assertion())
+        } else {
+            Err(Error::InvalidCertificateData(
+                "robot certificate doesn't match the pinned one".to_string(),
+            ))
+        }
+    }
+}
+
+/// TLS connector accepting only the certificate stored in `path`.
+pub fn pinned_connector(path: &Path) -> Result<Connector> {
+    let file = File::open(path).with_context(|| format!("can't open {}", path.display()))?;
+    let cert = match rustls_pemfile::certs(&mut BufReader::new(file))?.pop() {
+        Some(c) => Certificate(c),
+        None => bail!("no certificate in {}", path.display()),
+    };
+    let config = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_custom_certificate_verifier(Arc::new(PinnedCert(cert)))
+        .with_no_client_auth();
+    Ok(Connector::Rustls(Arc::new(config)))
+}
@@ -0,0 +1,212 @@
+//! Top-down plot of where the robot has been, for driving when the camera
+//! doesn't show much.
+
+use image::{Rgba, RgbaImage};
+use proto::Odometry;
+
+/// Smallest area shown, in metres, so the first steps are not blown up.
+const MIN_SPAN: f64 = 2.0;
+/// Path points closer than this to the previous one are not stored.
+const MIN_STEP: f64 = 0.02;
+/// Grid spacings in metres, the first one at least `MIN_GRID_PX` apart is used.
+const GRID_STEPS: [f64; 9] = [0.1, 0.2, 0.5, 1.0, 2.0, 5.0, 10.0, 20.0, 50.0];
+const MIN_GRID_PX: f64 = 20.0;
+
+const BACKGROUND: Rgba<u8> = Rgba([24, 24, 24, 255]);
+const GRID: Rgba<u8> = Rgba([56, 56, 56, 255]);
+const AXES: Rgba<u8> = Rgba([96, 96, 96, 255]);
+const PATH: Rgba<u8> = Rgba([64, 160, 255, 255]);
+const PHOTO: Rgba<u8> = Rgba([255, 200, 0, 255]);
+const ROBOT: Rgba<u8> = Rgba([255, 64, 64, 255]);
+
+#[derive(Default)]
+pub struct Trajectory {
+    path: Vec<(f64, f64)>,
+    photos: Vec<(f64, f64)>,
+    /// Latest pose and when the robot sent it, by its clock.
+    last: Option<(Odometry, u64)>,
+    /// Metres per second.
+    speed: f64,
+}
+
+impl Trajectory {
+    pub fn odometry(&mut self, pose: Odometry, at: u64) {
+        if let Some((last, last_at)) = &self.last {
+            let dt = at.saturating_sub(*last_at);
+            if dt > 0 {
+                self.speed = pose.distance_to(last) * 1000.0 / dt as f64;
+            }
+        }
+        let moved = self
+            .path
+            .last()
+            .is_none_or(|&(x, y)| (pose.x - x).hypot(pose.y - y) >= MIN_STEP);
+        if moved {
+            self.path.push((pose.x, pose.y));
+        }
+        self.last = Some((pose, at));
+    }
+
+    pub fn photo(&mut self, pose: &Odometry) {
+        self.photos.push((pose.x, pose.y));
+    }
+
+    pub fn summary(&self) -> String {
+        match &self.last {
+            Some((pose, _)) => format!(
+                "x {:.2} m, y {:.2} m, θ {:.0}°, {:.2} m/s",
+                pose.x,
+                pose.y,
+                pose.theta.to_degrees().rem_euclid(360.0),
+                self.speed
+            ),
+            None => "no odometry".to_string(),
+        }
+    }
+
+    /// Draws the path in the odometry frame, x to the right, zoomed out to fit
+    /// all of it.
+    pub fn render(&self, size: u32) -> RgbaImage {
+        let mut img = RgbaImage::from_pixel(size, size, BACKGROUND);
+        let Some((pose, _)) = &self.last else {
+            return img;
+        };
+
+        let points = self.path.iter().chain(&self.photos);
+        let (mut min_x, mut max_x, mut min_y, mut max_y) = (pose.x, pose.x, pose.y, pose.y);
+        for &(x, y) in points {
+            min_x = min_x.min(x);
+            max_x = max_x.max(x);
+            min_y = min_y.min(y);
+            max_y = max_y.max(y);
+        }
+        let span = (max_x - min_x).max(max_y - min_y).max(MIN_SPAN) * 1.2;
+        let (cx, cy) = ((min_x + max_x) / 2.0, (min_y + max_y) / 2.0);
+        let scale = size as f64 / span;
+        let half = size as f64 / 2.0;
+        let to_px = |x: f64, y: f64| ((x - cx) * scale + half, half - (y - cy) * scale);
+
+        let step = GRID_STEPS
+            .into_iter()
+            .find(|s| s * scale >= MIN_GRID_PX)
+            .unwrap_or(GRID_STEPS[GRID_STEPS.len() - 1]);
+        let first = ((cx - span / 2.0) / step).floor() as i64;
+        let last = ((cx + span / 2.0) / step).ceil() as i64;
+        for i in first..=last {
+            let (px, _) = to_px(i as f64 * step, 0.0);
+            let color = if i == 0 { AXES } else { GRID };
+            line(&mut img, (px, 0.0), (px, size as f64), color);
+        }
+        let first = ((cy - span / 2.0) / step).floor() as i64;
+        let last = ((cy + span / 2.0) / step).ceil() as i64;
+        for i in first..=last {
+            let (_, py) = to_px(0.0, i as f64 * step);
+            let color = if i == 0 { AXES } else { GRID };
+            line(&mut img, (0.0, py), (size as f64, py), color);
+        }
+
+        for pair in self.path.windows(2) {
+            line(
+                &mut img,
+                to_px(pair[0].0, pair[0].1),
+                to_px(pair[1].0, pair[1].1),
+                PATH,
+            );
+        }
+        for &(x, y) in &self.photos {
+            square(&mut img, to_px(x, y), 3, PHOTO);
+        }
+
+        let robot = to_px(pose.x, pose.y);
+        let heading = (
+            robot.0 + 10.0 * pose.theta.cos(),
+            robot.1 - 10.0 * pose.theta.sin(),
+        );
+        line(&mut img, robot, heading, ROBOT);
+        square(&mut img, robot, 2, ROBOT);
+        img
+    }
+}
+
+fn put(img: &mut RgbaImage, x: i64, y: i64, color: Rgba<u8>) {
+    if x >= 0 && y >= 0 && x < img.width() as i64 && y < img.height() as i64 {
+        img.put_pixel(x as u32, y as u32, color);
+    }
+}
+
+fn line(img: &mut RgbaImage, from: (f64, f64), to: (f64, f64), color: Rgba<u8>) {
+    let steps = (to.0 - from.0)
+        .abs()
+        .max((to.1 - from.1).abs())
+        .ceil()
+        .max(1.0);
+    for i in 0..=steps as i64 {
+        let t = i as f64 / steps;
+        let x = from.0 + (to.0 - from.0) * t;
+        let y = from.1 + (to.1 - from.1) * t;
+        put(img, x.round() as i64, y.round() as i64, color);
+    }
+}
+
+fn square(img: &mut RgbaImage, center: (f64, f64), radius: i64, color: Rgba<u8>) {
+    let (cx, cy) = (center.0.round() as i64, center.1.round() as i64);
+    for y in cy - radius..=cy + radius {
+        for x in cx - radius..=cx + radius {
+            put(img, x, y, color);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pose(x: f64, y: f64, theta: f64) -> Odometry {
+        Odometry { x, y, theta }
+    }
+
+    #[test]
+    fn thins_the_path() {
+        let mut trajectory = Trajectory::default();
+        trajectory.odometry(pose(0.0, 0.0, 0.0), 0);
+        trajectory.odometry(pose(0.01, 0.0, 0.0), 100);
+        trajectory.odometry(pose(0.015, 0.01, 0.0), 200);
+        assert_eq!(trajectory.path, [(0.0, 0.0)]);
+
+        // Measured from the last stored point, not the last pose
+        trajectory.odometry(pose(0.02, 0.0, 0.0), 300);
+        trajectory.odometry(pose(1.0, 1.0, 0.0), 400);
+        assert_eq!(trajectory.path, [(0.0, 0.0), (0.02, 0.0), (1.0, 1.0)]);
+    }
+
+    #[test]
+    fn summary() {
+        let mut trajectory = Trajectory::default();
+        assert_eq!(trajectory.summary(), "no odometry");
+
+        trajectory.odometry(pose(1.0, 2.0, 0.0), 1000);
+        trajectory.odometry(pose(1.0, 2.5, -std::f64::consts::FRAC_PI_2), 1500);
+        assert_eq!(trajectory.summary(), "x 1.00 m, y 2.50 m, θ 270°, 1.00 m/s");
+
+        // Same timestamp says nothing about speed
+        trajectory.odometry(pose(1.0, 3.0, 0.0), 1500);
+        assert!(trajectory.summary().ends_with("1.00 m/s"));
+    }
+
+    #[test]
+    fn render() {
+        let mut trajectory = Trajectory::default();
+        assert!(trajectory.render(64).pixels().all(|&p| p == BACKGROUND));
+
+        trajectory.odometry(pose(0.0, 0.0, 0.0), 0);
+        trajectory.photo(&pose(0.0, 0.0, 0.0));
+        trajectory.odometry(pose(0.5, 0.0, 0.0), 1000);
+        let img = trajectory.render(100);
+        // Robot at x 0.5 is right of the centre of the path, photo left of it
+        let scale = 100.0 / (MIN_SPAN * 1.2);
+        let robot = (50.0 + 0.25 * scale).round() as u32;
+        let photo = (50.0 - 0.25 * scale).round() as u32;
+        assert_eq!(*img.get_pixel(robot, 50), ROBOT);
+        assert_eq!(*img.get_pixel(photo, 50), PHOTO);
+    }
+}
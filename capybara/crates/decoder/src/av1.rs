@@ -0,0 +1,77 @@
+use anyhow::{bail, Result};
+use dav1d::{Decoder, Error::Again, PlanarImageComponent};
+use image::RgbImage;
+
+use crate::yuvrgb;
+use crate::{DecodedFrame, VideoDecoder};
+
+pub struct Av1Decoder {
+    decoder: Decoder,
+}
+
+impl Av1Decoder {
+    pub fn new() -> Result<Self> {
+        let mut decoder_settings = dav1d::Settings::new();
+        decoder_settings.set_max_frame_delay(1);
+        Ok(Self {
+            decoder: Decoder::with_settings(&decoder_settings)?,
+        })
+    }
+}
+
+impl VideoDecoder for Av1Decoder {
+    fn decode(&mut self, data: Vec<u8>, captured_at: u64) -> Result<Vec<DecodedFrame>> {
+        let mut decoded = Vec::new();
+        // dav1d hands the timestamp back with the picture
+        let timestamp = captured_at as i64;
+        while let Err(send_data_err) =
+            self.decoder
+                .send_data(data.clone(), None, Some(timestamp), None)
+        {
+            match send_data_err {
+                Again => {
+                    while let Err(send_pending_err) = self.decoder.send_pending_data() {
+                        match send_pending_err {
+                            Again => {
+                                if let Ok(picture) = self.decoder.get_picture() {
+                                    let captured_at =
+                                        picture.timestamp().unwrap_or(timestamp) as u64;
+                                    decoded.push(DecodedFrame {
+                                        image: convert_picture(picture)?,
+                                        captured_at,
+                                    });
+                                }
+                            }
+                            _ => bail!("{}", send_pending_err),
+                        }
+                    }
+                }
+                _ => bail!("{}", send_data_err),
+            }
+        }
+        Ok(decoded)
+    }
+}
+
+fn convert_picture(picture: dav1d::Picture) -> Result<RgbImage> {
+    let planes = &[
+        picture.plane(PlanarImageComponent::Y),
+        picture.plane(PlanarImageComponent::U),
+        picture.plane(PlanarImageComponent::V),
+    ];
+
+    let src_buf = planes.iter().map(AsRef::as_ref).collect::<Vec<_>>();
+    let strides = &[
+        picture.stride(PlanarImageComponent::Y) as usize,
+        picture.stride(PlanarImageComponent::U) as usize,
+        picture.stride(PlanarImageComponent::V) as usize,
+    ];
+
+    let (width, height) = (picture.width(), picture.height());
+    let rgb_buf = yuvrgb::yuv_to_bgra(&src_buf, strides, width, height)?;
+
+    match RgbImage::from_raw(width, height, rgb_buf) {
+        Some(i) => Ok(i),
+        None => bail!("image container too small"),
+    }
+}
@@ -1,19 +1,82 @@
-use anyhow::{bail, Result};
+use anyhow::Result;
 use crossbeam::channel::unbounded;
-use dav1d::{Decoder, Error::Again, Error::InvalidArgument, PlanarImageComponent};
 use futures::future::join_all;
 use image::RgbImage;
 use log::*;
-use tokio::sync::broadcast;
+use tokio::sync::{broadcast, mpsc, watch};
 use tokio::task::{spawn, spawn_blocking, JoinHandle};
 
-use common::{VIDEO_HEIGHT, VIDEO_WIDTH};
+use proto::{VideoCodec, VideoFrame};
 
+#[cfg(feature = "av1")]
+mod av1;
+mod mjpeg;
+#[cfg(feature = "av1")]
 mod yuvrgb;
 
+#[derive(Clone)]
+pub struct DecodedFrame {
+    pub image: RgbImage,
+    /// By the robot's clock, see [`VideoFrame::captured_at`].
+    pub captured_at: u64,
+}
+
+pub trait VideoDecoder: Send {
+    /// Fails if the packet is corrupted or doesn't follow the previous ones.
+    /// Decoders with a delay may return pictures of earlier packets.
+    fn decode(&mut self, data: Vec<u8>, captured_at: u64) -> Result<Vec<DecodedFrame>>;
+}
+
+/// Running totals of video packets.
+#[derive(Clone, Copy, Default, Debug)]
+pub struct DecoderStats {
+    pub decoded: u64,
+    /// Never arrived, judging by sequence numbers.
+    pub lost: u64,
+    /// Arrived while waiting for a keyframe.
+    pub skipped: u64,
+    /// Undecodable.
+    pub errors: u64,
+}
+
+impl DecoderStats {
+    /// Packets that didn't make it to the screen.
+    pub fn dropped(&self) -> u64 {
+        self.lost + self.skipped + self.errors
+    }
+}
+
+/// Codecs this build can decode, the preferred one first.
+pub fn supported_codecs() -> Vec<VideoCodec> {
+    vec![
+        #[cfg(feature = "av1")]
+        VideoCodec::Av1,
+        VideoCodec::Mjpeg,
+    ]
+}
+
+pub fn new_decoder(codec: VideoCodec) -> Result<Box<dyn VideoDecoder>> {
+    Ok(match codec {
+        #[cfg(feature = "av1")]
+        VideoCodec::Av1 => Box::new(av1::Av1Decoder::new()?),
+        #[cfg(not(feature = "av1"))]
+        VideoCodec::Av1 => anyhow::bail!("{codec} support is not built in"),
+        VideoCodec::Mjpeg => Box::new(mjpeg::MjpegDecoder),
+    })
+}
+
+/// Decodes video packets with the codec from `codec_rx`, which is set once
+/// it is agreed with the robot. Frame size follows the stream, so it may
+/// change when the robot adapts quality.
+///
+/// After a lost or undecodable packet the following ones are dropped until
+/// a keyframe arrives, and `keyframe_tx` asks for one.
 pub async fn run_decoder(
-    mut data_rx: broadcast::Receiver<Vec<u8>>,
-    image_tx: broadcast::Sender<RgbImage>,
+    mut data_rx: broadcast::Receiver<VideoFrame>,
+    mut codec_rx: watch::Receiver<Option<VideoCodec>>,
+    image_tx: broadcast::Sender<DecodedFrame>,
+    stats_tx: watch::Sender<DecoderStats>,
+    keyframe_tx: mpsc::Sender<()>,
 ) -> Result<()> {
     let (pkt_tx, pkt_rx) = unbounded();
     let data_task = spawn(async move {
@@ -33,59 +96,63 @@ pub async fn run_decoder(
     });
 
     let decoder_task: JoinHandle<Result<()>> = spawn_blocking(move || {
-        let handle_picture = |picture: dav1d::Picture| {
-            let planes = &[
-                picture.plane(PlanarImageComponent::Y),
-                picture.plane(PlanarImageComponent::U),
-                picture.plane(PlanarImageComponent::V),
-            ];
-
-            let src_buf = planes.iter().map(AsRef::as_ref).collect::<Vec<_>>();
-            let strides = &[
-                picture.stride(PlanarImageComponent::Y) as usize,
-                picture.stride(PlanarImageComponent::U) as usize,
-                picture.stride(PlanarImageComponent::V) as usize,
-            ];
-
-            let rgb_buf = yuvrgb::yuv_to_bgra(&src_buf, strides)?;
-
-            let image = match RgbImage::from_raw(VIDEO_WIDTH, VIDEO_HEIGHT, rgb_buf) {
-                Some(i) => i,
-                None => bail!("image container too small"),
-            };
-            let _ = image_tx.send(image);
-            Ok(())
-        };
-        let mut decoder_settings = dav1d::Settings::new();
-        decoder_settings.set_max_frame_delay(1);
-
-        let mut decoder = Decoder::with_settings(&decoder_settings)?;
+        let mut decoder: Option<Box<dyn VideoDecoder>> = None;
+        let mut expected_seq: Option<u32> = None;
+        // Nothing can be decoded before the first keyframe
+        let mut need_keyframe = true;
         loop {
-            let data = match pkt_rx.recv() {
-                Ok(d) => d,
+            let frame: VideoFrame = match pkt_rx.recv() {
+                Ok(f) => f,
                 Err(_) => return Ok(()),
             };
 
-            while let Err(send_data_err) = decoder.send_data(data.clone(), None, None, None) {
-                match send_data_err {
-                    Again => {
-                        while let Err(send_pending_err) = decoder.send_pending_data() {
-                            match send_pending_err {
-                                Again => match decoder.get_picture() {
-                                    Ok(picture) => {
-                                        handle_picture(picture)?;
-                                    }
-                                    Err(_) => {}
-                                },
-                                _ => bail!("{}", send_pending_err),
-                            }
-                        }
+            if codec_rx.has_changed().unwrap_or(false) {
+                let codec = *codec_rx.borrow_and_update();
+                decoder = match codec {
+                    Some(c) => {
+                        info!("decoding {c} video");
+                        Some(new_decoder(c)?)
                     }
-                    InvalidArgument => {
-                        warn!("encoder InvalidArgument");
-                        break;
+                    None => None,
+                };
+                expected_seq = None;
+                need_keyframe = true;
+            }
+            let Some(decoder) = decoder.as_mut() else {
+                continue;
+            };
+
+            if let Some(seq) = expected_seq {
+                if frame.seq != seq {
+                    let lost = frame.seq.wrapping_sub(seq);
+                    warn!("lost {lost} video packets");
+                    stats_tx.send_modify(|s| s.lost += lost as u64);
+                    need_keyframe = true;
+                }
+            }
+            expected_seq = Some(frame.seq.wrapping_add(1));
+            if need_keyframe {
+                if !frame.keyframe {
+                    debug!("waiting for keyframe, dropping packet {}", frame.seq);
+                    stats_tx.send_modify(|s| s.skipped += 1);
+                    let _ = keyframe_tx.try_send(());
+                    continue;
+                }
+                need_keyframe = false;
+            }
+
+            match decoder.decode(frame.data, frame.captured_at) {
+                Ok(decoded) => {
+                    stats_tx.send_modify(|s| s.decoded += decoded.len() as u64);
+                    for d in decoded {
+                        let _ = image_tx.send(d);
                     }
-                    _ => bail!("{}", send_data_err),
+                }
+                Err(e) => {
+                    warn!("can't decode video packet {}: {e}", frame.seq);
+                    stats_tx.send_modify(|s| s.errors += 1);
+                    need_keyframe = true;
+                    let _ = keyframe_tx.try_send(());
                 }
             }
         }
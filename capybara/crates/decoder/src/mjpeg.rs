@@ -0,0 +1,16 @@
+use anyhow::Result;
+use image::ImageFormat;
+
+use crate::{DecodedFrame, VideoDecoder};
+
+pub struct MjpegDecoder;
+
+impl VideoDecoder for MjpegDecoder {
+    fn decode(&mut self, data: Vec<u8>, captured_at: u64) -> Result<Vec<DecodedFrame>> {
+        let image = image::load_from_memory_with_format(&data, ImageFormat::Jpeg)?;
+        Ok(vec![DecodedFrame {
+            image: image.to_rgb8(),
+            captured_at,
+        }])
+    }
+}
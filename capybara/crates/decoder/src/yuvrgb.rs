@@ -1,8 +1,6 @@
 use anyhow::Result;
 use dcv_color_primitives as dcp;
 
-use common::{VIDEO_HEIGHT, VIDEO_WIDTH};
-
 use dcp::{ColorSpace, ImageFormat, PixelFormat};
 
 const YUV_FORMAT: ImageFormat = ImageFormat {
@@ -23,15 +21,20 @@ const RGB_FORMAT: ImageFormat = ImageFormat {
     num_planes: 1,
 };
 
-pub fn yuv_to_bgra(src_yuv_buf: &Vec<&[u8]>, yuv_strides: &[usize; 3]) -> Result<Vec<u8>> {
+pub fn yuv_to_bgra(
+    src_yuv_buf: &Vec<&[u8]>,
+    yuv_strides: &[usize; 3],
+    width: u32,
+    height: u32,
+) -> Result<Vec<u8>> {
     dcp::initialize();
 
-    let mut bgra_buf: Vec<_> = vec![0u8; VIDEO_WIDTH as usize * VIDEO_HEIGHT as usize* 4];
+    let mut bgra_buf: Vec<_> = vec![0u8; width as usize * height as usize * 4];
     let dst_bgra_buf = &mut [&mut bgra_buf[..]];
     let bgra_strides = &[0usize; 1];
     dcp::convert_image(
-        VIDEO_WIDTH,
-        VIDEO_HEIGHT,
+        width,
+        height,
         &YUV_FORMAT,
         Some(yuv_strides),
         src_yuv_buf,
@@ -41,11 +44,11 @@ pub fn yuv_to_bgra(src_yuv_buf: &Vec<&[u8]>, yuv_strides: &[usize; 3]) -> Result
     )?;
 
     let src_bgra_buf = &[&bgra_buf[..]];
-    let mut rgb_buf: Vec<_> = vec![0u8; VIDEO_WIDTH  as usize * VIDEO_HEIGHT as usize * 3];
+    let mut rgb_buf: Vec<_> = vec![0u8; width as usize * height as usize * 3];
     let dst_rgb_buf = &mut [&mut rgb_buf[..]];
     dcp::convert_image(
-        VIDEO_WIDTH,
-        VIDEO_HEIGHT,
+        width,
+        height,
         &BGRA_FORMAT,
         Some(bgra_strides),
         src_bgra_buf,
@@ -0,0 +1,154 @@
+use anyhow::{bail, Result};
+use image::Rgb;
+use log::*;
+use rav1e::config::SpeedSettings;
+use rav1e::prelude::*;
+use std::collections::VecDeque;
+
+use common::backend::CameraFrame;
+
+use crate::{EncodedPacket, EncoderParams, VideoEncoder};
+
+fn encoder_config(params: &EncoderParams) -> Config {
+    // Encoder configuration
+    let mut enc = EncoderConfig::default();
+
+    // Basic settings
+    enc.time_base = Rational {
+        num: 1,
+        den: params.fps,
+    };
+    enc.width = params.width as usize;
+    enc.height = params.height as usize;
+    enc.chroma_sampling = ChromaSampling::Cs444;
+
+    // Raspberry Pi
+    enc.speed_settings = SpeedSettings::from_preset(10);
+    enc.tiles = 4; // 4 cpu
+
+    enc.bitrate = params.bitrate;
+    enc.quantizer = params.quantizer;
+    enc.min_quantizer = params.min_quantizer;
+
+    // Low latency
+    enc.speed_settings.rdo_lookahead_frames = 1;
+    enc.low_latency = true;
+    enc.max_key_frame_interval = params.key_frame_interval;
+
+    Config::new().with_encoder_config(enc).with_threads(4)
+}
+
+pub struct Av1Encoder {
+    ctx: Context<u8>,
+    width: usize,
+    frames_sent: u64,
+    /// Capture times of frames inside the encoder by input frame number.
+    captured_at: VecDeque<(u64, u64)>,
+}
+
+impl Av1Encoder {
+    pub fn new(params: &EncoderParams) -> Result<Self> {
+        Ok(Self {
+            ctx: encoder_config(params).new_context()?,
+            width: params.width as usize,
+            frames_sent: 0,
+            captured_at: VecDeque::new(),
+        })
+    }
+}
+
+impl VideoEncoder for Av1Encoder {
+    fn encode(&mut self, frame: &CameraFrame, keyframe: bool) -> Result<Option<EncodedPacket>> {
+        // Convert RgbImage to Frame
+        let mut r_slice: Vec<u8> = vec![];
+        let mut g_slice: Vec<u8> = vec![];
+        let mut b_slice: Vec<u8> = vec![];
+        for pixel in frame.image.pixels() {
+            let (r, g, b) = to_ycbcr(pixel);
+            r_slice.push(r);
+            g_slice.push(g);
+            b_slice.push(b);
+        }
+        let planes = vec![r_slice, g_slice, b_slice];
+        let mut video_frame = self.ctx.new_frame();
+        for (dst, src) in video_frame.planes.iter_mut().zip(planes) {
+            dst.copy_from_raw_u8(&src, self.width, 1);
+        }
+
+        let mut frame_params = FrameParameters::default();
+        if keyframe {
+            frame_params.frame_type_override = FrameTypeOverride::Key;
+        }
+
+        // Send frame to encoder
+        match self.ctx.send_frame((video_frame, frame_params)) {
+            Ok(_) => {
+                debug!("queued frame");
+                self.captured_at
+                    .push_back((self.frames_sent, frame.captured_at));
+                self.frames_sent += 1;
+            }
+            Err(e) => match e {
+                EncoderStatus::EnoughData => {
+                    warn!("unable to append frame to the internal queue");
+                }
+                _ => {
+                    bail!("unable to send frame");
+                }
+            },
+        }
+
+        // Receive data from encoder
+        match self.ctx.receive_packet() {
+            Ok(pkt) => {
+                while self
+                    .captured_at
+                    .front()
+                    .is_some_and(|&(n, _)| n < pkt.input_frameno)
+                {
+                    self.captured_at.pop_front();
+                }
+                let captured_at = match self.captured_at.front() {
+                    Some(&(n, t)) if n == pkt.input_frameno => t,
+                    _ => frame.captured_at,
+                };
+                Ok(Some(EncodedPacket {
+                    keyframe: pkt.frame_type == FrameType::KEY,
+                    captured_at,
+                    data: pkt.data,
+                }))
+            }
+            Err(e) => match e {
+                EncoderStatus::LimitReached => {
+                    warn!("read thread: Limit reached");
+                    Ok(None)
+                }
+                EncoderStatus::Encoded => {
+                    debug!("read thread: Encoded");
+                    Ok(None)
+                }
+                EncoderStatus::NeedMoreData => {
+                    debug!("read thread: Need more data");
+                    Ok(None)
+                }
+                _ => {
+                    bail!("unable to receive packet");
+                }
+            },
+        }
+    }
+}
+
+fn clamp(val: f32) -> u8 {
+    (val.round() as u8).max(0_u8).min(255_u8)
+}
+
+fn to_ycbcr(pixel: &Rgb<u8>) -> (u8, u8, u8) {
+    let [r, g, b] = pixel.0;
+
+    let y = 16_f32 + (65.481 * r as f32 + 128.553 * g as f32 + 24.966 * b as f32) / 255_f32;
+    let cb = 128_f32 + (-37.797 * r as f32 - 74.203 * g as f32 + 112.000 * b as f32) / 255_f32;
+    let cr = 128_f32 + (112.000 * r as f32 - 93.786 * g as f32 - 18.214 * b as f32) / 255_f32;
+
+    (clamp(y), clamp(cb), clamp(cr))
+}
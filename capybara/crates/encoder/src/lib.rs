@@ -1,51 +1,102 @@
-use anyhow::{bail, Error, Result};
+use anyhow::{Error, Result};
 use crossbeam::channel::unbounded;
 use futures::future::join_all;
-use image::{Rgb, RgbImage};
 use log::*;
-use rav1e::config::SpeedSettings;
-use rav1e::prelude::*;
-use tokio::sync::{broadcast, watch};
+use tokio::sync::{broadcast, mpsc, watch};
 use tokio::task::{spawn, spawn_blocking, JoinHandle};
+use tokio::time::Instant;
+
+use common::backend::CameraFrame;
+use proto::{VideoCodec, VideoFrame};
+
+#[cfg(feature = "av1")]
+mod av1;
+mod mjpeg;
+
+/// Encoder settings that can be changed while streaming.
+#[derive(Clone, Debug, PartialEq)]
+pub struct EncoderParams {
+    pub width: u32,
+    pub height: u32,
+    pub fps: u64,
+    /// Target bitrate in kbps, 0 to use constant `quantizer`.
+    pub bitrate: i32,
+    pub quantizer: usize,
+    pub min_quantizer: u8,
+    pub key_frame_interval: u64,
+}
 
-use common::{VIDEO_HEIGHT, VIDEO_WIDTH};
-
-pub async fn run_encoder(
-    mut cam_rx: watch::Receiver<RgbImage>,
-    data_tx: broadcast::Sender<Vec<u8>>,
-) -> Result<()> {
-    // Encoder configuration
-    let mut enc = EncoderConfig::default();
-
-    // Basic settings
-    enc.time_base = Rational { num: 1, den: 10 }; // 10 FPS
-    enc.width = VIDEO_WIDTH as usize;
-    enc.height = VIDEO_HEIGHT as usize;
-    enc.chroma_sampling = ChromaSampling::Cs444;
-
-    // Raspberry Pi
-    enc.speed_settings = SpeedSettings::from_preset(10);
-    enc.tiles = 4; // 4 cpu
+pub struct EncodedPacket {
+    /// Decodable without any previous packet.
+    pub keyframe: bool,
+    /// Of the camera frame in this packet.
+    pub captured_at: u64,
+    pub data: Vec<u8>,
+}
 
-    // Low birate
-    enc.bitrate = 30;
-    enc.min_quantizer = 160;
+pub trait VideoEncoder: Send {
+    /// Returns `None` while the encoder buffers frames. `keyframe` forces
+    /// this frame to be a keyframe.
+    fn encode(&mut self, frame: &CameraFrame, keyframe: bool) -> Result<Option<EncodedPacket>>;
+}
 
-    // Low latency
-    enc.speed_settings.rdo_lookahead_frames = 1;
-    enc.low_latency = true;
-    enc.max_key_frame_interval = 50;
+/// Codecs this build can encode, the preferred one first.
+pub fn supported_codecs() -> Vec<VideoCodec> {
+    vec![
+        #[cfg(feature = "av1")]
+        VideoCodec::Av1,
+        VideoCodec::Mjpeg,
+    ]
+}
 
-    let cfg = Config::new().with_encoder_config(enc).with_threads(4);
+pub fn new_encoder(codec: VideoCodec, params: &EncoderParams) -> Result<Box<dyn VideoEncoder>> {
+    Ok(match codec {
+        #[cfg(feature = "av1")]
+        VideoCodec::Av1 => Box::new(av1::Av1Encoder::new(params)?),
+        #[cfg(not(feature = "av1"))]
+        VideoCodec::Av1 => anyhow::bail!("{codec} support is not built in"),
+        VideoCodec::Mjpeg => Box::new(mjpeg::MjpegEncoder::new(params)),
+    })
+}
 
+/// Encodes camera frames, restarting the encoder with a keyframe whenever
+/// `params_rx` changes. A message on `keyframe_rx` makes the next frame a
+/// keyframe. Frames waiting for the encoder are counted in `queue_tx`.
+/// Frames are skipped while `codec` is not in `codecs_rx`, if given.
+pub async fn run_encoder(
+    codec: VideoCodec,
+    codecs_rx: Option<watch::Receiver<Vec<VideoCodec>>>,
+    mut cam_rx: watch::Receiver<CameraFrame>,
+    mut params_rx: watch::Receiver<EncoderParams>,
+    mut keyframe_rx: mpsc::Receiver<()>,
+    data_tx: broadcast::Sender<VideoFrame>,
+    queue_tx: watch::Sender<u32>,
+) -> Result<()> {
+    let frame_params_rx = params_rx.clone();
     let (frame_tx, frame_rx) = unbounded();
     let frame_task: JoinHandle<Result<(), Error>> = spawn(async move {
+        let mut last_frame: Option<Instant> = None;
         while cam_rx.changed().await.is_ok() {
-            let img = (*cam_rx.borrow()).clone();
-            let resized = image::DynamicImage::ImageRgb8(img)
-                .resize(VIDEO_WIDTH, VIDEO_HEIGHT, image::imageops::Triangle)
+            if codecs_rx
+                .as_ref()
+                .is_some_and(|rx| !rx.borrow().contains(&codec))
+            {
+                continue;
+            }
+            let (width, height, fps) = {
+                let p = frame_params_rx.borrow();
+                (p.width, p.height, p.fps)
+            };
+            // Some slack, so camera jitter doesn't halve the frame rate
+            if last_frame.is_some_and(|t| t.elapsed().as_secs_f64() < 0.75 / fps as f64) {
+                continue;
+            }
+            last_frame = Some(Instant::now());
+            let CameraFrame { image, captured_at } = (*cam_rx.borrow()).clone();
+            let image = image::DynamicImage::ImageRgb8(image)
+                .resize_exact(width, height, image::imageops::Triangle)
                 .to_rgb8();
-            if frame_tx.send(resized).is_err() {
+            if frame_tx.send(CameraFrame { image, captured_at }).is_err() {
                 break;
             }
         }
@@ -53,8 +104,17 @@ pub async fn run_encoder(
     });
 
     let encoder_task: JoinHandle<Result<()>> = spawn_blocking(move || {
-        let mut ctx: Context<u8> = cfg.new_context()?;
+        let mut params = params_rx.borrow_and_update().clone();
+        let mut encoder = new_encoder(codec, &params)?;
+        let mut seq = 0u32;
         loop {
+            if params_rx.has_changed().unwrap_or(false) {
+                params = params_rx.borrow_and_update().clone();
+                info!("encoder params changed to {params:?}");
+                encoder = new_encoder(codec, &params)?;
+            }
+
+            queue_tx.send_replace(frame_rx.len() as u32);
             // Drop old frames and receive one
             while frame_rx.len() > 2 {
                 warn!("dropping frames");
@@ -68,72 +128,31 @@ pub async fn run_encoder(
                 Ok(f) => f,
                 Err(_) => return Ok(()),
             };
-
-            // Convert RgbImage to Frame
-            let mut r_slice: Vec<u8> = vec![];
-            let mut g_slice: Vec<u8> = vec![];
-            let mut b_slice: Vec<u8> = vec![];
-            for pixel in frame.pixels() {
-                let (r, g, b) = to_ycbcr(pixel);
-                r_slice.push(r);
-                g_slice.push(g);
-                b_slice.push(b);
-            }
-            let planes = vec![r_slice, g_slice, b_slice];
-            let mut video_frame = ctx.new_frame();
-            for (dst, src) in video_frame.planes.iter_mut().zip(planes) {
-                dst.copy_from_raw_u8(&src, VIDEO_WIDTH as usize, 1);
+            if frame.image.dimensions() != (params.width, params.height) {
+                debug!("skipping frame resized for old params");
+                continue;
             }
 
-            // Send frame to encoder
-            match ctx.send_frame(video_frame) {
-                Ok(_) => {
-                    debug!("queued frame");
-                }
-                Err(e) => match e {
-                    EncoderStatus::EnoughData => {
-                        warn!("unable to append frame to the internal queue");
-                    }
-                    _ => {
-                        bail!("unable to send frame");
-                    }
-                },
+            let keyframe = keyframe_rx.try_recv().is_ok();
+            if keyframe {
+                info!("keyframe requested");
+            }
+            if let Some(pkt) = encoder.encode(&frame, keyframe)? {
+                debug!("sending packet from encoder thread");
+                let _ = data_tx.send(VideoFrame {
+                    seq,
+                    captured_at: pkt.captured_at,
+                    keyframe: pkt.keyframe,
+                    codec,
+                    width: params.width,
+                    height: params.height,
+                    data: pkt.data,
+                });
+                seq = seq.wrapping_add(1);
             }
-
-            // Receive data from encoder
-            match ctx.receive_packet() {
-                Ok(pkt) => {
-                    debug!("sending packet from encoder thread");
-                    let _ = data_tx.send(pkt.data);
-                }
-                Err(e) => match e {
-                    EncoderStatus::LimitReached => {
-                        warn!("read thread: Limit reached");
-                    }
-                    EncoderStatus::Encoded => debug!("read thread: Encoded"),
-                    EncoderStatus::NeedMoreData => debug!("read thread: Need more data"),
-                    _ => {
-                        bail!("unable to receive packet");
-                    }
-                },
-            };
         }
     });
 
     join_all(vec![frame_task, encoder_task]).await;
     Ok(())
 }
-
-fn clamp(val: f32) -> u8 {
-    (val.round() as u8).max(0_u8).min(255_u8)
-}
-
-fn to_ycbcr(pixel: &Rgb<u8>) -> (u8, u8, u8) {
-    let [r, g, b] = pixel.0;
-
-    let y = 16_f32 + (65.481 * r as f32 + 128.553 * g as f32 + 24.966 * b as f32) / 255_f32;
-    let cb = 128_f32 + (-37.797 * r as f32 - 74.203 * g as f32 + 112.000 * b as f32) / 255_f32;
-    let cr = 128_f32 + (112.000 * r as f32 - 93.786 * g as f32 - 18.214 * b as f32) / 255_f32;
-
-    (clamp(y), clamp(cb), clamp(cr))
-}
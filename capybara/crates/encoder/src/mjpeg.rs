@@ -0,0 +1,34 @@
+use anyhow::Result;
+use image::codecs::jpeg::JpegEncoder;
+
+use common::backend::CameraFrame;
+
+use crate::{EncodedPacket, EncoderParams, VideoEncoder};
+
+/// Compresses every frame on its own, so there is nothing to lose sync with.
+pub struct MjpegEncoder {
+    quality: u8,
+}
+
+impl MjpegEncoder {
+    pub fn new(params: &EncoderParams) -> Self {
+        // Quality levels are tuned for rav1e, whose quantizer runs from 0
+        // (best) to 255
+        let quality = 100 - params.min_quantizer as u32 * 100 / 255;
+        Self {
+            quality: quality.clamp(10, 95) as u8,
+        }
+    }
+}
+
+impl VideoEncoder for MjpegEncoder {
+    fn encode(&mut self, frame: &CameraFrame, _keyframe: bool) -> Result<Option<EncodedPacket>> {
+        let mut data = Vec::new();
+        JpegEncoder::new_with_quality(&mut data, self.quality).encode_image(&frame.image)?;
+        Ok(Some(EncodedPacket {
+            keyframe: true,
+            captured_at: frame.captured_at,
+            data,
+        }))
+    }
+}
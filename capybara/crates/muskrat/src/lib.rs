@@ -1,66 +1,119 @@
-use anyhow::Result;
-// use log::*;
+use anyhow::{bail, Result};
 use log::*;
-use std::sync::Arc;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::sync::{broadcast, mpsc, Mutex};
-use tokio::task::JoinSet;
-use tokio::time::{sleep, Duration};
-use tokio_serial::SerialPort;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadHalf, WriteHalf};
+use tokio::sync::{broadcast, mpsc};
 use tokio_serial::SerialPortBuilderExt;
 
-use common::wait_tasks;
+use common::backend::ArmActuator;
+use common::config::MuskratConfig;
+use protocol::{Decoder, Packet};
 
+pub mod protocol;
 pub mod servo;
 
+/// Arduino board driving the claw servo and reading the start button.
+/// Works over any byte stream, so tests can use a fake port.
+pub struct Muskrat<P> {
+    reader: ReadHalf<P>,
+    writer: WriteHalf<P>,
+    decoder: Decoder,
+    presses: usize,
+}
+
+impl<P: AsyncRead + AsyncWrite> Muskrat<P> {
+    pub fn new(port: P) -> Self {
+        let (reader, writer) = tokio::io::split(port);
+        Self {
+            reader,
+            writer,
+            decoder: Decoder::default(),
+            presses: 0,
+        }
+    }
+}
+
+impl<P: AsyncRead + AsyncWrite + Send + 'static> ArmActuator for Muskrat<P> {
+    async fn set_raw_angle(&mut self, angle: f64) -> Result<()> {
+        let angle = angle as u16;
+        debug!("sending {} angle", angle);
+        self.writer
+            .write_all(&Packet::SetServo(angle).encode())
+            .await?;
+        Ok(())
+    }
+
+    async fn button(&mut self) -> Result<()> {
+        let mut buf = [0u8; 64];
+        while self.presses == 0 {
+            let n = self.reader.read(&mut buf).await?;
+            if n == 0 {
+                bail!("board disconnected");
+            }
+            for packet in self.decoder.feed(&buf[..n]) {
+                match packet {
+                    Packet::Button => self.presses += 1,
+                    p => warn!("unexpected packet from board: {p:?}"),
+                }
+            }
+        }
+        self.presses -= 1;
+        Ok(())
+    }
+}
+
 pub async fn run_muskrat(
-    mut set_angle_rx: mpsc::Receiver<f64>,
+    config: MuskratConfig,
+    set_angle_rx: mpsc::Receiver<f64>,
     button_tx: broadcast::Sender<()>,
 ) -> Result<()> {
-    let mut port = tokio_serial::new(
-        "/dev/serial/by-path/platform-fd500000.pcie-pci-0000:01:00.0-usb-0:1.4.1:1.0-port0",
-        115200,
-    )
-    .open_native_async()?;
+    let mut port = tokio_serial::new(config.port, config.baud_rate).open_native_async()?;
     port.set_exclusive(true)?;
 
-    let port = Arc::new(Mutex::new(port));
-
-    let rec_port = port.clone();
-
-    let mut tasks = JoinSet::<Result<()>>::new();
+    run_arm(Muskrat::new(port), set_angle_rx, button_tx).await
+}
 
-    tasks.spawn(async move {
-        loop {
-            let angle = match set_angle_rx.recv().await {
-                Some(d) => d as u32,
+pub async fn run_arm(
+    mut arm: impl ArmActuator,
+    mut set_angle_rx: mpsc::Receiver<f64>,
+    button_tx: broadcast::Sender<()>,
+) -> Result<()> {
+    loop {
+        tokio::select! {
+            angle = set_angle_rx.recv() => match angle {
+                Some(a) => arm.set_raw_angle(a).await?,
                 None => return Ok(()),
-            };
-            let mut p = port.lock().await;
-            debug!("sending {} angle", angle);
-            // p.write_all(&[0x03]).await?;
-            p.write_all(angle.to_string().as_bytes()).await?;
-            p.write_all(b"\n").await?;
-        }
-    });
-
-    tasks.spawn(async move {
-        loop {
-            let mut p = rec_port.lock().await;
-            if p.bytes_to_read()? > 0 {
-                let mut buf = [0u8; 1];
-                p.read_exact(&mut buf).await?;
-                if buf[0] == 0x07 {
-                    let _ = button_tx.send(());
-                }
+            },
+            res = arm.button() => {
+                res?;
+                let _ = button_tx.send(());
             }
-            sleep(Duration::from_millis(20)).await;
         }
-    });
-    // while let Some(_angle) = set_angle_rx.recv().await {
-    //     // debug!("set angle = {}", angle);
-    // }
-    wait_tasks(tasks).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::duplex;
+    use tokio::task::spawn;
+
+    #[tokio::test]
+    async fn fake_port() {
+        let (port, mut board) = duplex(64);
+        let (set_angle_tx, set_angle_rx) = mpsc::channel(1);
+        let (button_tx, mut button_rx) = broadcast::channel(1);
+        spawn(run_arm(Muskrat::new(port), set_angle_rx, button_tx));
+
+        set_angle_tx.send(2390.0).await.unwrap();
+        let mut frame = [0u8; 6];
+        board.read_exact(&mut frame).await.unwrap();
+        assert_eq!(
+            Decoder::default().feed(&frame),
+            vec![Packet::SetServo(2390)]
+        );
 
-    Ok(())
+        board.write_all(&[0x07, 0x03]).await.unwrap();
+        board.write_all(&Packet::Button.encode()).await.unwrap();
+        button_rx.recv().await.unwrap();
+    }
 }
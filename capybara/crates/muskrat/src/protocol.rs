@@ -0,0 +1,140 @@
+//! Framing of the serial link with the Arduino (see `muskrat.ino`).
+//!
+//! Every frame is `START, command, payload length, payload..., crc8`, where
+//! the CRC covers everything between the start byte and the CRC itself.
+
+pub const START: u8 = 0xAA;
+
+const CMD_SET_SERVO: u8 = 0x01;
+const CMD_BUTTON: u8 = 0x02;
+
+const MAX_PAYLOAD: usize = 8;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Packet {
+    /// Servo pulse width in microseconds, sent to the board.
+    SetServo(u16),
+    /// Button was pressed and released, sent by the board.
+    Button,
+}
+
+impl Packet {
+    pub fn encode(&self) -> Vec<u8> {
+        let (cmd, payload) = match self {
+            Packet::SetServo(us) => (CMD_SET_SERVO, us.to_le_bytes().to_vec()),
+            Packet::Button => (CMD_BUTTON, vec![]),
+        };
+        let mut frame = vec![START, cmd, payload.len() as u8];
+        frame.extend(payload);
+        frame.push(crc8(&frame[1..]));
+        frame
+    }
+
+    fn parse(cmd: u8, payload: &[u8]) -> Option<Self> {
+        match (cmd, payload) {
+            (CMD_SET_SERVO, &[lo, hi]) => Some(Packet::SetServo(u16::from_le_bytes([lo, hi]))),
+            (CMD_BUTTON, &[]) => Some(Packet::Button),
+            _ => None,
+        }
+    }
+}
+
+/// Reassembles packets from a byte stream, skipping garbage and corrupted frames.
+#[derive(Default)]
+pub struct Decoder {
+    buf: Vec<u8>,
+}
+
+impl Decoder {
+    pub fn feed(&mut self, data: &[u8]) -> Vec<Packet> {
+        self.buf.extend_from_slice(data);
+        let mut packets = Vec::new();
+        loop {
+            match self.buf.iter().position(|&b| b == START) {
+                Some(i) => {
+                    self.buf.drain(..i);
+                }
+                None => {
+                    self.buf.clear();
+                    break;
+                }
+            }
+            if self.buf.len() < 3 {
+                break;
+            }
+            let len = self.buf[2] as usize;
+            if len > MAX_PAYLOAD {
+                self.buf.remove(0);
+                continue;
+            }
+            if self.buf.len() < len + 4 {
+                break;
+            }
+
+            let body = &self.buf[1..len + 3];
+            let packet = if crc8(body) == self.buf[len + 3] {
+                Packet::parse(body[0], &body[2..])
+            } else {
+                None
+            };
+            match packet {
+                Some(p) => {
+                    packets.push(p);
+                    self.buf.drain(..len + 4);
+                }
+                // The start byte may have been a payload byte of a real frame
+                None => {
+                    self.buf.remove(0);
+                }
+            }
+        }
+        packets
+    }
+}
+
+/// CRC-8 with polynomial 0x07.
+pub fn crc8(data: &[u8]) -> u8 {
+    let mut crc = 0u8;
+    for &byte in data {
+        crc ^= byte;
+        for _ in 0..8 {
+            crc = if crc & 0x80 != 0 {
+                (crc << 1) ^ 0x07
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn roundtrip() {
+        let mut decoder = Decoder::default();
+        for packet in [Packet::SetServo(2390), Packet::Button, Packet::SetServo(0)] {
+            assert_eq!(decoder.feed(&packet.encode()), vec![packet]);
+        }
+    }
+
+    #[test]
+    fn skips_garbage_and_corrupted_frames() {
+        let mut corrupted = Packet::SetServo(2300).encode();
+        corrupted[3] ^= 0xFF;
+
+        let mut stream = vec![0x00, START, 0x12];
+        stream.extend(corrupted);
+        stream.extend(Packet::Button.encode());
+        stream.extend(Packet::SetServo(2500).encode());
+
+        let mut decoder = Decoder::default();
+        let mut packets = Vec::new();
+        for chunk in stream.chunks(3) {
+            packets.extend(decoder.feed(chunk));
+        }
+        assert_eq!(packets, vec![Packet::Button, Packet::SetServo(2500)]);
+    }
+}
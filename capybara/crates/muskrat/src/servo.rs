@@ -2,68 +2,147 @@ use anyhow::Result;
 use tokio::sync::{mpsc, watch};
 use tokio::time::{sleep, Duration, Instant};
 
-const MAX_SPEED: f64 = 50.0;
-//const ACCEL: f64 = 2.0;
+use common::config::ServoConfig;
+use proto::ServoPosition;
 
+/// Period of position updates while moving.
+const STEP: Duration = Duration::from_millis(20);
+
+/// Trapezoidal motion: accelerates up to the top speed and brakes in time to
+/// stop at the target.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Profile {
+    pub position: f64,
+    /// Signed, per second.
+    pub speed: f64,
+}
+
+impl Profile {
+    pub fn new(position: f64) -> Self {
+        Self {
+            position,
+            speed: 0.0,
+        }
+    }
+
+    pub fn settled_at(&self, target: f64) -> bool {
+        self.position == target && self.speed == 0.0
+    }
+
+    /// Advances by `dt` seconds. `acceleration` of 0 means no ramps.
+    pub fn step(&mut self, target: f64, max_speed: f64, acceleration: f64, dt: f64) {
+        let distance = target - self.position;
+        let (stop_speed, max_change) = if acceleration > 0.0 {
+            (
+                (2.0 * acceleration * distance.abs()).sqrt(),
+                acceleration * dt,
+            )
+        } else {
+            (f64::INFINITY, f64::INFINITY)
+        };
+        let wanted = distance.signum() * max_speed.min(stop_speed);
+        self.speed += (wanted - self.speed).clamp(-max_change, max_change);
+
+        let step = self.speed * dt;
+        // Braking is discrete, so snap to the target instead of overshooting
+        if step * distance >= 0.0 && step.abs() >= distance.abs() {
+            self.position = target;
+            self.speed = 0.0;
+        } else {
+            self.position += step;
+        }
+    }
+}
+
+/// Moves the servo to the latest target of `set_angle_rx` and reports the
+/// progress on `position_tx`. Both are normalized positions, only the angles
+/// sent to `set_raw_angle_tx` are pulse widths from the calibration.
 pub async fn run_servo(
-    set_angle_rx: watch::Receiver<f64>,
+    config: ServoConfig,
+    mut set_angle_rx: watch::Receiver<f64>,
     set_raw_angle_tx: mpsc::Sender<f64>,
+    position_tx: watch::Sender<ServoPosition>,
 ) -> Result<()> {
-    let mut angle: f64 = 2400.0;
-    // let mut speed: f64 = 0.0;
+    let calibration = &config.calibration;
+    let mut profile = Profile::new(config.clamp(config.start_position));
+    let _ = set_raw_angle_tx
+        .send(calibration.to_raw(profile.position))
+        .await;
     let mut last_run = Instant::now();
     loop {
-        let target = *set_angle_rx.borrow();
-        let dt = (Instant::now() - last_run).as_secs_f64();
-        last_run = Instant::now();
-        if angle + MAX_SPEED * dt < target {
-            angle += MAX_SPEED * dt;
-        } else {
-            angle -= MAX_SPEED * dt;
+        let target = config.clamp(*set_angle_rx.borrow_and_update());
+        let position = ServoPosition {
+            position: profile.position,
+            target,
         };
-        let _ = set_raw_angle_tx.send(angle).await;
-        sleep(Duration::from_millis(50)).await;
+        position_tx.send_if_modified(|p| {
+            let changed = *p != position;
+            *p = position;
+            changed
+        });
+
+        if profile.settled_at(target) {
+            if set_angle_rx.changed().await.is_err() {
+                return Ok(());
+            }
+            last_run = Instant::now();
+            continue;
+        }
+        sleep(STEP).await;
+        let dt = last_run.elapsed().as_secs_f64();
+        last_run = Instant::now();
+        profile.step(target, config.max_speed, config.acceleration, dt);
+        let _ = set_raw_angle_tx
+            .send(calibration.to_raw(profile.position))
+            .await;
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    // loop {
-    //     let target = *set_angle_rx.borrow();
-    //     let dt = (Instant::now() - last_run).as_secs_f64();
-    //     last_run = Instant::now();
-    //     if target > angle {
-    //         speed = if angle + (ACCEL * (speed / ACCEL).powi(2) / 2.0) < target {
-    //             if speed + ACCEL * dt < MAX_SPEED {
-    //                 speed + ACCEL * dt
-    //             } else {
-    //                 speed - ACCEL * dt
-    //             }
-    //         } else {
-    //             if speed - ACCEL * dt > -MAX_SPEED {
-    //                 speed - ACCEL * dt
-    //             } else {
-    //                 speed + ACCEL * dt
-    //             }
-    //         };
-    //     } else {
-    //         speed = if angle - (ACCEL * (speed / ACCEL).powi(2) / 2.0) > target {
-    //             if speed - ACCEL * dt > -MAX_SPEED {
-    //                 speed - ACCEL * dt
-    //             } else {
-    //                 speed + ACCEL * dt
-    //             }
-    //         } else {
-    //             if speed + ACCEL * dt > MAX_SPEED {
-    //                 speed - ACCEL * dt
-    //             } else {
-    //                 speed + ACCEL * dt
-    //             }
-    //     };
-    // }
-    //     angle += speed * dt;
-    //     set_raw_angle_tx.send(angle).await.unwrap();
-    //     sleep(Duration::from_millis(10)).await;
-    // }
+    /// Returns the steps taken and the highest speed.
+    fn run_to(profile: &mut Profile, target: f64, acceleration: f64) -> (usize, f64) {
+        let mut top_speed: f64 = 0.0;
+        for steps in 1..10_000 {
+            profile.step(target, 50.0, acceleration, 0.02);
+            top_speed = top_speed.max(profile.speed.abs());
+            if profile.settled_at(target) {
+                return (steps, top_speed);
+            }
+        }
+        panic!("servo never reached {target}, stuck at {profile:?}");
+    }
 
-    // // while let Some(angle) = set_angle_rx.recv().await {
-    // //     set_raw_angle_tx.send(angle).await.unwrap();
-    // // }
+    #[test]
+    fn trapezoid() {
+        let mut profile = Profile::new(2300.0);
+        let (steps, top_speed) = run_to(&mut profile, 2500.0, 100.0);
+        assert_eq!(top_speed, 50.0);
+        // 4 s at full speed plus half a second lost on each ramp
+        assert!((200..=230).contains(&steps), "{steps} steps");
+
+        let (_, top_speed) = run_to(&mut profile, 2490.0, 100.0);
+        assert!(top_speed < 50.0);
+    }
+
+    #[test]
+    fn no_ramps() {
+        let mut profile = Profile::new(2500.0);
+        let (steps, _) = run_to(&mut profile, 2300.0, 0.0);
+        assert_eq!(steps, 200);
+    }
+
+    #[test]
+    fn reverses_smoothly() {
+        let mut profile = Profile::new(2300.0);
+        for _ in 0..50 {
+            profile.step(2500.0, 50.0, 100.0, 0.02);
+        }
+        let before = profile.speed;
+        profile.step(2300.0, 50.0, 100.0, 0.02);
+        assert!((before - profile.speed - 2.0).abs() < 1e-9);
+        run_to(&mut profile, 2300.0, 100.0);
+    }
 }
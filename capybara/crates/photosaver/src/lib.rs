@@ -1,15 +1,32 @@
 use anyhow::Result;
-use image::io::Reader;
 use log::*;
-use rand::distributions::Alphanumeric;
-use rand::{thread_rng, Rng};
-use std::io::Cursor;
+use serde::Serialize;
 use tokio::sync::broadcast;
-use tokio::task::spawn_blocking;
 
-pub async fn run_photosaver(mut data_rx: broadcast::Receiver<Vec<u8>>) -> Result<()> {
+use proto::{Odometry, Photo, PhotoFormat};
+
+mod video;
+
+pub use video::run_videosaver;
+
+/// Written next to every photo.
+#[derive(Serialize)]
+struct Sidecar<'a> {
+    id: u32,
+    /// Milliseconds since unix epoch by the robot's clock.
+    captured_at: u64,
+    pose: &'a Odometry,
+    width: u32,
+    height: u32,
+    format: PhotoFormat,
+}
+
+/// Saves photos to `photos/` as the robot encoded them, named by their
+/// number and capture time, with a JSON file holding the pose.
+pub async fn run_photosaver(mut data_rx: broadcast::Receiver<Photo>) -> Result<()> {
+    tokio::fs::create_dir_all("photos").await?;
     loop {
-        let data = match data_rx.recv().await {
+        let photo = match data_rx.recv().await {
             Ok(d) => d,
             Err(broadcast::error::RecvError::Lagged(l)) => {
                 error!("lagged for {l} packets");
@@ -18,21 +35,22 @@ pub async fn run_photosaver(mut data_rx: broadcast::Receiver<Vec<u8>>) -> Result
             Err(_) => return Ok(()),
         };
 
-        let res: Result<()> = spawn_blocking(move || {
-            let mut reader = Reader::new(Cursor::new(data));
-            reader.set_format(image::ImageFormat::WebP);
-            let img = reader.decode()?;
-
-            let img_name: String = thread_rng()
-                .sample_iter(&Alphanumeric)
-                .take(8)
-                .map(char::from)
-                .collect();
-            img.save(format!("photos/{img_name}.jpg"))?;
-            info!("saved photo {img_name}.jpg");
-            Ok(())
-        })
+        let name = format!("photo-{:04}-{}", photo.id, photo.captured_at);
+        let file = format!("{name}.{}", photo.format.extension());
+        tokio::fs::write(format!("photos/{file}"), &photo.data).await?;
+        let sidecar = Sidecar {
+            id: photo.id,
+            captured_at: photo.captured_at,
+            pose: &photo.pose,
+            width: photo.width,
+            height: photo.height,
+            format: photo.format,
+        };
+        tokio::fs::write(
+            format!("photos/{name}.json"),
+            serde_json::to_string_pretty(&sidecar)?,
+        )
         .await?;
-        res?;
+        info!("saved photo {file}");
     }
 }
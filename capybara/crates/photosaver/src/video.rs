@@ -0,0 +1,406 @@
+//! Saves the received video. AV1 goes to IVF files and MJPEG, which IVF has
+//! no place for, to Matroska files. ffmpeg, mpv and VLC play both.
+
+use anyhow::{Context, Result};
+use log::*;
+use std::io::SeekFrom;
+use std::path::PathBuf;
+use tokio::fs::File;
+use tokio::io::{AsyncSeekExt, AsyncWriteExt};
+use tokio::sync::broadcast;
+
+use proto::{now_millis, VideoCodec, VideoFrame};
+
+/// Timestamps are in milliseconds.
+const TIMEBASE: u32 = 1000;
+const FRAME_COUNT_OFFSET: u64 = 24;
+/// Matroska timestamps are in units of this many nanoseconds, so in
+/// milliseconds like IVF.
+const MKV_TIMESTAMP_SCALE: u64 = 1_000_000;
+/// Size of an element that goes on to the end of the file.
+const MKV_UNKNOWN_SIZE: [u8; 8] = [0x01, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff];
+
+// Matroska element ids
+const EBML: u32 = 0x1a45dfa3;
+const EBML_VERSION: u32 = 0x4286;
+const EBML_READ_VERSION: u32 = 0x42f7;
+const EBML_MAX_ID_LENGTH: u32 = 0x42f2;
+const EBML_MAX_SIZE_LENGTH: u32 = 0x42f3;
+const DOC_TYPE: u32 = 0x4282;
+const DOC_TYPE_VERSION: u32 = 0x4287;
+const DOC_TYPE_READ_VERSION: u32 = 0x4285;
+const SEGMENT: u32 = 0x18538067;
+const INFO: u32 = 0x1549a966;
+const TIMESTAMP_SCALE: u32 = 0x2ad7b1;
+const MUXING_APP: u32 = 0x4d80;
+const WRITING_APP: u32 = 0x5741;
+const TRACKS: u32 = 0x1654ae6b;
+const TRACK_ENTRY: u32 = 0xae;
+const TRACK_NUMBER: u32 = 0xd7;
+const TRACK_UID: u32 = 0x73c5;
+const TRACK_TYPE: u32 = 0x83;
+const CODEC_ID: u32 = 0x86;
+const VIDEO: u32 = 0xe0;
+const PIXEL_WIDTH: u32 = 0xb0;
+const PIXEL_HEIGHT: u32 = 0xba;
+const CLUSTER: u32 = 0x1f43b675;
+const TIMESTAMP: u32 = 0xe7;
+const SIMPLE_BLOCK: u32 = 0xa3;
+
+enum Output {
+    /// The frame count in the header is updated after every frame, so the
+    /// file stays playable however control exits.
+    Ivf(File),
+    /// The segment has no size and every frame is a cluster of its own, so
+    /// the file is complete after every frame like IVF.
+    Mkv(File),
+}
+
+/// Part of the video with one codec and picture size.
+struct Part {
+    output: Output,
+    codec: VideoCodec,
+    width: u32,
+    height: u32,
+    frames: u32,
+    first_captured_at: u64,
+    last_pts: Option<u64>,
+}
+
+impl Part {
+    /// `path` is without an extension.
+    async fn create(path: PathBuf, frame: &VideoFrame) -> Result<Self> {
+        let output = match frame.codec {
+            VideoCodec::Av1 => {
+                let path = path.with_extension("ivf");
+                let mut file = File::create(&path)
+                    .await
+                    .with_context(|| format!("can't create {}", path.display()))?;
+                file.write_all(&ivf_header(frame)).await?;
+                file.flush().await?;
+                info!("saving AV1 video to {}", path.display());
+                Output::Ivf(file)
+            }
+            VideoCodec::Mjpeg => {
+                let path = path.with_extension("mkv");
+                let mut file = File::create(&path)
+                    .await
+                    .with_context(|| format!("can't create {}", path.display()))?;
+                file.write_all(&mkv_header(frame)).await?;
+                file.flush().await?;
+                info!("saving MJPEG video to {}", path.display());
+                Output::Mkv(file)
+            }
+        };
+        Ok(Self {
+            output,
+            codec: frame.codec,
+            width: frame.width,
+            height: frame.height,
+            frames: 0,
+            first_captured_at: frame.captured_at,
+            last_pts: None,
+        })
+    }
+
+    fn fits(&self, frame: &VideoFrame) -> bool {
+        self.codec == frame.codec && (self.width, self.height) == (frame.width, frame.height)
+    }
+
+    async fn write(&mut self, frame: &VideoFrame) -> Result<()> {
+        // Players expect increasing timestamps, the robot's clock may step back
+        let pts = frame.captured_at.saturating_sub(self.first_captured_at);
+        let pts = match self.last_pts {
+            Some(last) if pts <= last => last + 1,
+            _ => pts,
+        };
+        self.last_pts = Some(pts);
+        self.frames += 1;
+
+        match &mut self.output {
+            Output::Ivf(file) => {
+                let mut record = Vec::with_capacity(12 + frame.data.len());
+                record.extend_from_slice(&(frame.data.len() as u32).to_le_bytes());
+                record.extend_from_slice(&pts.to_le_bytes());
+                record.extend_from_slice(&frame.data);
+                file.write_all(&record).await?;
+
+                file.seek(SeekFrom::Start(FRAME_COUNT_OFFSET)).await?;
+                file.write_all(&self.frames.to_le_bytes()).await?;
+                file.seek(SeekFrom::End(0)).await?;
+                file.flush().await?;
+            }
+            Output::Mkv(file) => {
+                file.write_all(&mkv_cluster(pts, &frame.data)).await?;
+                file.flush().await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn ivf_header(frame: &VideoFrame) -> Vec<u8> {
+    let mut header = Vec::with_capacity(32);
+    header.extend_from_slice(b"DKIF");
+    header.extend_from_slice(&0u16.to_le_bytes());
+    header.extend_from_slice(&32u16.to_le_bytes());
+    header.extend_from_slice(b"AV01");
+    header.extend_from_slice(&(frame.width as u16).to_le_bytes());
+    header.extend_from_slice(&(frame.height as u16).to_le_bytes());
+    header.extend_from_slice(&TIMEBASE.to_le_bytes());
+    header.extend_from_slice(&1u32.to_le_bytes());
+    // Frame count, see FRAME_COUNT_OFFSET
+    header.extend_from_slice(&0u32.to_le_bytes());
+    header.extend_from_slice(&0u32.to_le_bytes());
+    header
+}
+
+/// EBML element, sizes always take 8 bytes.
+fn element(id: u32, body: &[u8]) -> Vec<u8> {
+    let id = id.to_be_bytes();
+    let skip = id.iter().take_while(|b| **b == 0).count();
+    let mut element = Vec::with_capacity(4 + 8 + body.len());
+    element.extend_from_slice(&id[skip..]);
+    element.extend_from_slice(&(body.len() as u64 | 1 << 56).to_be_bytes());
+    element.extend_from_slice(body);
+    element
+}
+
+fn uint_element(id: u32, value: u64) -> Vec<u8> {
+    let bytes = value.to_be_bytes();
+    let skip = bytes.iter().take_while(|b| **b == 0).count().min(7);
+    element(id, &bytes[skip..])
+}
+
+/// Everything up to the first cluster, with one MJPEG track.
+fn mkv_header(frame: &VideoFrame) -> Vec<u8> {
+    let ebml = [
+        uint_element(EBML_VERSION, 1),
+        uint_element(EBML_READ_VERSION, 1),
+        uint_element(EBML_MAX_ID_LENGTH, 4),
+        uint_element(EBML_MAX_SIZE_LENGTH, 8),
+        element(DOC_TYPE, b"matroska"),
+        uint_element(DOC_TYPE_VERSION, 2),
+        uint_element(DOC_TYPE_READ_VERSION, 2),
+    ]
+    .concat();
+    let info = [
+        uint_element(TIMESTAMP_SCALE, MKV_TIMESTAMP_SCALE),
+        element(MUXING_APP, b"capybara"),
+        element(WRITING_APP, b"capybara"),
+    ]
+    .concat();
+    let video = [
+        uint_element(PIXEL_WIDTH, frame.width as u64),
+        uint_element(PIXEL_HEIGHT, frame.height as u64),
+    ]
+    .concat();
+    let track = [
+        uint_element(TRACK_NUMBER, 1),
+        uint_element(TRACK_UID, 1),
+        // Video
+        uint_element(TRACK_TYPE, 1),
+        element(CODEC_ID, b"V_MJPEG"),
+        element(VIDEO, &video),
+    ]
+    .concat();
+
+    let mut header = element(EBML, &ebml);
+    header.extend_from_slice(&SEGMENT.to_be_bytes());
+    header.extend_from_slice(&MKV_UNKNOWN_SIZE);
+    header.extend_from_slice(&element(INFO, &info));
+    header.extend_from_slice(&element(TRACKS, &element(TRACK_ENTRY, &track)));
+    header
+}
+
+/// Cluster at `pts` with a single keyframe of track 1.
+fn mkv_cluster(pts: u64, data: &[u8]) -> Vec<u8> {
+    let mut block = Vec::with_capacity(4 + data.len());
+    // Track number as a 1 byte vint, timestamp relative to the cluster and
+    // the keyframe flag
+    block.push(0x81);
+    block.extend_from_slice(&0i16.to_be_bytes());
+    block.push(0x80);
+    block.extend_from_slice(data);
+    let cluster = [uint_element(TIMESTAMP, pts), element(SIMPLE_BLOCK, &block)].concat();
+    element(CLUSTER, &cluster)
+}
+
+/// Writes the video stream to `photos/`, starting a new part whenever the
+/// codec or the picture size changes. Every part starts with a keyframe, and
+/// after lost packets nothing is written until the next one.
+pub async fn run_videosaver(mut data_rx: broadcast::Receiver<VideoFrame>) -> Result<()> {
+    tokio::fs::create_dir_all("photos").await?;
+    let started_at = now_millis();
+    let mut number = 0;
+    let mut part: Option<Part> = None;
+    let mut last_seq: Option<u32> = None;
+    let mut need_keyframe = true;
+    loop {
+        let frame = match data_rx.recv().await {
+            Ok(f) => f,
+            // Shows up as a gap in seq
+            Err(broadcast::error::RecvError::Lagged(l)) => {
+                error!("video saver lagged for {l} packets");
+                continue;
+            }
+            Err(_) => break,
+        };
+
+        if last_seq.is_some_and(|s| frame.seq != s.wrapping_add(1)) {
+            need_keyframe = true;
+        }
+        last_seq = Some(frame.seq);
+
+        let fits = part.as_ref().is_some_and(|p| p.fits(&frame));
+        if need_keyframe || !fits {
+            if !frame.keyframe {
+                continue;
+            }
+            need_keyframe = false;
+            if !fits {
+                if let Some(old) = part.take() {
+                    info!("saved {} video frames", old.frames);
+                }
+                number += 1;
+                let path = PathBuf::from(format!("photos/video-{started_at}-{number}"));
+                part = Some(Part::create(path, &frame).await?);
+            }
+        }
+        if let Some(part) = part.as_mut() {
+            part.write(&frame).await?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(codec: VideoCodec, captured_at: u64, data: &[u8]) -> VideoFrame {
+        VideoFrame {
+            seq: 0,
+            captured_at,
+            keyframe: true,
+            codec,
+            width: 640,
+            height: 480,
+            data: data.to_vec(),
+        }
+    }
+
+    fn u32_at(bytes: &[u8], at: usize) -> u32 {
+        u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap())
+    }
+
+    #[tokio::test]
+    async fn ivf_layout() {
+        let path = std::env::temp_dir().join(format!("ivf-{}", std::process::id()));
+        let first = frame(VideoCodec::Av1, 5000, &[1, 2, 3]);
+        let mut part = Part::create(path.clone(), &first).await.unwrap();
+        part.write(&first).await.unwrap();
+        // Stepped back, so the timestamp is bumped
+        part.write(&frame(VideoCodec::Av1, 4000, &[4]))
+            .await
+            .unwrap();
+
+        // Complete without anything like finish()
+        let path = path.with_extension("ivf");
+        let bytes = std::fs::read(&path).unwrap();
+        assert_eq!(&bytes[0..4], b"DKIF");
+        assert_eq!(&bytes[6..8], &32u16.to_le_bytes());
+        assert_eq!(&bytes[8..12], b"AV01");
+        assert_eq!(&bytes[12..14], &640u16.to_le_bytes());
+        assert_eq!(&bytes[14..16], &480u16.to_le_bytes());
+        assert_eq!(u32_at(&bytes, 16), TIMEBASE);
+        assert_eq!(u32_at(&bytes, 20), 1);
+        assert_eq!(u32_at(&bytes, FRAME_COUNT_OFFSET as usize), 2);
+
+        assert_eq!(u32_at(&bytes, 32), 3);
+        assert_eq!(&bytes[36..44], &0u64.to_le_bytes());
+        assert_eq!(&bytes[44..47], &[1, 2, 3]);
+        assert_eq!(u32_at(&bytes, 47), 1);
+        assert_eq!(&bytes[51..59], &1u64.to_le_bytes());
+        assert_eq!(&bytes[59..], &[4]);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    /// Splits EBML elements into their ids and bodies. An element of unknown
+    /// size reaches to the end.
+    fn elements(mut bytes: &[u8]) -> Vec<(u32, &[u8])> {
+        let mut elements = Vec::new();
+        while !bytes.is_empty() {
+            let id_len = bytes[0].leading_zeros() as usize + 1;
+            let id = uint(&bytes[..id_len]) as u32;
+            bytes = &bytes[id_len..];
+            let size_len = bytes[0].leading_zeros() as usize + 1;
+            let marker = 1 << (7 * size_len);
+            let size = match uint(&bytes[..size_len]) & !marker {
+                size if size == marker - 1 => bytes.len() - size_len,
+                size => size as usize,
+            };
+            bytes = &bytes[size_len..];
+            elements.push((id, &bytes[..size]));
+            bytes = &bytes[size..];
+        }
+        elements
+    }
+
+    fn child<'a>(elements: &[(u32, &'a [u8])], id: u32) -> &'a [u8] {
+        elements.iter().find(|(i, _)| *i == id).unwrap().1
+    }
+
+    fn uint(bytes: &[u8]) -> u64 {
+        bytes.iter().fold(0, |v, b| v << 8 | *b as u64)
+    }
+
+    #[tokio::test]
+    async fn mkv_layout() {
+        let path = std::env::temp_dir().join(format!("mkv-{}", std::process::id()));
+        let first = frame(VideoCodec::Mjpeg, 5000, &[0xff, 0xd8]);
+        let mut part = Part::create(path.clone(), &first).await.unwrap();
+        part.write(&first).await.unwrap();
+        part.write(&frame(VideoCodec::Mjpeg, 5040, &[0xff, 0xd9]))
+            .await
+            .unwrap();
+
+        // Complete without anything like finish()
+        let path = path.with_extension("mkv");
+        let bytes = std::fs::read(&path).unwrap();
+        let top = elements(&bytes);
+        assert_eq!(top.len(), 2);
+        assert_eq!(top[0].0, EBML);
+        assert_eq!(child(&elements(top[0].1), DOC_TYPE), b"matroska");
+        assert_eq!(top[1].0, SEGMENT);
+
+        let segment = elements(top[1].1);
+        let info = elements(child(&segment, INFO));
+        assert_eq!(uint(child(&info, TIMESTAMP_SCALE)), MKV_TIMESTAMP_SCALE);
+        let tracks = elements(child(&segment, TRACKS));
+        let track = elements(child(&tracks, TRACK_ENTRY));
+        assert_eq!(uint(child(&track, TRACK_NUMBER)), 1);
+        assert_eq!(uint(child(&track, TRACK_TYPE)), 1);
+        assert_eq!(child(&track, CODEC_ID), b"V_MJPEG");
+        let video = elements(child(&track, VIDEO));
+        assert_eq!(uint(child(&video, PIXEL_WIDTH)), 640);
+        assert_eq!(uint(child(&video, PIXEL_HEIGHT)), 480);
+
+        let clusters: Vec<_> = segment
+            .iter()
+            .filter(|(id, _)| *id == CLUSTER)
+            .map(|(_, body)| elements(body))
+            .collect();
+        assert_eq!(clusters.len(), 2);
+        for (cluster, (pts, data)) in clusters.iter().zip([(0, [0xff, 0xd8]), (40, [0xff, 0xd9])]) {
+            assert_eq!(uint(child(cluster, TIMESTAMP)), pts);
+            let block = child(cluster, SIMPLE_BLOCK);
+            assert_eq!(&block[..4], &[0x81, 0, 0, 0x80]);
+            assert_eq!(&block[4..], &data);
+        }
+
+        assert!(part.fits(&frame(VideoCodec::Mjpeg, 0, &[])));
+        assert!(!part.fits(&frame(VideoCodec::Av1, 0, &[])));
+        std::fs::remove_file(path).unwrap();
+    }
+}
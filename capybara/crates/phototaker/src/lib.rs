@@ -1,29 +1,216 @@
-use anyhow::Result;
-use image::RgbImage;
+use anyhow::{anyhow, Context, Result};
+use image::codecs::jpeg::JpegEncoder;
+use image::{ImageOutputFormat, RgbImage};
 use log::*;
-use tokio::sync::{broadcast, mpsc, watch};
+use std::io::Cursor;
+use tokio::sync::{broadcast, mpsc, oneshot, watch};
 use tokio::task::spawn_blocking;
 
+use common::backend::{CameraFrame, StillRequest};
+use proto::{CommandResult, Odometry, Photo, PhotoFormat, PhotoOptions, PhotoResolution};
+
+/// Longest burst a request may ask for, so one can't hog the camera.
+pub const MAX_BURST: u8 = 10;
+
+#[derive(Clone, Debug)]
+pub enum PhotoEvent {
+    Taken(Photo),
+    /// All photos of the request are taken, or taking one failed.
+    Done {
+        request_id: u32,
+        result: CommandResult,
+    },
+}
+
+/// Takes the photos of every request and sends them out, followed by the
+/// result of the request. Photos are numbered from 1 and carry the pose they
+/// were taken at.
 pub async fn run_phototaker(
-    mut photo_request_rx: mpsc::Receiver<()>,
-    camera_rx: watch::Receiver<RgbImage>,
-    photo_data_tx: broadcast::Sender<Vec<u8>>,
+    mut photo_request_rx: mpsc::Receiver<(u32, PhotoOptions)>,
+    mut camera_rx: watch::Receiver<CameraFrame>,
+    still_tx: mpsc::Sender<StillRequest>,
+    odometry_rx: watch::Receiver<Odometry>,
+    photo_tx: broadcast::Sender<PhotoEvent>,
 ) -> Result<()> {
-    while let Some(_) = photo_request_rx.recv().await {
-        info!("taking photo");
-        let img = (*camera_rx.borrow()).clone();
-
-        let webp: Result<Vec<u8>> = spawn_blocking(move || {
-            let mut webp_buf: Vec<u8> = Vec::new();
-            img.write_to(
-                &mut std::io::Cursor::new(&mut webp_buf),
-                image::ImageOutputFormat::WebP,
-            )?;
-            Ok(webp_buf)
-        })
-        .await?;
-
-        let _ = photo_data_tx.send(webp?);
+    let mut photo_id = 0;
+    while let Some((request_id, options)) = photo_request_rx.recv().await {
+        info!("taking photos for request {request_id}: {options:?}");
+        let mut result = CommandResult::Done;
+        // The camera switches to the full resolution and back once per burst
+        let mut stills = match options.resolution {
+            PhotoResolution::Full => Some(take_stills(&still_tx, options.burst).await),
+            PhotoResolution::Stream => None,
+        };
+        for shot in 0..options.burst {
+            let frame = match &mut stills {
+                Some(Ok(stills)) => stills.next().context("camera took too few stills"),
+                Some(Err(e)) => Err(anyhow!("{e:#}")),
+                // Every photo of a burst from a new frame
+                None if shot > 0 && camera_rx.changed().await.is_err() => {
+                    Err(anyhow!("camera is not running"))
+                }
+                None => Ok(camera_rx.borrow_and_update().clone()),
+            };
+            let id = photo_id + 1;
+            let pose = odometry_rx.borrow().clone();
+            let (format, quality) = (options.format, options.quality);
+            let photo = match frame {
+                Ok(frame) => {
+                    spawn_blocking(move || -> Result<Photo> {
+                        Ok(Photo {
+                            id,
+                            captured_at: frame.captured_at,
+                            pose,
+                            width: frame.image.width(),
+                            height: frame.image.height(),
+                            format,
+                            data: encode(&frame.image, format, quality)?,
+                        })
+                    })
+                    .await?
+                }
+                Err(e) => Err(e),
+            };
+            match photo {
+                Ok(photo) => {
+                    info!("took photo {id}");
+                    photo_id = id;
+                    let _ = photo_tx.send(PhotoEvent::Taken(photo));
+                }
+                Err(e) => {
+                    error!("photo failed: {e:#}");
+                    result = CommandResult::Failed(format!("{e:#}"));
+                    break;
+                }
+            }
+        }
+        let _ = photo_tx.send(PhotoEvent::Done { request_id, result });
     }
     Ok(())
 }
+
+async fn take_stills(
+    still_tx: &mpsc::Sender<StillRequest>,
+    count: u8,
+) -> Result<std::vec::IntoIter<CameraFrame>> {
+    let (reply_tx, reply_rx) = oneshot::channel();
+    let request = StillRequest {
+        count: count as usize,
+        reply_tx,
+    };
+    still_tx
+        .send(request)
+        .await
+        .map_err(|_| anyhow!("camera is not running"))?;
+    let stills = reply_rx.await.context("camera is not running")??;
+    Ok(stills.into_iter())
+}
+
+fn encode(image: &RgbImage, format: PhotoFormat, quality: u8) -> Result<Vec<u8>> {
+    let mut data = Vec::new();
+    match format {
+        PhotoFormat::Jpeg => {
+            JpegEncoder::new_with_quality(&mut data, quality.clamp(1, 100)).encode_image(image)?
+        }
+        PhotoFormat::Png => image.write_to(&mut Cursor::new(&mut data), ImageOutputFormat::Png)?,
+        PhotoFormat::WebP => {
+            image.write_to(&mut Cursor::new(&mut data), ImageOutputFormat::WebP)?
+        }
+    }
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::Rgb;
+
+    fn request(resolution: PhotoResolution, burst: u8) -> mpsc::Receiver<(u32, PhotoOptions)> {
+        let options = PhotoOptions {
+            resolution,
+            burst,
+            ..Default::default()
+        };
+        let (request_tx, request_rx) = mpsc::channel(1);
+        request_tx.try_send((7, options)).unwrap();
+        request_rx
+    }
+
+    async fn events(mut photo_rx: broadcast::Receiver<PhotoEvent>) -> (usize, CommandResult) {
+        let mut taken = 0;
+        loop {
+            match photo_rx.recv().await.unwrap() {
+                PhotoEvent::Taken(_) => taken += 1,
+                PhotoEvent::Done { request_id, result } => {
+                    assert_eq!(request_id, 7);
+                    return (taken, result);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn encodes() {
+        let image = RgbImage::from_pixel(16, 8, Rgb([200, 100, 50]));
+        let jpeg = encode(&image, PhotoFormat::Jpeg, 90).unwrap();
+        assert_eq!(&jpeg[..2], &[0xff, 0xd8]);
+        let png = encode(&image, PhotoFormat::Png, 90).unwrap();
+        assert_eq!(&png[1..4], b"PNG");
+        let decoded = image::load_from_memory(&png).unwrap().into_rgb8();
+        assert_eq!(decoded, image);
+    }
+
+    #[tokio::test]
+    async fn one_still_request_per_burst() {
+        let request_rx = request(PhotoResolution::Full, 3);
+        let (_camera_tx, camera_rx) = watch::channel(CameraFrame::blank(4, 4));
+        let (still_tx, mut still_rx) = mpsc::channel::<StillRequest>(1);
+        let (_odometry_tx, odometry_rx) = watch::channel(Odometry {
+            x: 0.0,
+            y: 0.0,
+            theta: 0.0,
+        });
+        let (photo_tx, photo_rx) = broadcast::channel(16);
+        tokio::spawn(async move {
+            let StillRequest { count, reply_tx } = still_rx.recv().await.unwrap();
+            let stills = (0..count).map(|_| CameraFrame::blank(8, 8)).collect();
+            assert!(reply_tx.send(Ok(stills)).is_ok());
+            assert!(still_rx.recv().await.is_none());
+        });
+        let taker = tokio::spawn(run_phototaker(
+            request_rx,
+            camera_rx,
+            still_tx,
+            odometry_rx,
+            photo_tx,
+        ));
+
+        assert_eq!(events(photo_rx).await, (3, CommandResult::Done));
+        taker.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn camera_stopping_fails_the_burst() {
+        let request_rx = request(PhotoResolution::Stream, 2);
+        let (camera_tx, camera_rx) = watch::channel(CameraFrame::blank(4, 4));
+        drop(camera_tx);
+        let (still_tx, _still_rx) = mpsc::channel(1);
+        let (_odometry_tx, odometry_rx) = watch::channel(Odometry {
+            x: 0.0,
+            y: 0.0,
+            theta: 0.0,
+        });
+        let (photo_tx, photo_rx) = broadcast::channel(16);
+        tokio::spawn(run_phototaker(
+            request_rx,
+            camera_rx,
+            still_tx,
+            odometry_rx,
+            photo_tx,
+        ));
+
+        let (taken, result) = events(photo_rx).await;
+        assert_eq!(taken, 1);
+        assert!(matches!(result, CommandResult::Failed(_)));
+    }
+}
@@ -1,6 +1,23 @@
 use borsh::{BorshDeserialize, BorshSerialize};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
 
-#[derive(BorshSerialize, BorshDeserialize, PartialEq, Debug, Clone)]
+/// First bytes of every frame, used to reject traffic that is not ours.
+pub const MAGIC: [u8; 4] = *b"CPBR";
+
+/// Must be bumped on every incompatible change of the packets below.
+pub const PROTOCOL_VERSION: u16 = 17;
+
+/// Largest [`PhotoChunk`] payload, small enough to not hold up other packets
+/// on the radio for long.
+pub const PHOTO_CHUNK_SIZE: usize = 1024;
+/// Largest serialized [`Photo`] sent in chunks, above a lossless picture at
+/// the full resolution of the camera.
+pub const MAX_PHOTO_SIZE: usize = 64 << 20;
+
+#[derive(BorshSerialize, BorshDeserialize, Serialize, PartialEq, Debug, Clone)]
 pub struct Odometry {
     pub x: f64,
     pub y: f64,
@@ -13,22 +30,496 @@ impl Odometry {
     }
 }
 
+/// Servo target and where its motion profile has got to, 0 is open and 1 is
+/// closed.
+#[derive(BorshSerialize, BorshDeserialize, PartialEq, Debug, Clone, Copy)]
+pub struct ServoPosition {
+    pub position: f64,
+    pub target: f64,
+}
+
 #[derive(BorshSerialize, BorshDeserialize, PartialEq, Debug, Clone)]
 pub struct Velocity {
     pub linear: f64,
     pub angular: f64,
 }
 
-#[derive(BorshSerialize, BorshDeserialize, PartialEq, Debug)]
-pub enum PacketToSlave {
-    TakePhoto,
+/// Command to the robot. Every command is answered with [`PacketToMaster::Ack`]
+/// carrying the same `id`.
+#[derive(BorshSerialize, BorshDeserialize, PartialEq, Debug, Clone)]
+pub struct PacketToSlave {
+    pub id: u32,
+    pub command: Command,
+}
+
+#[derive(BorshSerialize, BorshDeserialize, PartialEq, Debug, Clone)]
+pub enum Command {
+    /// Answered once the last photo of the burst is taken.
+    TakePhoto(PhotoOptions),
     SetVelocity(Velocity),
+    /// Moves the claw servo, 0 is open and 1 is closed. Limited to the
+    /// robot's calibrated range.
     SetAngle(f64),
+    /// Moves the servo target by this much. Retransmissions are not applied
+    /// twice.
+    JogAngle(f64),
+    /// Moves the servo to a position named in the robot's config.
+    ServoPreset(String),
+    /// Asks for the controller lease. Only the controller may drive the
+    /// robot, other clients are read-only observers. Answered with
+    /// [`CommandResult::Busy`] if another session holds the lease.
+    TakeControl,
+    ReleaseControl,
+    /// Sent by the controller periodically, the robot adapts video quality
+    /// to it.
+    ReportLinkStats(LinkStats),
+    /// Makes the encoder emit a keyframe right away, sent when the decoder
+    /// lost its reference frames.
+    RequestKeyframe,
+    /// Confirms a [`PhotoChunk`]. Not answered, the robot resends the chunk
+    /// until it is acked, so a lost ack costs a resend.
+    AckPhotoChunk {
+        transfer: u32,
+        index: u32,
+    },
+}
+
+#[derive(BorshSerialize, BorshDeserialize, PartialEq, Debug, Clone)]
+pub struct PhotoOptions {
+    pub resolution: PhotoResolution,
+    pub format: PhotoFormat,
+    /// 1 to 100, only used by JPEG.
+    pub quality: u8,
+    /// Photos taken one after another, each from a new camera frame.
+    pub burst: u8,
+}
+
+impl Default for PhotoOptions {
+    fn default() -> Self {
+        Self {
+            resolution: PhotoResolution::Stream,
+            format: PhotoFormat::Jpeg,
+            quality: 90,
+            burst: 1,
+        }
+    }
+}
+
+#[derive(BorshSerialize, BorshDeserialize, PartialEq, Eq, Debug, Clone, Copy)]
+pub enum PhotoResolution {
+    /// The camera frame the video is encoded from.
+    Stream,
+    /// The largest the camera supports. Video stalls while the camera
+    /// switches to it and back.
+    Full,
+}
+
+impl FromStr for PhotoResolution {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "stream" => Ok(Self::Stream),
+            "full" => Ok(Self::Full),
+            _ => Err(format!("unknown resolution {s}, expected stream or full")),
+        }
+    }
+}
+
+#[derive(BorshSerialize, BorshDeserialize, Serialize, PartialEq, Eq, Debug, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum PhotoFormat {
+    Jpeg,
+    Png,
+    /// Lossless.
+    WebP,
+}
+
+impl PhotoFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Self::Jpeg => "jpg",
+            Self::Png => "png",
+            Self::WebP => "webp",
+        }
+    }
+}
+
+impl FromStr for PhotoFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "jpeg" | "jpg" => Ok(Self::Jpeg),
+            "png" => Ok(Self::Png),
+            "webp" => Ok(Self::WebP),
+            _ => Err(format!("unknown format {s}, expected jpeg, png or webp")),
+        }
+    }
+}
+
+/// Video reception measured by the control station over `period_ms`.
+#[derive(BorshSerialize, BorshDeserialize, PartialEq, Debug, Clone)]
+pub struct LinkStats {
+    pub period_ms: u32,
+    pub bytes: u32,
+    pub frames: u32,
+    /// Frames that arrived noticeably later than the fastest ones.
+    pub late_frames: u32,
+    pub decode_errors: u32,
+}
+
+/// Kinds of packets the robot sends, in the order they go out when the link
+/// is busy.
+#[derive(BorshSerialize, BorshDeserialize, PartialEq, Eq, Debug, Clone, Copy)]
+pub enum UplinkStream {
+    /// Odometry, servo position and failsafe.
+    Telemetry,
+    /// Answers to commands.
+    Acks,
+    Video,
+    Photos,
+}
+
+impl UplinkStream {
+    pub const ALL: [Self; 4] = [Self::Telemetry, Self::Acks, Self::Video, Self::Photos];
+}
+
+impl fmt::Display for UplinkStream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Telemetry => write!(f, "telemetry"),
+            Self::Acks => write!(f, "acks"),
+            Self::Video => write!(f, "video"),
+            Self::Photos => write!(f, "photos"),
+        }
+    }
+}
+
+/// What the robot sent over `period_ms`, see [`PacketToMaster::UplinkStats`].
+#[derive(BorshSerialize, BorshDeserialize, PartialEq, Debug, Clone)]
+pub struct UplinkStats {
+    pub period_ms: u32,
+    pub streams: Vec<StreamStats>,
+}
+
+#[derive(BorshSerialize, BorshDeserialize, PartialEq, Debug, Clone)]
+pub struct StreamStats {
+    pub stream: UplinkStream,
+    pub bytes: u32,
+    pub packets: u32,
+    /// Thrown away because the queue was full.
+    pub dropped: u32,
+    /// Waiting in the queue at the end of the period.
+    pub queued: u32,
+}
+
+/// State of the robot itself, see [`PacketToMaster::Health`]. Readings the
+/// robot has no sensor for are `None`.
+#[derive(BorshSerialize, BorshDeserialize, PartialEq, Debug, Clone)]
+pub struct Health {
+    /// Degrees Celsius.
+    pub cpu_temp: Option<f32>,
+    /// Average of runnable processes over the last minute.
+    pub load: Option<f32>,
+    pub cores: u32,
+    /// Bytes.
+    pub mem_used: Option<u64>,
+    pub mem_total: Option<u64>,
+    /// Volts.
+    pub battery: Option<f32>,
+    /// Camera frames waiting for the video encoder.
+    pub encoder_queue: u32,
+    pub tasks: Vec<TaskStatus>,
+}
+
+#[derive(BorshSerialize, BorshDeserialize, PartialEq, Debug, Clone)]
+pub struct TaskStatus {
+    pub name: String,
+    pub alive: bool,
+    /// Why the task stopped, `None` if it returned without an error.
+    pub error: Option<String>,
+}
+
+#[derive(BorshSerialize, BorshDeserialize, PartialEq, Debug, Clone)]
+pub enum CommandResult {
+    Done,
+    /// Robot is still executing a previous command of this kind.
+    Busy,
+    Failed(String),
+}
+
+#[derive(BorshSerialize, BorshDeserialize, Deserialize, PartialEq, Eq, Debug, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum VideoCodec {
+    /// Small packets, but heavy on the Raspberry Pi.
+    Av1,
+    /// Every packet is a JPEG picture, cheap to encode but needs more bandwidth.
+    Mjpeg,
+}
+
+impl fmt::Display for VideoCodec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Av1 => write!(f, "AV1"),
+            Self::Mjpeg => write!(f, "MJPEG"),
+        }
+    }
+}
+
+/// Encoded video packet.
+#[derive(BorshSerialize, BorshDeserialize, PartialEq, Debug, Clone)]
+pub struct VideoFrame {
+    /// Counts packets out of the encoder, a gap means some were lost.
+    pub seq: u32,
+    /// When the camera captured the picture, milliseconds since unix epoch
+    /// by the robot's clock.
+    pub captured_at: u64,
+    /// Decodable without any previous packet.
+    pub keyframe: bool,
+    /// The robot may stream in several codecs, a client only gets the one
+    /// from its [`HelloAck`].
+    pub codec: VideoCodec,
+    /// Of the encoded picture, changes with the quality level.
+    pub width: u32,
+    pub height: u32,
+    pub data: Vec<u8>,
+}
+
+/// Taken for [`Command::TakePhoto`], a burst sends several. Sent in
+/// [`PhotoChunk`]s.
+#[derive(BorshSerialize, BorshDeserialize, PartialEq, Debug, Clone)]
+pub struct Photo {
+    /// Counts photos taken since the robot started.
+    pub id: u32,
+    /// When the camera captured the picture, milliseconds since unix epoch
+    /// by the robot's clock.
+    pub captured_at: u64,
+    /// Where the robot was when taking it.
+    pub pose: Odometry,
+    pub width: u32,
+    pub height: u32,
+    pub format: PhotoFormat,
+    pub data: Vec<u8>,
+}
+
+/// Part of a serialized [`Photo`]. Chunks are resent until the controller
+/// acks them with [`Command::AckPhotoChunk`], which lets a transfer resume
+/// after a reconnect.
+#[derive(BorshSerialize, BorshDeserialize, PartialEq, Debug, Clone)]
+pub struct PhotoChunk {
+    /// Unique across robot restarts.
+    pub transfer: u32,
+    pub index: u32,
+    /// Chunks in the transfer.
+    pub count: u32,
+    pub data: Vec<u8>,
 }
 
 #[derive(BorshSerialize, BorshDeserialize, PartialEq, Debug)]
 pub enum PacketToMaster {
-    Video(Vec<u8>),
-    Photo(Vec<u8>),
+    Video(VideoFrame),
+    PhotoChunk(PhotoChunk),
     Odometry(Odometry),
+    /// Sent while the servo moves and when it stops.
+    Servo(ServoPosition),
+    Ack {
+        id: u32,
+        result: CommandResult,
+    },
+    /// Robot stopped itself because velocity commands stopped coming.
+    Failsafe {
+        tripped: bool,
+    },
+    /// Sent every second by the uplink scheduler.
+    UplinkStats(UplinkStats),
+    /// Sent every few seconds.
+    Health(Health),
+    /// Sent to every client when the controller lease changes hands.
+    ControlChanged {
+        controller: Option<u32>,
+    },
+}
+
+/// First packet sent by the client after connecting.
+#[derive(BorshSerialize, BorshDeserialize, PartialEq, Debug, Clone)]
+pub struct Hello {
+    pub name: String,
+    /// Video codecs the client can decode.
+    pub codecs: Vec<VideoCodec>,
+}
+
+/// Server reply to [`Hello`].
+#[derive(BorshSerialize, BorshDeserialize, PartialEq, Debug, Clone)]
+pub struct HelloAck {
+    pub accepted: bool,
+    /// Id of this connection, compare with [`PacketToMaster::ControlChanged`].
+    pub session: u32,
+    /// Codec of the client's video, the robot's most preferred one the client
+    /// can decode. The client is rejected if there is none.
+    pub codec: VideoCodec,
+}
+
+/// Wire frame wrapping every packet. Its layout must never change, so that
+/// peers with different protocol versions can still tell each other apart.
+#[derive(BorshSerialize, BorshDeserialize, PartialEq, Debug, Clone)]
+pub struct Envelope {
+    pub magic: [u8; 4],
+    pub version: u16,
+    pub seq: u32,
+    /// Milliseconds since unix epoch.
+    pub timestamp: u64,
+    /// Length-prefixed serialized packet.
+    pub payload: Vec<u8>,
+}
+
+impl Envelope {
+    /// Parses a frame, checking magic and protocol version.
+    pub fn decode(bytes: &[u8]) -> Result<Self, ProtoError> {
+        let envelope = Self::try_from_slice(bytes).map_err(ProtoError::Malformed)?;
+        if envelope.magic != MAGIC {
+            return Err(ProtoError::BadMagic(envelope.magic));
+        }
+        if envelope.version != PROTOCOL_VERSION {
+            return Err(ProtoError::VersionMismatch {
+                local: PROTOCOL_VERSION,
+                remote: envelope.version,
+            });
+        }
+        Ok(envelope)
+    }
+
+    pub fn packet<T: BorshDeserialize>(&self) -> Result<T, ProtoError> {
+        T::try_from_slice(&self.payload).map_err(ProtoError::Malformed)
+    }
+}
+
+/// Wraps outgoing packets into envelopes with increasing sequence numbers.
+#[derive(Default)]
+pub struct Framer {
+    seq: u32,
+}
+
+impl Framer {
+    pub fn frame(&mut self, payload: Vec<u8>) -> Result<Vec<u8>, ProtoError> {
+        let envelope = Envelope {
+            magic: MAGIC,
+            version: PROTOCOL_VERSION,
+            seq: self.seq,
+            timestamp: now_millis(),
+            payload,
+        };
+        self.seq = self.seq.wrapping_add(1);
+        envelope.try_to_vec().map_err(ProtoError::Malformed)
+    }
+
+    pub fn frame_packet<T: BorshSerialize>(&mut self, packet: &T) -> Result<Vec<u8>, ProtoError> {
+        let payload = packet.try_to_vec().map_err(ProtoError::Malformed)?;
+        self.frame(payload)
+    }
+}
+
+pub fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+#[derive(Debug)]
+pub enum ProtoError {
+    Malformed(std::io::Error),
+    BadMagic([u8; 4]),
+    VersionMismatch { local: u16, remote: u16 },
+    HandshakeRejected,
+}
+
+impl fmt::Display for ProtoError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Malformed(e) => write!(f, "malformed packet: {e}"),
+            Self::BadMagic(m) => write!(f, "bad magic {m:X?}"),
+            Self::VersionMismatch { local, remote } => write!(
+                f,
+                "protocol version mismatch: we speak v{local}, peer speaks v{remote}"
+            ),
+            Self::HandshakeRejected => write!(f, "handshake rejected by peer"),
+        }
+    }
+}
+
+impl std::error::Error for ProtoError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(magic: [u8; 4], version: u16) -> Vec<u8> {
+        Envelope {
+            magic,
+            version,
+            seq: 0,
+            timestamp: 0,
+            payload: Vec::new(),
+        }
+        .try_to_vec()
+        .unwrap()
+    }
+
+    #[test]
+    fn roundtrip() {
+        let packet = PacketToSlave {
+            id: 7,
+            command: Command::SetVelocity(Velocity {
+                linear: 1.0,
+                angular: -0.5,
+            }),
+        };
+        let mut framer = Framer::default();
+        framer.frame_packet(&packet).unwrap();
+        let bytes = framer.frame_packet(&packet).unwrap();
+
+        let envelope = Envelope::decode(&bytes).unwrap();
+        assert_eq!(envelope.seq, 1);
+        assert_eq!(envelope.packet::<PacketToSlave>().unwrap(), packet);
+    }
+
+    #[test]
+    fn bad_magic() {
+        assert!(matches!(
+            Envelope::decode(&frame(*b"NOPE", PROTOCOL_VERSION)),
+            Err(ProtoError::BadMagic(m)) if m == *b"NOPE"
+        ));
+    }
+
+    #[test]
+    fn version_mismatch() {
+        assert!(matches!(
+            Envelope::decode(&frame(MAGIC, PROTOCOL_VERSION + 1)),
+            Err(ProtoError::VersionMismatch { local, remote })
+                if local == PROTOCOL_VERSION && remote == PROTOCOL_VERSION + 1
+        ));
+    }
+
+    #[test]
+    fn truncated() {
+        let bytes = frame(MAGIC, PROTOCOL_VERSION);
+        assert!(matches!(
+            Envelope::decode(&bytes[..bytes.len() - 1]),
+            Err(ProtoError::Malformed(_))
+        ));
+    }
+
+    #[test]
+    fn photo_options_from_str() {
+        assert_eq!("stream".parse(), Ok(PhotoResolution::Stream));
+        assert_eq!("full".parse(), Ok(PhotoResolution::Full));
+        assert!("Full".parse::<PhotoResolution>().is_err());
+
+        assert_eq!("jpeg".parse(), Ok(PhotoFormat::Jpeg));
+        assert_eq!("jpg".parse(), Ok(PhotoFormat::Jpeg));
+        assert_eq!("png".parse(), Ok(PhotoFormat::Png));
+        assert_eq!("webp".parse(), Ok(PhotoFormat::WebP));
+        assert!("gif".parse::<PhotoFormat>().is_err());
+    }
 }
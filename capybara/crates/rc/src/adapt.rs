@@ -0,0 +1,205 @@
+use anyhow::Result;
+use log::*;
+use tokio::sync::{mpsc, watch};
+
+use common::config::VideoConfig;
+use encoder::EncoderParams;
+use proto::LinkStats;
+
+/// Quality levels from the slowest radio to good Wi-Fi.
+const LEVELS: [EncoderParams; 5] = [
+    EncoderParams {
+        width: 160,
+        height: 120,
+        fps: 5,
+        bitrate: 15,
+        quantizer: 100,
+        min_quantizer: 180,
+        key_frame_interval: 25,
+    },
+    EncoderParams {
+        width: 320,
+        height: 240,
+        fps: 10,
+        bitrate: 30,
+        quantizer: 100,
+        min_quantizer: 160,
+        key_frame_interval: 50,
+    },
+    EncoderParams {
+        width: 320,
+        height: 240,
+        fps: 15,
+        bitrate: 150,
+        quantizer: 100,
+        min_quantizer: 120,
+        key_frame_interval: 75,
+    },
+    EncoderParams {
+        width: 640,
+        height: 480,
+        fps: 15,
+        bitrate: 500,
+        quantizer: 100,
+        min_quantizer: 80,
+        key_frame_interval: 75,
+    },
+    EncoderParams {
+        width: 640,
+        height: 480,
+        fps: 30,
+        bitrate: 1500,
+        quantizer: 100,
+        min_quantizer: 40,
+        key_frame_interval: 150,
+    },
+];
+
+/// Good reports needed before trying the next level.
+const UPGRADE_AFTER: u32 = 5;
+const MAX_UPGRADE_AFTER: u32 = 80;
+
+pub fn initial_params(config: &VideoConfig) -> EncoderParams {
+    LEVELS[config.level.min(LEVELS.len() - 1)].clone()
+}
+
+/// Steps quality down as soon as the link struggles and probes the next
+/// level up after a run of good reports. Each failed probe doubles the wait
+/// before the next one, so a link at its limit doesn't oscillate.
+struct Adapter {
+    level: usize,
+    good_reports: u32,
+    upgrade_after: u32,
+    probing: bool,
+}
+
+impl Adapter {
+    fn new(level: usize) -> Self {
+        Self {
+            level: level.min(LEVELS.len() - 1),
+            good_reports: 0,
+            upgrade_after: UPGRADE_AFTER,
+            probing: false,
+        }
+    }
+
+    fn update(&mut self, stats: &LinkStats) -> Option<usize> {
+        // No frames at all means nothing gets through
+        let congested =
+            stats.frames == 0 || stats.decode_errors > 0 || stats.late_frames * 5 > stats.frames;
+        if congested {
+            self.good_reports = 0;
+            if self.probing {
+                self.upgrade_after = (self.upgrade_after * 2).min(MAX_UPGRADE_AFTER);
+            }
+            self.probing = false;
+            if self.level > 0 {
+                self.level -= 1;
+                return Some(self.level);
+            }
+            return None;
+        }
+
+        self.good_reports += 1;
+        if self.good_reports < self.upgrade_after {
+            return None;
+        }
+        self.good_reports = 0;
+        if self.probing {
+            // Previous probe held up
+            self.upgrade_after = UPGRADE_AFTER;
+        }
+        self.probing = false;
+        if self.level + 1 < LEVELS.len() {
+            self.level += 1;
+            self.probing = true;
+            return Some(self.level);
+        }
+        None
+    }
+}
+
+pub async fn run_adapter(
+    config: VideoConfig,
+    mut stats_rx: mpsc::Receiver<LinkStats>,
+    params_tx: watch::Sender<EncoderParams>,
+) -> Result<()> {
+    let mut adapter = Adapter::new(config.level);
+    while let Some(stats) = stats_rx.recv().await {
+        debug!(
+            "link: {} B/s, {} frames, {} late, {} decode errors",
+            stats.bytes as u64 * 1000 / stats.period_ms.max(1) as u64,
+            stats.frames,
+            stats.late_frames,
+            stats.decode_errors
+        );
+        if !config.adaptive {
+            continue;
+        }
+        if let Some(level) = adapter.update(&stats) {
+            info!("switching video to quality level {level}");
+            let _ = params_tx.send(LEVELS[level].clone());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GOOD: LinkStats = LinkStats {
+        period_ms: 1000,
+        bytes: 4000,
+        frames: 10,
+        late_frames: 0,
+        decode_errors: 0,
+    };
+    const CONGESTED: LinkStats = LinkStats {
+        late_frames: 5,
+        ..GOOD
+    };
+
+    /// Level switched to after `reports` good reports, if any.
+    fn good_run(adapter: &mut Adapter, reports: u32) -> Option<usize> {
+        (0..reports).filter_map(|_| adapter.update(&GOOD)).last()
+    }
+
+    #[test]
+    fn steps_down_at_once() {
+        let mut adapter = Adapter::new(2);
+        assert_eq!(adapter.update(&CONGESTED), Some(1));
+        let silent = LinkStats { frames: 0, ..GOOD };
+        assert_eq!(adapter.update(&silent), Some(0));
+        assert_eq!(adapter.update(&CONGESTED), None);
+    }
+
+    #[test]
+    fn steps_up_after_good_reports() {
+        let mut adapter = Adapter::new(1);
+        assert_eq!(good_run(&mut adapter, UPGRADE_AFTER - 1), None);
+        assert_eq!(adapter.update(&GOOD), Some(2));
+        assert_eq!(good_run(&mut adapter, UPGRADE_AFTER), Some(3));
+    }
+
+    #[test]
+    fn failed_probe_backs_off() {
+        let mut adapter = Adapter::new(1);
+        assert_eq!(good_run(&mut adapter, UPGRADE_AFTER), Some(2));
+        assert_eq!(adapter.update(&CONGESTED), Some(1));
+        // Twice as long before probing again
+        assert_eq!(good_run(&mut adapter, 2 * UPGRADE_AFTER - 1), None);
+        assert_eq!(adapter.update(&GOOD), Some(2));
+        // The probe holds up, so the wait is back to normal after it
+        assert_eq!(good_run(&mut adapter, 2 * UPGRADE_AFTER - 1), None);
+        assert_eq!(adapter.update(&GOOD), Some(3));
+        assert_eq!(good_run(&mut adapter, UPGRADE_AFTER), Some(4));
+    }
+
+    #[test]
+    fn stays_at_the_top() {
+        let mut adapter = Adapter::new(LEVELS.len());
+        assert_eq!(adapter.level, LEVELS.len() - 1);
+        assert_eq!(good_run(&mut adapter, 10 * UPGRADE_AFTER), None);
+    }
+}
@@ -0,0 +1,135 @@
+//! Periodic report on the robot's computer, battery and tasks, so that the
+//! station notices an overheating Raspberry Pi or a task that died.
+
+use anyhow::Result;
+use std::path::Path;
+use tokio::sync::watch;
+use tokio::time::{interval, Duration};
+
+use common::config::HealthConfig;
+use proto::{Health, PacketToMaster, TaskStatus, UplinkStream};
+
+use crate::uplink::Uplink;
+
+const HEALTH_PERIOD: Duration = Duration::from_secs(2);
+const POWER_SUPPLIES: &str = "/sys/class/power_supply";
+
+/// Reports what it can read from sysfs and procfs, along with the tasks in
+/// every `tasks_rx` and the longest queue of `encoder_queue_rx`.
+pub async fn run_health(
+    config: HealthConfig,
+    tasks_rx: Vec<watch::Receiver<Vec<TaskStatus>>>,
+    encoder_queue_rx: Vec<watch::Receiver<u32>>,
+    uplink: Uplink,
+) -> Result<()> {
+    let cores = std::thread::available_parallelism().map_or(1, |n| n.get() as u32);
+    let battery_path = config
+        .battery
+        .map(|supply| Path::new(POWER_SUPPLIES).join(supply).join("voltage_now"));
+    let mut health_interval = interval(HEALTH_PERIOD);
+    loop {
+        health_interval.tick().await;
+        let (mem_used, mem_total) = read("/proc/meminfo")
+            .await
+            .and_then(|m| parse_meminfo(&m))
+            .unzip();
+        let battery = match &battery_path {
+            Some(path) => read(path).await.and_then(|v| parse_voltage(&v)),
+            None => None,
+        };
+        let health = Health {
+            cpu_temp: read("/sys/class/thermal/thermal_zone0/temp")
+                .await
+                .and_then(|t| parse_temp(&t)),
+            load: read("/proc/loadavg").await.and_then(|l| parse_loadavg(&l)),
+            cores,
+            mem_used,
+            mem_total,
+            battery,
+            encoder_queue: encoder_queue_rx
+                .iter()
+                .map(|rx| *rx.borrow())
+                .max()
+                .unwrap_or_default(),
+            tasks: tasks_rx.iter().flat_map(|rx| rx.borrow().clone()).collect(),
+        };
+        uplink.send(UplinkStream::Telemetry, &PacketToMaster::Health(health))?;
+    }
+}
+
+async fn read(path: impl AsRef<Path>) -> Option<String> {
+    tokio::fs::read_to_string(path).await.ok()
+}
+
+/// In millidegrees in sysfs.
+fn parse_temp(temp: &str) -> Option<f32> {
+    let millis: f32 = temp.trim().parse().ok()?;
+    Some(millis / 1000.0)
+}
+
+/// The 1 minute load average.
+fn parse_loadavg(loadavg: &str) -> Option<f32> {
+    loadavg.split_whitespace().next()?.parse().ok()
+}
+
+/// Used and total bytes. Caches count as free, as the kernel gives them up
+/// when needed.
+fn parse_meminfo(meminfo: &str) -> Option<(u64, u64)> {
+    let field = |name: &str| -> Option<u64> {
+        let kb: u64 = meminfo
+            .lines()
+            .find_map(|line| line.strip_prefix(name))?
+            .trim()
+            .trim_end_matches("kB")
+            .trim()
+            .parse()
+            .ok()?;
+        Some(kb * 1024)
+    };
+    let total = field("MemTotal:")?;
+    let available = field("MemAvailable:")?;
+    Some((total.saturating_sub(available), total))
+}
+
+/// In microvolts in sysfs.
+fn parse_voltage(voltage: &str) -> Option<f32> {
+    let micros: f32 = voltage.trim().parse().ok()?;
+    Some(micros / 1_000_000.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn meminfo() {
+        let meminfo = "\
+MemTotal:        3884328 kB
+MemFree:          211504 kB
+MemAvailable:    2853616 kB
+Buffers:          110412 kB
+";
+        let used = (3884328 - 2853616) * 1024;
+        assert_eq!(parse_meminfo(meminfo), Some((used, 3884328 * 1024)));
+        // Kernels before 3.14 don't have MemAvailable
+        assert_eq!(
+            parse_meminfo("MemTotal: 3884328 kB\nMemFree: 211504 kB\n"),
+            None
+        );
+        assert_eq!(parse_meminfo(""), None);
+    }
+
+    #[test]
+    fn loadavg() {
+        assert_eq!(parse_loadavg("0.52 0.58 0.59 1/389 12345\n"), Some(0.52));
+        assert_eq!(parse_loadavg(""), None);
+    }
+
+    #[test]
+    fn sysfs_units() {
+        assert_eq!(parse_temp("48312\n"), Some(48.312));
+        assert_eq!(parse_temp("\n"), None);
+        assert_eq!(parse_voltage("12150000\n"), Some(12.15));
+        assert_eq!(parse_voltage("n/a"), None);
+    }
+}
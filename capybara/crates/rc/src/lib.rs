@@ -1,72 +1,328 @@
-use anyhow::Result;
-use borsh::{BorshDeserialize, BorshSerialize};
-use image::RgbImage;
+use anyhow::{bail, Result};
+use borsh::BorshDeserialize;
 use log::*;
+use std::collections::VecDeque;
+use tokio::sync::mpsc::error::TrySendError;
 use tokio::sync::{broadcast, mpsc, watch};
-use tokio::task::JoinSet;
+use tokio::time::{sleep, Duration};
 
-use common::wait_tasks;
-use encoder::run_encoder;
-use phototaker::run_phototaker;
-use proto::{Odometry, Velocity};
-use proto::{PacketToMaster, PacketToSlave};
+use adapt::{initial_params, run_adapter};
+use common::backend::{CameraFrame, StillRequest};
+use common::config::{HealthConfig, ServoConfig, UplinkConfig, VideoConfig};
+use common::Tasks;
+use encoder::{run_encoder, supported_codecs};
+use health::run_health;
+use photos::run_photo_sender;
+use phototaker::{run_phototaker, PhotoEvent, MAX_BURST};
+use proto::{Command, CommandResult, PacketToMaster, PacketToSlave};
+use proto::{Odometry, ServoPosition, TaskStatus, UplinkStream, Velocity, VideoCodec};
+use uplink::{run_uplink, Uplink};
 
+mod adapt;
+mod health;
+mod photos;
+mod uplink;
+
+/// How many recent command ids are remembered to skip retransmissions.
+const RECENT_COMMANDS: usize = 64;
+/// A moving servo reports its position ten times a second.
+const SERVO_REPORT_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Channels to the tasks driving the hardware.
+pub struct Robot {
+    /// Servo target, already within the configured limits.
+    pub angle_tx: watch::Sender<f64>,
+    pub servo_rx: watch::Receiver<ServoPosition>,
+    pub velocity_tx: broadcast::Sender<Velocity>,
+    pub odometry_rx: watch::Receiver<Odometry>,
+    pub camera_rx: watch::Receiver<CameraFrame>,
+    pub still_tx: mpsc::Sender<StillRequest>,
+    pub failsafe_rx: watch::Receiver<bool>,
+    /// Tasks of the program, reported to the station along with ours.
+    pub tasks_rx: watch::Receiver<Vec<TaskStatus>>,
+}
+
+/// Codecs of the video streams, the preferred one first. Clients that can't
+/// decode it fall back to MJPEG, which is cheap to encode alongside.
+pub fn stream_codecs(video: &VideoConfig) -> Result<Vec<VideoCodec>> {
+    let supported = supported_codecs();
+    let codec = match video.codec {
+        Some(codec) if !supported.contains(&codec) => {
+            bail!("{codec} video is configured, but not built in")
+        }
+        Some(codec) => codec,
+        None => supported[0],
+    };
+    let mut codecs = vec![codec];
+    if codec != VideoCodec::Mjpeg {
+        codecs.push(VideoCodec::Mjpeg);
+    }
+    Ok(codecs)
+}
+
+/// Commands that must not be executed twice, with their results to answer
+/// retransmissions of commands whose ack got lost. ws numbers the commands of
+/// all sessions, so ids don't repeat after a reconnect.
+#[derive(Default)]
+struct RecentCommands(VecDeque<(u32, Option<CommandResult>)>);
+
+impl RecentCommands {
+    fn contains(&self, id: u32) -> bool {
+        self.0.iter().any(|&(i, _)| i == id)
+    }
+
+    /// `None` while the command is still running.
+    fn result(&self, id: u32) -> Option<CommandResult> {
+        self.0
+            .iter()
+            .find(|&&(i, _)| i == id)
+            .and_then(|(_, result)| result.clone())
+    }
+
+    fn push(&mut self, id: u32, result: Option<CommandResult>) {
+        if self.0.len() == RECENT_COMMANDS {
+            self.0.pop_front();
+        }
+        self.0.push_back((id, result));
+    }
+
+    fn finish(&mut self, id: u32, result: CommandResult) {
+        if let Some((_, r)) = self.0.iter_mut().find(|(i, _)| *i == id) {
+            *r = Some(result);
+        }
+    }
+}
+
+/// `codecs_rx` has the codecs of [`stream_codecs`] clients watch, as reported
+/// by ws. The preferred one is always encoded, so that recordings have video.
+#[allow(clippy::too_many_arguments)]
 pub async fn run_rc(
     mut down_rx: broadcast::Receiver<Vec<u8>>,
     up_tx: broadcast::Sender<Vec<u8>>,
-    angle_tx: watch::Sender<f64>,
-    velocity_tx: broadcast::Sender<Velocity>,
-    mut odometry_rx: watch::Receiver<Odometry>,
-    camera_rx: watch::Receiver<RgbImage>,
+    codecs_rx: watch::Receiver<Vec<VideoCodec>>,
+    robot: Robot,
+    servo: ServoConfig,
+    video: VideoConfig,
+    uplink_config: UplinkConfig,
+    health_config: HealthConfig,
 ) -> Result<()> {
-    let mut tasks = JoinSet::<Result<()>>::new();
+    let Robot {
+        angle_tx,
+        mut servo_rx,
+        velocity_tx,
+        mut odometry_rx,
+        camera_rx,
+        still_tx,
+        mut failsafe_rx,
+        tasks_rx,
+    } = robot;
+    let codecs = stream_codecs(&video)?;
+    let mut tasks = Tasks::default();
+
+    let (uplink, packet_rx) = Uplink::channel();
+    tasks.spawn("uplink", run_uplink(uplink_config, packet_rx, up_tx));
 
     let (photo_request_tx, photo_request_rx) = mpsc::channel(1);
-    let (photo_data_tx, mut photo_data_rx) = broadcast::channel(8);
-    tasks.spawn(run_phototaker(
-        photo_request_rx,
-        camera_rx.clone(),
-        photo_data_tx,
-    ));
+    let (photo_tx, photo_rx) = broadcast::channel(2 * MAX_BURST as usize);
+    let mut photo_events = photo_tx.subscribe();
+    tasks.spawn(
+        "phototaker",
+        run_phototaker(
+            photo_request_rx,
+            camera_rx.clone(),
+            still_tx,
+            odometry_rx.clone(),
+            photo_tx,
+        ),
+    );
+    // Acks are repeated for resent chunks, so dropping some does no harm
+    let (chunk_ack_tx, chunk_ack_rx) = mpsc::channel(64);
+    tasks.spawn(
+        "photo sender",
+        run_photo_sender(photo_rx, chunk_ack_rx, uplink.clone()),
+    );
+
+    let (params_tx, params_rx) = watch::channel(initial_params(&video));
+    let (link_stats_tx, link_stats_rx) = mpsc::channel(4);
+    tasks.spawn(
+        "video adapter",
+        run_adapter(video, link_stats_rx, params_tx),
+    );
 
     let (encoder_tx, mut encoder_rx) = broadcast::channel(32);
-    tasks.spawn(run_encoder(camera_rx, encoder_tx));
+    let mut keyframe_txs = Vec::new();
+    let mut encoder_queue_rxs = Vec::new();
+    for (i, codec) in codecs.into_iter().enumerate() {
+        // One pending request is enough, the next frame becomes a keyframe
+        let (keyframe_tx, keyframe_rx) = mpsc::channel(1);
+        let (queue_tx, queue_rx) = watch::channel(0);
+        tasks.spawn(
+            if i == 0 {
+                "encoder"
+            } else {
+                "fallback encoder"
+            },
+            run_encoder(
+                codec,
+                (i > 0).then(|| codecs_rx.clone()),
+                camera_rx.clone(),
+                params_rx.clone(),
+                keyframe_rx,
+                encoder_tx.clone(),
+                queue_tx,
+            ),
+        );
+        keyframe_txs.push(keyframe_tx);
+        encoder_queue_rxs.push(queue_rx);
+    }
+    drop(encoder_tx);
+    tasks.spawn(
+        "health",
+        run_health(
+            health_config,
+            vec![tasks_rx, tasks.status()],
+            encoder_queue_rxs,
+            uplink.clone(),
+        ),
+    );
 
-    tasks.spawn(async move {
+    let uplink_ack = uplink.clone();
+    tasks.spawn("commands", async move {
+        let mut recent = RecentCommands::default();
+        let mut phototaker_running = true;
         loop {
-            let cmd_bytes = match down_rx.recv().await {
-                Ok(d) => d,
-                Err(broadcast::error::RecvError::Lagged(l)) => {
-                    error!("lagged for {l} packets");
+            let cmd_bytes = tokio::select! {
+                cmd = down_rx.recv() => match cmd {
+                    Ok(d) => d,
+                    Err(broadcast::error::RecvError::Lagged(l)) => {
+                        error!("lagged for {l} packets");
+                        continue;
+                    }
+                    Err(_) => return Ok(()),
+                },
+                event = photo_events.recv(), if phototaker_running => {
+                    match event {
+                        Ok(PhotoEvent::Done { request_id, result }) => {
+                            recent.finish(request_id, result.clone());
+                            let pkt = PacketToMaster::Ack {
+                                id: request_id,
+                                result,
+                            };
+                            uplink_ack.send(UplinkStream::Acks, &pkt)?;
+                        }
+                        Ok(PhotoEvent::Taken(_)) => {}
+                        Err(broadcast::error::RecvError::Lagged(l)) => {
+                            error!("lagged for {l} photo events");
+                        }
+                        Err(_) => phototaker_running = false,
+                    }
                     continue;
                 }
-                Err(_) => return Ok(()),
             };
             debug!("got cmd len = {}", cmd_bytes.len());
-            let cmd = PacketToSlave::try_from_slice(&cmd_bytes)?;
-            match cmd {
-                PacketToSlave::TakePhoto => {
-                    let _ = photo_request_tx.send(()).await;
+            // Like ws, skip what a client of another version might send
+            let PacketToSlave { id, command } = match PacketToSlave::try_from_slice(&cmd_bytes) {
+                Ok(pkt) => pkt,
+                Err(e) => {
+                    warn!("skipping undecodable command: {e}");
+                    continue;
                 }
-                PacketToSlave::SetVelocity(v) => {
-                    let _ = velocity_tx.send(v);
+            };
+            let result = match command {
+                Command::TakePhoto(options) => {
+                    // Acked once the phototaker is done
+                    if recent.contains(id) {
+                        match recent.result(id) {
+                            Some(result) => result,
+                            None => {
+                                debug!("photo command {id} is still running");
+                                continue;
+                            }
+                        }
+                    } else if !(1..=MAX_BURST).contains(&options.burst) {
+                        CommandResult::Failed(format!(
+                            "burst of {} photos, must be 1 to {MAX_BURST}",
+                            options.burst
+                        ))
+                    } else {
+                        match photo_request_tx.try_send((id, options)) {
+                            Ok(_) => {
+                                recent.push(id, None);
+                                continue;
+                            }
+                            Err(TrySendError::Full(_)) => CommandResult::Busy,
+                            Err(TrySendError::Closed(_)) => {
+                                CommandResult::Failed("phototaker is not running".to_string())
+                            }
+                        }
+                    }
                 }
-                PacketToSlave::SetAngle(a) => {
-                    let _ = angle_tx.send(a);
+                Command::SetVelocity(v) => match velocity_tx.send(v) {
+                    Ok(_) => CommandResult::Done,
+                    Err(_) => CommandResult::Failed("drive is not running".to_string()),
+                },
+                Command::SetAngle(a) => match angle_tx.send(servo.clamp(a)) {
+                    Ok(_) => CommandResult::Done,
+                    Err(_) => CommandResult::Failed("servo is not running".to_string()),
+                },
+                // Jogs add up, a retransmitted one only gets its result again
+                Command::JogAngle(delta) => match recent.result(id) {
+                    Some(result) => result,
+                    None => {
+                        angle_tx.send_modify(|target| *target = servo.clamp(*target + delta));
+                        recent.push(id, Some(CommandResult::Done));
+                        CommandResult::Done
+                    }
+                },
+                Command::ServoPreset(name) => match servo.preset(&name) {
+                    Some(a) => match angle_tx.send(a) {
+                        Ok(_) => CommandResult::Done,
+                        Err(_) => CommandResult::Failed("servo is not running".to_string()),
+                    },
+                    None => CommandResult::Failed(format!("no servo preset {name}")),
+                },
+                Command::ReportLinkStats(stats) => match link_stats_tx.try_send(stats) {
+                    Ok(_) => CommandResult::Done,
+                    Err(TrySendError::Full(_)) => CommandResult::Busy,
+                    Err(TrySendError::Closed(_)) => {
+                        CommandResult::Failed("video adapter is not running".to_string())
+                    }
+                },
+                Command::AckPhotoChunk { transfer, index } => {
+                    let _ = chunk_ack_tx.try_send((transfer, index));
+                    continue;
                 }
-            }
+                // Not knowing the client's codec, every encoder makes one
+                Command::RequestKeyframe => {
+                    let mut running = false;
+                    for keyframe_tx in &keyframe_txs {
+                        running |=
+                            !matches!(keyframe_tx.try_send(()), Err(TrySendError::Closed(_)));
+                    }
+                    match running {
+                        true => CommandResult::Done,
+                        false => CommandResult::Failed("encoder is not running".to_string()),
+                    }
+                }
+                // Handled by ws, never forwarded here
+                Command::TakeControl | Command::ReleaseControl => {
+                    CommandResult::Failed("control lease is managed by ws".to_string())
+                }
+            };
+            let pkt = PacketToMaster::Ack { id, result };
+            uplink_ack.send(UplinkStream::Acks, &pkt)?;
         }
     });
 
-    let up_tx_odometry = up_tx.clone();
-    tasks.spawn(async move {
+    let uplink_odometry = uplink.clone();
+    tasks.spawn("odometry", async move {
         let mut skipped = 4u8;
         while odometry_rx.changed().await.is_ok() {
             if skipped > 3 {
                 skipped = 0;
                 let o = (*odometry_rx.borrow()).clone();
                 let pkt = PacketToMaster::Odometry(o);
-                let _ = up_tx_odometry.send(pkt.try_to_vec()?);
+                uplink_odometry.send(UplinkStream::Telemetry, &pkt)?;
             } else {
                 skipped += 1;
             }
@@ -74,39 +330,119 @@ pub async fn run_rc(
         Ok(())
     });
 
-    let up_tx_video = up_tx.clone();
-    tasks.spawn(async move {
-        loop {
-            let video_data = match encoder_rx.recv().await {
-                Ok(d) => d,
-                Err(broadcast::error::RecvError::Lagged(l)) => {
-                    error!("lagged for {l} video packets");
-                    continue;
-                }
-                Err(_) => return Ok(()),
-            };
-            let pkt = PacketToMaster::Video(video_data);
-            let _ = up_tx_video.send(pkt.try_to_vec()?);
+    let uplink_servo = uplink.clone();
+    tasks.spawn("servo reports", async move {
+        while servo_rx.changed().await.is_ok() {
+            let position = *servo_rx.borrow_and_update();
+            let pkt = PacketToMaster::Servo(position);
+            uplink_servo.send(UplinkStream::Telemetry, &pkt)?;
+            sleep(SERVO_REPORT_INTERVAL).await;
         }
+        Ok(())
     });
 
-    let up_tx_photo = up_tx.clone();
-    tasks.spawn(async move {
+    let uplink_failsafe = uplink.clone();
+    tasks.spawn("failsafe", async move {
+        while failsafe_rx.changed().await.is_ok() {
+            let tripped = *failsafe_rx.borrow();
+            let pkt = PacketToMaster::Failsafe { tripped };
+            uplink_failsafe.send(UplinkStream::Telemetry, &pkt)?;
+        }
+        Ok(())
+    });
+
+    let uplink_video = uplink.clone();
+    tasks.spawn("video", async move {
         loop {
-            let photo_data = match photo_data_rx.recv().await {
+            let video_frame = match encoder_rx.recv().await {
                 Ok(d) => d,
                 Err(broadcast::error::RecvError::Lagged(l)) => {
-                    error!("lagged for {l} photos");
+                    error!("lagged for {l} video packets");
                     continue;
                 }
                 Err(_) => return Ok(()),
             };
-            let pkt = PacketToMaster::Photo(photo_data);
-            let _ = up_tx_photo.send(pkt.try_to_vec()?);
+            let pkt = PacketToMaster::Video(video_frame);
+            uplink_video.send(UplinkStream::Video, &pkt)?;
         }
     });
 
-    wait_tasks(tasks).await;
+    tasks.wait().await;
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use borsh::BorshSerialize;
+
+    #[tokio::test]
+    async fn garbage_commands_are_skipped() {
+        let (down_tx, down_rx) = broadcast::channel(8);
+        let (up_tx, mut up_rx) = broadcast::channel(1024);
+        let (angle_tx, _angle_rx) = watch::channel(0.5);
+        let (_servo_tx, servo_rx) = watch::channel(ServoPosition {
+            position: 0.5,
+            target: 0.5,
+        });
+        let (velocity_tx, mut velocity_rx) = broadcast::channel(8);
+        let (_odometry_tx, odometry_rx) = watch::channel(Odometry {
+            x: 0.0,
+            y: 0.0,
+            theta: 0.0,
+        });
+        let (_camera_tx, camera_rx) = watch::channel(CameraFrame::blank(64, 48));
+        let (still_tx, _still_rx) = mpsc::channel(1);
+        let (_failsafe_tx, failsafe_rx) = watch::channel(false);
+        let (_tasks_tx, tasks_rx) = watch::channel(Vec::new());
+        let (_codecs_tx, codecs_rx) = watch::channel(Vec::new());
+        let robot = Robot {
+            angle_tx,
+            servo_rx,
+            velocity_tx,
+            odometry_rx,
+            camera_rx,
+            still_tx,
+            failsafe_rx,
+            tasks_rx,
+        };
+        let rc = tokio::spawn(run_rc(
+            down_rx,
+            up_tx,
+            codecs_rx,
+            robot,
+            ServoConfig::default(),
+            VideoConfig::default(),
+            UplinkConfig::default(),
+            HealthConfig::default(),
+        ));
+
+        let velocity = Velocity {
+            linear: 0.3,
+            angular: 0.0,
+        };
+        let command = PacketToSlave {
+            id: 7,
+            command: Command::SetVelocity(velocity.clone()),
+        };
+        down_tx.send(vec![0xff; 3]).unwrap();
+        down_tx.send(command.try_to_vec().unwrap()).unwrap();
+
+        let applied = tokio::time::timeout(Duration::from_secs(5), velocity_rx.recv()).await;
+        assert_eq!(applied.unwrap().unwrap(), velocity);
+        let acked = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                let Ok(pkt) = up_rx.recv().await else {
+                    continue;
+                };
+                if let Ok(PacketToMaster::Ack { id, result }) = PacketToMaster::try_from_slice(&pkt)
+                {
+                    return (id, result);
+                }
+            }
+        });
+        assert_eq!(acked.await.unwrap(), (7, CommandResult::Done));
+        rc.abort();
+    }
+}
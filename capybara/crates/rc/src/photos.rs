@@ -0,0 +1,244 @@
+//! Sends photos in chunks, only a few of them unacked at a time, so that a
+//! photo trickles through the radio between video and telemetry instead of
+//! holding it up for seconds. Chunks are resent until the controller acks
+//! them, so transfers carry on after the link comes back.
+
+use anyhow::{bail, Result};
+use borsh::BorshSerialize;
+use log::*;
+use std::collections::VecDeque;
+use tokio::sync::{broadcast, mpsc};
+use tokio::time::{interval, Duration, Instant};
+
+use phototaker::PhotoEvent;
+use proto::{PacketToMaster, Photo, PhotoChunk, UplinkStream, MAX_PHOTO_SIZE, PHOTO_CHUNK_SIZE};
+
+use crate::uplink::Uplink;
+
+/// Chunks sent but not acked yet.
+const WINDOW: usize = 4;
+/// Long enough for a chunk and its ack to get through a busy radio.
+const RESEND_AFTER: Duration = Duration::from_secs(2);
+/// Photos kept until the controller got them, the oldest are given up.
+const MAX_TRANSFERS: usize = 16;
+
+struct Transfer {
+    id: u32,
+    chunks: Vec<Vec<u8>>,
+    acked: Vec<bool>,
+    sent_at: Vec<Option<Instant>>,
+}
+
+impl Transfer {
+    fn new(id: u32, photo: &Photo) -> Result<Self> {
+        let data = photo.try_to_vec()?;
+        if data.len() > MAX_PHOTO_SIZE {
+            bail!(
+                "photo {} is too large to send, {} bytes",
+                photo.id,
+                data.len()
+            );
+        }
+        let chunks: Vec<Vec<u8>> = data.chunks(PHOTO_CHUNK_SIZE).map(<[u8]>::to_vec).collect();
+        Ok(Self {
+            id,
+            acked: vec![false; chunks.len()],
+            sent_at: vec![None; chunks.len()],
+            chunks,
+        })
+    }
+
+    fn done(&self) -> bool {
+        self.acked.iter().all(|&a| a)
+    }
+
+    fn in_flight(&self, now: Instant) -> usize {
+        self.sent_at
+            .iter()
+            .zip(&self.acked)
+            .filter(|&(sent_at, acked)| !acked && sent_at.is_some_and(|t| now - t < RESEND_AFTER))
+            .count()
+    }
+
+    /// Index of a chunk never sent or sent too long ago.
+    fn due(&self, now: Instant) -> Option<usize> {
+        (0..self.chunks.len())
+            .find(|&i| !self.acked[i] && self.sent_at[i].is_none_or(|t| now - t >= RESEND_AFTER))
+    }
+}
+
+/// Photos waiting to get through, oldest first.
+#[derive(Default)]
+struct Outbox {
+    transfers: VecDeque<Transfer>,
+}
+
+impl Outbox {
+    fn push(&mut self, transfer: Transfer) {
+        if self.transfers.len() == MAX_TRANSFERS {
+            if let Some(dropped) = self.transfers.pop_front() {
+                warn!("giving up on photo transfer {}", dropped.id);
+            }
+        }
+        self.transfers.push_back(transfer);
+    }
+
+    fn ack(&mut self, transfer: u32, index: u32) {
+        let Some(t) = self.transfers.iter_mut().find(|t| t.id == transfer) else {
+            return;
+        };
+        if let Some(acked) = t.acked.get_mut(index as usize) {
+            *acked = true;
+        }
+        if t.done() {
+            info!("photo transfer {transfer} complete");
+            self.transfers.retain(|t| t.id != transfer);
+        }
+    }
+
+    /// Chunks to send now to keep the window full.
+    fn next_chunks(&mut self, now: Instant) -> Vec<PhotoChunk> {
+        let mut in_flight: usize = self.transfers.iter().map(|t| t.in_flight(now)).sum();
+        let mut chunks = Vec::new();
+        for t in &mut self.transfers {
+            while in_flight < WINDOW {
+                let Some(index) = t.due(now) else {
+                    break;
+                };
+                t.sent_at[index] = Some(now);
+                in_flight += 1;
+                chunks.push(PhotoChunk {
+                    transfer: t.id,
+                    index: index as u32,
+                    count: t.chunks.len() as u32,
+                    data: t.chunks[index].clone(),
+                });
+            }
+        }
+        chunks
+    }
+}
+
+/// Sends photos from the phototaker in chunks. `ack_rx` gets
+/// `(transfer, index)` of acked chunks.
+pub async fn run_photo_sender(
+    mut photo_rx: broadcast::Receiver<PhotoEvent>,
+    mut ack_rx: mpsc::Receiver<(u32, u32)>,
+    uplink: Uplink,
+) -> Result<()> {
+    // Ids of photos restart with the robot, transfers must not. The clock
+    // may be unset after a reboot, so they start anywhere.
+    let mut next_transfer: u32 = rand::random();
+    let mut outbox = Outbox::default();
+    let mut resend_interval = interval(Duration::from_millis(100));
+    loop {
+        tokio::select! {
+            event = photo_rx.recv() => match event {
+                Ok(PhotoEvent::Taken(photo)) => {
+                    let transfer = match Transfer::new(next_transfer, &photo) {
+                        Ok(transfer) => transfer,
+                        Err(e) => {
+                            error!("{e:#}");
+                            continue;
+                        }
+                    };
+                    info!(
+                        "sending photo {} as transfer {next_transfer} in {} chunks",
+                        photo.id,
+                        transfer.chunks.len()
+                    );
+                    next_transfer = next_transfer.wrapping_add(1);
+                    outbox.push(transfer);
+                }
+                Ok(PhotoEvent::Done { .. }) => {}
+                Err(broadcast::error::RecvError::Lagged(l)) => {
+                    error!("lagged for {l} photos");
+                }
+                Err(_) => return Ok(()),
+            },
+            Some((transfer, index)) = ack_rx.recv() => outbox.ack(transfer, index),
+            _ = resend_interval.tick() => {}
+        }
+        for chunk in outbox.next_chunks(Instant::now()) {
+            debug!(
+                "sending chunk {}/{} of transfer {}",
+                chunk.index + 1,
+                chunk.count,
+                chunk.transfer
+            );
+            let pkt = PacketToMaster::PhotoChunk(chunk);
+            uplink.send(UplinkStream::Photos, &pkt)?;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proto::{Odometry, PhotoFormat};
+
+    fn photo(size: usize) -> Photo {
+        Photo {
+            id: 1,
+            captured_at: 0,
+            pose: Odometry {
+                x: 0.0,
+                y: 0.0,
+                theta: 0.0,
+            },
+            width: 1,
+            height: 1,
+            format: PhotoFormat::Jpeg,
+            data: vec![7; size],
+        }
+    }
+
+    #[test]
+    fn transfer_chunks() {
+        let small = photo(3 * PHOTO_CHUNK_SIZE);
+        let t = Transfer::new(5, &small).unwrap();
+        assert_eq!(t.chunks.len(), 4);
+        assert_eq!(t.chunks.concat(), small.try_to_vec().unwrap());
+        assert!(Transfer::new(5, &photo(MAX_PHOTO_SIZE)).is_err());
+    }
+
+    #[test]
+    fn window_and_resends() {
+        let mut outbox = Outbox::default();
+        outbox.push(Transfer::new(1, &photo(2 * PHOTO_CHUNK_SIZE)).unwrap());
+        outbox.push(Transfer::new(2, &photo(2 * PHOTO_CHUNK_SIZE)).unwrap());
+        let start = Instant::now();
+        let sent = |chunks: Vec<PhotoChunk>| -> Vec<(u32, u32)> {
+            chunks.iter().map(|c| (c.transfer, c.index)).collect()
+        };
+
+        assert_eq!(
+            sent(outbox.next_chunks(start)),
+            [(1, 0), (1, 1), (1, 2), (2, 0)]
+        );
+        assert!(outbox.next_chunks(start).is_empty());
+        outbox.ack(1, 1);
+        outbox.ack(9, 0);
+        assert_eq!(sent(outbox.next_chunks(start)), [(2, 1)]);
+
+        let later = start + RESEND_AFTER;
+        outbox.ack(1, 0);
+        assert_eq!(
+            sent(outbox.next_chunks(later)),
+            [(1, 2), (2, 0), (2, 1), (2, 2)]
+        );
+        outbox.ack(1, 2);
+        assert_eq!(outbox.transfers.len(), 1);
+        assert_eq!(outbox.transfers[0].id, 2);
+    }
+
+    #[test]
+    fn oldest_transfer_is_given_up() {
+        let mut outbox = Outbox::default();
+        for id in 0..=MAX_TRANSFERS as u32 {
+            outbox.push(Transfer::new(id, &photo(1)).unwrap());
+        }
+        assert_eq!(outbox.transfers.len(), MAX_TRANSFERS);
+        assert_eq!(outbox.transfers[0].id, 1);
+    }
+}
@@ -0,0 +1,407 @@
+//! Sends what the robot has to say in order of importance. Every stream has
+//! its own queue, so when the link can't keep up video and photos wait or
+//! lose packets while telemetry still gets through. Streams with a share of
+//! the link go first within it, so that photos get through next to video.
+
+use anyhow::Result;
+use borsh::BorshSerialize;
+use std::collections::VecDeque;
+use tokio::sync::{broadcast, mpsc};
+use tokio::time::{interval, sleep, Duration, Instant};
+
+use common::config::{DropPolicy, StreamConfig, UplinkConfig};
+use proto::{PacketToMaster, StreamStats, UplinkStats, UplinkStream};
+
+const STATS_PERIOD: Duration = Duration::from_secs(1);
+/// How often a busy link is checked for room.
+const PACE: Duration = Duration::from_millis(10);
+/// Unused rate saved up, so that an idle stream can't burst for long.
+const BURST: Duration = Duration::from_millis(200);
+
+type Packets = mpsc::UnboundedReceiver<(UplinkStream, Vec<u8>)>;
+
+/// Hands packets to [`run_uplink`], cloned into every task that sends.
+#[derive(Clone)]
+pub struct Uplink {
+    tx: mpsc::UnboundedSender<(UplinkStream, Vec<u8>)>,
+}
+
+impl Uplink {
+    pub fn channel() -> (Self, Packets) {
+        let (tx, rx) = mpsc::unbounded_channel();
+        (Self { tx }, rx)
+    }
+
+    pub fn send(&self, stream: UplinkStream, pkt: &PacketToMaster) -> Result<()> {
+        let _ = self.tx.send((stream, pkt.try_to_vec()?));
+        Ok(())
+    }
+}
+
+/// Bytes per second. Goes negative to let through packets bigger than what
+/// it holds, which then delays the next ones.
+struct TokenBucket {
+    rate: f64,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: u32, now: Instant) -> Self {
+        Self {
+            rate: rate as f64,
+            tokens: 0.0,
+            updated: now,
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate * BURST.as_secs_f64());
+        self.updated = now;
+    }
+
+    fn ready(&self) -> bool {
+        self.tokens > 0.0
+    }
+
+    fn take(&mut self, bytes: usize) {
+        self.tokens -= bytes as f64;
+    }
+}
+
+struct Queue {
+    stream: UplinkStream,
+    config: StreamConfig,
+    packets: VecDeque<Vec<u8>>,
+    budget: Option<TokenBucket>,
+    share: Option<TokenBucket>,
+    bytes: u32,
+    sent: u32,
+    dropped: u32,
+}
+
+impl Queue {
+    /// `rate` is the link's, for the share.
+    fn new(stream: UplinkStream, config: StreamConfig, rate: u32, now: Instant) -> Self {
+        Self {
+            stream,
+            budget: config.budget.map(|rate| TokenBucket::new(rate, now)),
+            share: config
+                .share
+                .map(|share| TokenBucket::new((rate as f64 * share).max(1.0) as u32, now)),
+            config,
+            packets: VecDeque::new(),
+            bytes: 0,
+            sent: 0,
+            dropped: 0,
+        }
+    }
+
+    fn push(&mut self, pkt: Vec<u8>) {
+        if self.packets.len() >= self.config.queue {
+            self.dropped += 1;
+            match self.config.drop {
+                DropPolicy::Oldest => {
+                    self.packets.pop_front();
+                }
+                DropPolicy::Newest => return,
+            }
+        }
+        self.packets.push_back(pkt);
+    }
+
+    /// Next packet, if the stream's budget allows it.
+    fn pop(&mut self, now: Instant) -> Option<Vec<u8>> {
+        if let Some(budget) = &mut self.budget {
+            budget.refill(now);
+            if !budget.ready() {
+                return None;
+            }
+        }
+        let pkt = self.packets.pop_front()?;
+        if let Some(budget) = &mut self.budget {
+            budget.take(pkt.len());
+        }
+        self.bytes = self.bytes.saturating_add(pkt.len() as u32);
+        self.sent += 1;
+        Some(pkt)
+    }
+
+    /// Next packet, if the stream has some of its share left. Only these
+    /// count against the share, what the stream gets of a spare link would
+    /// otherwise leave it in debt when the link fills up.
+    fn pop_share(&mut self, now: Instant) -> Option<Vec<u8>> {
+        let share = self.share.as_mut()?;
+        share.refill(now);
+        if !share.ready() {
+            return None;
+        }
+        let pkt = self.pop(now)?;
+        if let Some(share) = &mut self.share {
+            share.take(pkt.len());
+        }
+        Some(pkt)
+    }
+
+    /// Since the last call.
+    fn stats(&mut self) -> StreamStats {
+        let stats = StreamStats {
+            stream: self.stream,
+            bytes: self.bytes,
+            packets: self.sent,
+            dropped: self.dropped,
+            queued: self.packets.len() as u32,
+        };
+        self.bytes = 0;
+        self.sent = 0;
+        self.dropped = 0;
+        stats
+    }
+}
+
+/// Queues of all streams, in the order of [`UplinkStream`], which is the
+/// priority.
+struct Scheduler {
+    queues: Vec<Queue>,
+    link: TokenBucket,
+}
+
+impl Scheduler {
+    fn new(config: &UplinkConfig, now: Instant) -> Self {
+        let queues = UplinkStream::ALL
+            .into_iter()
+            .map(|stream| {
+                let stream_config = match stream {
+                    UplinkStream::Telemetry => &config.telemetry,
+                    UplinkStream::Acks => &config.acks,
+                    UplinkStream::Video => &config.video,
+                    UplinkStream::Photos => &config.photos,
+                };
+                Queue::new(stream, stream_config.clone(), config.rate, now)
+            })
+            .collect();
+        Self {
+            queues,
+            link: TokenBucket::new(config.rate, now),
+        }
+    }
+
+    fn push(&mut self, stream: UplinkStream, pkt: Vec<u8>) {
+        self.queues[stream as usize].push(pkt);
+    }
+
+    fn backlog(&self) -> bool {
+        self.queues.iter().any(|q| !q.packets.is_empty())
+    }
+
+    /// What fits on the link now, shares first and then by priority.
+    fn send(&mut self, now: Instant) -> Vec<Vec<u8>> {
+        let mut sent = Vec::new();
+        self.link.refill(now);
+        while self.link.ready() {
+            let pkt = match self.queues.iter_mut().find_map(|q| q.pop_share(now)) {
+                Some(pkt) => pkt,
+                None => match self.queues.iter_mut().find_map(|q| q.pop(now)) {
+                    Some(pkt) => pkt,
+                    None => break,
+                },
+            };
+            self.link.take(pkt.len());
+            sent.push(pkt);
+        }
+        sent
+    }
+
+    fn stats(&mut self, period_ms: u32) -> UplinkStats {
+        UplinkStats {
+            period_ms,
+            streams: self.queues.iter_mut().map(Queue::stats).collect(),
+        }
+    }
+}
+
+/// Forwards packets to the clients within the link rate and the stream
+/// budgets. Reports what it sent every second.
+pub async fn run_uplink(
+    config: UplinkConfig,
+    mut packet_rx: Packets,
+    up_tx: broadcast::Sender<Vec<u8>>,
+) -> Result<()> {
+    let now = Instant::now();
+    let mut scheduler = Scheduler::new(&config, now);
+    let mut stats_interval = interval(STATS_PERIOD);
+    let mut stats_since = now;
+    loop {
+        tokio::select! {
+            pkt = packet_rx.recv() => match pkt {
+                Some((stream, pkt)) => scheduler.push(stream, pkt),
+                None => return Ok(()),
+            },
+            _ = sleep(PACE), if scheduler.backlog() => {}
+            _ = stats_interval.tick() => {
+                let stats = scheduler.stats(stats_since.elapsed().as_millis() as u32);
+                stats_since = Instant::now();
+                let pkt = PacketToMaster::UplinkStats(stats);
+                scheduler.push(UplinkStream::Telemetry, pkt.try_to_vec()?);
+            }
+        }
+
+        for pkt in scheduler.send(Instant::now()) {
+            let _ = up_tx.send(pkt);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ms(ms: u64) -> Duration {
+        Duration::from_millis(ms)
+    }
+
+    fn queue(budget: Option<u32>, drop: DropPolicy, now: Instant) -> Queue {
+        let config = StreamConfig {
+            budget,
+            share: None,
+            queue: 2,
+            drop,
+        };
+        Queue::new(UplinkStream::Video, config, 1000, now)
+    }
+
+    #[test]
+    fn token_bucket() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(1000, start);
+        assert!(!bucket.ready());
+        bucket.refill(start + ms(100));
+        assert!(bucket.ready());
+        // A big packet goes through and holds up the next ones
+        bucket.take(300);
+        assert!(!bucket.ready());
+        bucket.refill(start + ms(300));
+        assert!(!bucket.ready());
+        bucket.refill(start + ms(301));
+        assert!(bucket.ready());
+        // Idle time saves up no more than a burst
+        bucket.refill(start + ms(10_000));
+        assert_eq!(bucket.tokens, 200.0);
+    }
+
+    #[test]
+    fn drop_policies() {
+        let now = Instant::now();
+        let mut oldest = queue(None, DropPolicy::Oldest, now);
+        let mut newest = queue(None, DropPolicy::Newest, now);
+        for pkt in 1..=3 {
+            oldest.push(vec![pkt]);
+            newest.push(vec![pkt]);
+        }
+        assert_eq!(oldest.packets, [vec![2], vec![3]]);
+        assert_eq!(newest.packets, [vec![1], vec![2]]);
+        assert_eq!(oldest.stats().dropped, 1);
+        assert_eq!(oldest.stats().dropped, 0);
+    }
+
+    #[test]
+    fn budget_paces_the_queue() {
+        let start = Instant::now();
+        let mut queue = queue(Some(1000), DropPolicy::Oldest, start);
+        queue.push(vec![0; 150]);
+        queue.push(vec![0; 150]);
+        assert_eq!(queue.pop(start), None);
+        assert_eq!(queue.pop(start + ms(100)).map(|p| p.len()), Some(150));
+        assert_eq!(queue.pop(start + ms(140)), None);
+        assert!(queue.pop(start + ms(160)).is_some());
+        assert_eq!(queue.pop(start + ms(1000)), None);
+
+        let stats = queue.stats();
+        assert_eq!((stats.packets, stats.bytes, stats.queued), (2, 300, 0));
+    }
+
+    #[test]
+    fn photos_get_through_saturated_video() {
+        let start = Instant::now();
+        let config = UplinkConfig {
+            rate: 5000,
+            ..UplinkConfig::default()
+        };
+        let mut scheduler = Scheduler::new(&config, start);
+        let mut photo_bytes = 0;
+        let mut video_bytes = 0;
+        for step in 1..=1000 {
+            // Video alone would fill the link twice over, photo chunks are
+            // always waiting too
+            scheduler.push(UplinkStream::Video, vec![0; 100]);
+            scheduler.push(UplinkStream::Video, vec![0; 100]);
+            scheduler.push(UplinkStream::Photos, vec![1; 100]);
+            for pkt in scheduler.send(start + ms(step * 10)) {
+                match pkt[0] {
+                    1 => photo_bytes += pkt.len(),
+                    _ => video_bytes += pkt.len(),
+                }
+            }
+        }
+
+        // 10 seconds at 5000 bytes per second, a fifth of it for photos
+        assert!((9_000..=11_000).contains(&photo_bytes), "{photo_bytes}");
+        assert!((38_000..=41_000).contains(&video_bytes), "{video_bytes}");
+        let stats = scheduler.stats(10_000);
+        assert!(stats.streams[UplinkStream::Video as usize].dropped > 0);
+    }
+
+    #[test]
+    fn shares_leave_the_rest_to_priority() {
+        let start = Instant::now();
+        let config = UplinkConfig {
+            rate: 5000,
+            ..UplinkConfig::default()
+        };
+        let mut scheduler = Scheduler::new(&config, start);
+        // Video is idle, so photos can use the whole link
+        let mut photo_bytes = 0;
+        for step in 1..=100 {
+            scheduler.push(UplinkStream::Photos, vec![1; 100]);
+            photo_bytes += scheduler
+                .send(start + ms(step * 10))
+                .iter()
+                .map(Vec::len)
+                .sum::<usize>();
+        }
+        assert!(photo_bytes > 4_500, "{photo_bytes}");
+    }
+
+    #[test]
+    fn idle_link_leaves_the_share_intact() {
+        let start = Instant::now();
+        let config = UplinkConfig {
+            rate: 5000,
+            ..UplinkConfig::default()
+        };
+        let mut scheduler = Scheduler::new(&config, start);
+        let mut step = 0;
+        let mut send = |scheduler: &mut Scheduler, video: bool| {
+            step += 1;
+            if video {
+                scheduler.push(UplinkStream::Video, vec![0; 100]);
+                scheduler.push(UplinkStream::Video, vec![0; 100]);
+            }
+            scheduler.push(UplinkStream::Photos, vec![1; 100]);
+            scheduler
+                .send(start + ms(step * 10))
+                .iter()
+                .filter(|pkt| pkt[0] == 1)
+                .map(Vec::len)
+                .sum::<usize>()
+        };
+        // Photos alone use the whole link for 100 seconds
+        let alone: usize = (0..10_000).map(|_| send(&mut scheduler, false)).sum();
+        assert!(alone > 450_000, "{alone}");
+
+        // Then video fills it, photos keep their fifth from the first second
+        let next_second: usize = (0..100).map(|_| send(&mut scheduler, true)).sum();
+        assert!((800..=1_300).contains(&next_second), "{next_second}");
+    }
+}
@@ -0,0 +1,130 @@
+//! `rchost calibrate`: the operator moves the servo from the terminal, marks
+//! the open, closed and limit positions and saves them to the calibration
+//! file.
+
+use anyhow::{bail, Result};
+use std::io::Write;
+use tokio::io::{stdin, AsyncBufReadExt, BufReader};
+use tokio::sync::{broadcast, mpsc};
+use tokio::task::spawn;
+
+use common::config::{Config, ServoCalibration};
+use muskrat::{run_arm, run_muskrat};
+use sim::VirtualServo;
+
+/// Typical servo pulse range, protects against typos.
+const PULSE_MIN: f64 = 500.0;
+const PULSE_MAX: f64 = 2500.0;
+/// Move of a bare `+` or `-`.
+const JOG_STEP: f64 = 10.0;
+
+const HELP: &str = "\
+<us>          move to this pulse width
++[us], -[us]  move by this much, 10 if omitted
+open, closed  mark the current position
+min, max      mark the current position as a limit
+show          print the calibration
+save          write the calibration file
+quit";
+
+pub async fn calibrate(config: Config) -> Result<()> {
+    let (set_raw_angle_tx, set_raw_angle_rx) = mpsc::channel::<f64>(1);
+    let (button_tx, _) = broadcast::channel(1);
+    let mut arm = if config.sim.enabled {
+        spawn(run_arm(
+            VirtualServo::new(&config.sim),
+            set_raw_angle_rx,
+            button_tx,
+        ))
+    } else {
+        spawn(run_muskrat(config.muskrat, set_raw_angle_rx, button_tx))
+    };
+
+    let path = config.servo.calibration_file;
+    let mut calibration = match ServoCalibration::load(&path) {
+        Ok(calibration) => calibration,
+        Err(e) => {
+            println!("starting from the defaults, {e:#}");
+            ServoCalibration::default()
+        }
+    };
+    let mut pulse = calibration
+        .to_raw(config.servo.initial_position)
+        .clamp(PULSE_MIN, PULSE_MAX);
+    let mut lines = BufReader::new(stdin()).lines();
+    println!("calibrating the servo for {}\n{HELP}", path.display());
+    loop {
+        if set_raw_angle_tx.send(pulse).await.is_err() {
+            bail!("servo is not running");
+        }
+        print!("{pulse:.0} us> ");
+        std::io::stdout().flush()?;
+        let line = tokio::select! {
+            line = lines.next_line() => line?,
+            res = &mut arm => {
+                res??;
+                bail!("servo is not running");
+            }
+        };
+        let Some(line) = line else {
+            return Ok(());
+        };
+        match line.trim() {
+            "" => {}
+            "open" => calibration.open = pulse,
+            "closed" => calibration.closed = pulse,
+            "min" => calibration.min = pulse,
+            "max" => calibration.max = pulse,
+            "show" => println!("{calibration:?}"),
+            "save" => match calibration.save(&path) {
+                Ok(()) => println!("saved {}", path.display()),
+                Err(e) => println!("not saved: {e:#}"),
+            },
+            "quit" | "q" => return Ok(()),
+            cmd => match parse_move(cmd, pulse) {
+                Some(p) => pulse = p.clamp(PULSE_MIN, PULSE_MAX),
+                None => println!("{HELP}"),
+            },
+        }
+    }
+}
+
+/// Absolute pulse width or a `+`/`-` jog.
+fn parse_move(cmd: &str, pulse: f64) -> Option<f64> {
+    let number = |s: &str| s.trim().parse::<f64>().ok().filter(|n| n.is_finite());
+    let jog = |rest: &str| match rest {
+        "" => Some(JOG_STEP),
+        n => number(n),
+    };
+    if let Some(rest) = cmd.strip_prefix('+') {
+        jog(rest).map(|d| pulse + d)
+    } else if let Some(rest) = cmd.strip_prefix('-') {
+        jog(rest).map(|d| pulse - d)
+    } else {
+        number(cmd)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn moves() {
+        assert_eq!(parse_move("1500", 2000.0), Some(1500.0));
+        assert_eq!(parse_move(" 1500.5 ", 2000.0), Some(1500.5));
+        assert_eq!(parse_move("+", 2000.0), Some(2010.0));
+        assert_eq!(parse_move("-", 2000.0), Some(1990.0));
+        assert_eq!(parse_move("+25", 2000.0), Some(2025.0));
+        assert_eq!(parse_move("- 25", 2000.0), Some(1975.0));
+    }
+
+    #[test]
+    fn not_moves() {
+        assert_eq!(parse_move("opne", 2000.0), None);
+        assert_eq!(parse_move("+x", 2000.0), None);
+        assert_eq!(parse_move("inf", 2000.0), None);
+        assert_eq!(parse_move("NaN", 2000.0), None);
+        assert_eq!(parse_move("+nan", 2000.0), None);
+    }
+}
@@ -1,25 +1,63 @@
 use anyhow::Result;
-use image::RgbImage;
+use clap::{Parser, Subcommand};
+use log::*;
 use tokio::sync::{broadcast, mpsc, watch};
-use tokio::task::JoinSet;
 
-use camera::run_camera;
+use camera::{run_camera, run_camera_source};
+use common::backend::{run_drive, CameraFrame};
+use common::config::Args;
 use common::init_log;
-use common::wait_tasks;
-use muskrat::run_muskrat;
+use common::Tasks;
 use muskrat::servo::run_servo;
-use proto::Odometry;
-use rc::run_rc;
+use muskrat::{run_arm, run_muskrat};
+use proto::{Odometry, ServoPosition};
+use rc::{run_rc, stream_codecs, Robot};
+use recorder::run_recorder;
 use ros::run_ros;
+use sim::{SyntheticCamera, UnicycleDriveBase, VirtualServo};
 use ws::run_ws;
 
+use calibrate::calibrate;
+
+mod calibrate;
+
+#[derive(Parser, Debug)]
+struct Cli {
+    #[command(flatten)]
+    args: Args,
+    #[command(subcommand)]
+    mode: Option<Mode>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Mode {
+    /// Move the servo from the terminal and save its positions to the
+    /// calibration file
+    Calibrate,
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
     init_log();
+    let cli = Cli::parse();
+    // A broken calibration must not keep us from making a new one
+    if let Some(Mode::Calibrate) = cli.mode {
+        return calibrate(cli.args.load_without_calibration()?).await;
+    }
+    let config = cli.args.load()?;
 
     let (set_raw_angle_tx, set_raw_angle_rx) = mpsc::channel::<f64>(1);
-    let (angle_tx, angle_rx) = watch::channel(2390.0);
-    let (camera_tx, camera_rx) = watch::channel(RgbImage::new(640, 480));
+    let initial_position = config.servo.clamp(config.servo.initial_position);
+    let (angle_tx, angle_rx) = watch::channel(initial_position);
+    let (servo_tx, servo_rx) = watch::channel(ServoPosition {
+        position: config.servo.clamp(config.servo.start_position),
+        target: initial_position,
+    });
+    let (camera_tx, camera_rx) = watch::channel(CameraFrame::blank(
+        config.camera.width,
+        config.camera.height,
+    ));
+    let (still_tx, still_rx) = mpsc::channel(1);
     let (button_tx, _) = broadcast::channel(1);
 
     let (up_tx, _) = broadcast::channel(32);
@@ -31,22 +69,82 @@ async fn main() -> Result<()> {
         theta: 0.0,
     });
     let (velocity_tx, velocity_rx) = broadcast::channel(1);
+    let (failsafe_tx, failsafe_rx) = watch::channel(false);
 
-    let mut tasks = JoinSet::<Result<()>>::new();
-    tasks.spawn(run_ros(odometry_tx, velocity_rx));
-    tasks.spawn(run_muskrat(set_raw_angle_rx, button_tx));
-    tasks.spawn(run_servo(angle_rx, set_raw_angle_tx));
-    tasks.spawn(run_ws(up_tx.clone(), down_tx));
-    tasks.spawn(run_camera(camera_tx));
-    tasks.spawn(run_rc(
-        down_rx,
-        up_tx,
+    let mut tasks = Tasks::default();
+    if config.sim.enabled {
+        info!("using simulated hardware");
+        let camera = SyntheticCamera::new(&config.camera, &config.sim, odometry_rx.clone());
+        tasks.spawn("camera", run_camera_source(camera, camera_tx, still_rx));
+        tasks.spawn(
+            "drive",
+            run_drive(
+                UnicycleDriveBase::new(odometry_tx),
+                config.ros.command_timeout(),
+                velocity_rx,
+                failsafe_tx,
+            ),
+        );
+        tasks.spawn(
+            "arm",
+            run_arm(VirtualServo::new(&config.sim), set_raw_angle_rx, button_tx),
+        );
+    } else {
+        tasks.spawn("camera", run_camera(config.camera, camera_tx, still_rx));
+        tasks.spawn(
+            "ros",
+            run_ros(config.ros, odometry_tx, velocity_rx, failsafe_tx),
+        );
+        tasks.spawn(
+            "muskrat",
+            run_muskrat(config.muskrat, set_raw_angle_rx, button_tx),
+        );
+    }
+    tasks.spawn(
+        "servo",
+        run_servo(config.servo.clone(), angle_rx, set_raw_angle_tx, servo_tx),
+    );
+    let codecs = stream_codecs(&config.video)?;
+    let (codecs_tx, codecs_rx) = watch::channel(Vec::new());
+    if config.record.enabled {
+        tasks.spawn(
+            "recorder",
+            run_recorder(
+                config.record,
+                codecs[0],
+                up_tx.subscribe(),
+                down_tx.subscribe(),
+            ),
+        );
+    }
+    tasks.spawn(
+        "ws",
+        run_ws(config.ws, codecs, codecs_tx, up_tx.clone(), down_tx),
+    );
+    let robot = Robot {
         angle_tx,
+        servo_rx,
         velocity_tx,
         odometry_rx,
         camera_rx,
-    ));
+        still_tx,
+        failsafe_rx,
+        tasks_rx: tasks.status(),
+    };
+    tasks.spawn(
+        "rc",
+        run_rc(
+            down_rx,
+            up_tx,
+            codecs_rx,
+            robot,
+            config.servo,
+            config.video,
+            config.uplink,
+            config.health,
+        ),
+    );
 
-    wait_tasks(tasks).await;
+    tasks.wait().await;
     Ok(())
 }
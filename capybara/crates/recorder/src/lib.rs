@@ -0,0 +1,222 @@
+//! Recordings of everything passing between the robot and its clients, so a
+//! run can be replayed without the robot.
+
+use anyhow::{bail, Context, Result};
+use borsh::{BorshDeserialize, BorshSerialize};
+use log::*;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+use std::time::Duration;
+use tokio::io::{AsyncWriteExt, BufWriter};
+use tokio::sync::broadcast;
+use tokio::time::interval;
+
+use common::config::RecordConfig;
+use proto::{now_millis, VideoCodec, PROTOCOL_VERSION};
+
+/// First bytes of a recording file.
+pub const MAGIC: [u8; 4] = *b"CPRC";
+
+/// Unflushed records are lost if the robot loses power.
+const FLUSH_PERIOD: Duration = Duration::from_secs(1);
+
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone)]
+pub struct Header {
+    pub magic: [u8; 4],
+    /// Packets are only readable by the same protocol version.
+    pub version: u16,
+    pub codec: VideoCodec,
+    pub started_at: u64,
+}
+
+#[derive(BorshSerialize, BorshDeserialize, PartialEq, Debug, Clone, Copy)]
+pub enum Direction {
+    /// Serialized [`proto::PacketToMaster`].
+    Up,
+    /// Serialized [`proto::PacketToSlave`].
+    Down,
+}
+
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone)]
+pub struct Record {
+    /// Milliseconds since unix epoch.
+    pub timestamp: u64,
+    pub direction: Direction,
+    pub packet: Vec<u8>,
+}
+
+/// Writes every packet on `up_rx` and `down_rx` to a new file in the
+/// configured directory.
+pub async fn run_recorder(
+    config: RecordConfig,
+    codec: VideoCodec,
+    mut up_rx: broadcast::Receiver<Vec<u8>>,
+    mut down_rx: broadcast::Receiver<Vec<u8>>,
+) -> Result<()> {
+    tokio::fs::create_dir_all(&config.dir).await?;
+    let started_at = now_millis();
+    let path = config.dir.join(format!("session-{started_at}.rec"));
+    let file = tokio::fs::File::create(&path)
+        .await
+        .with_context(|| format!("can't create recording {}", path.display()))?;
+    info!("recording to {}", path.display());
+
+    let mut writer = BufWriter::new(file);
+    let header = Header {
+        magic: MAGIC,
+        version: PROTOCOL_VERSION,
+        codec,
+        started_at,
+    };
+    writer.write_all(&header.try_to_vec()?).await?;
+
+    let mut flush_interval = interval(FLUSH_PERIOD);
+    loop {
+        let (direction, received) = tokio::select! {
+            up = up_rx.recv() => (Direction::Up, up),
+            down = down_rx.recv() => (Direction::Down, down),
+            _ = flush_interval.tick() => {
+                writer.flush().await?;
+                continue;
+            }
+        };
+        let packet = match received {
+            Ok(p) => p,
+            Err(broadcast::error::RecvError::Lagged(l)) => {
+                error!("recorder lagged for {l} packets");
+                continue;
+            }
+            Err(_) => break,
+        };
+        let record = Record {
+            timestamp: now_millis(),
+            direction,
+            packet,
+        };
+        writer.write_all(&record.try_to_vec()?).await?;
+    }
+    writer.flush().await?;
+    Ok(())
+}
+
+/// Reads a recording back. Blocking, so better used from a dedicated thread.
+pub struct Recording {
+    pub header: Header,
+    reader: BufReader<File>,
+}
+
+impl Recording {
+    pub fn open(path: &Path) -> Result<Self> {
+        let file =
+            File::open(path).with_context(|| format!("can't open recording {}", path.display()))?;
+        let mut reader = BufReader::new(file);
+        let header = Header::deserialize_reader(&mut reader)
+            .with_context(|| format!("{} is not a recording", path.display()))?;
+        if header.magic != MAGIC {
+            bail!("{} is not a recording", path.display());
+        }
+        if header.version != PROTOCOL_VERSION {
+            bail!(
+                "{} was recorded with protocol v{}, we speak v{PROTOCOL_VERSION}",
+                path.display(),
+                header.version
+            );
+        }
+        Ok(Self { header, reader })
+    }
+
+    /// Returns `None` at the end of the recording.
+    pub fn next_record(&mut self) -> Result<Option<Record>> {
+        if self.reader.fill_buf()?.is_empty() {
+            return Ok(None);
+        }
+        match Record::deserialize_reader(&mut self.reader) {
+            Ok(record) => Ok(Some(record)),
+            // Recorder was killed in the middle of a record. Depending on
+            // the field borsh reports that differently, but either way the
+            // file ends there.
+            Err(_) if self.reader.fill_buf()?.is_empty() => {
+                warn!("recording is truncated");
+                Ok(None)
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn write_recording(name: &str, records: &[Record], tail: &[u8]) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("{name}-{}.rec", std::process::id()));
+        let header = Header {
+            magic: MAGIC,
+            version: PROTOCOL_VERSION,
+            codec: VideoCodec::Mjpeg,
+            started_at: 1000,
+        };
+        let mut bytes = header.try_to_vec().unwrap();
+        for record in records {
+            bytes.extend(record.try_to_vec().unwrap());
+        }
+        bytes.extend(tail);
+        std::fs::write(&path, bytes).unwrap();
+        path
+    }
+
+    fn record(timestamp: u64, direction: Direction, packet: &[u8]) -> Record {
+        Record {
+            timestamp,
+            direction,
+            packet: packet.to_vec(),
+        }
+    }
+
+    #[test]
+    fn roundtrip() {
+        let records = [
+            record(1001, Direction::Down, &[1, 2, 3]),
+            record(1002, Direction::Up, &[]),
+        ];
+        let path = write_recording("roundtrip", &records, &[]);
+        let mut recording = Recording::open(&path).unwrap();
+        assert_eq!(recording.header.codec, VideoCodec::Mjpeg);
+        assert_eq!(recording.header.started_at, 1000);
+
+        for expected in &records {
+            let record = recording.next_record().unwrap().unwrap();
+            assert_eq!(record.timestamp, expected.timestamp);
+            assert_eq!(record.direction, expected.direction);
+            assert_eq!(record.packet, expected.packet);
+        }
+        assert!(recording.next_record().unwrap().is_none());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn truncated() {
+        let last = record(1002, Direction::Up, &[4, 5, 6, 7])
+            .try_to_vec()
+            .unwrap();
+        let path = write_recording(
+            "truncated",
+            &[record(1001, Direction::Down, &[1])],
+            &last[..last.len() - 2],
+        );
+        let mut recording = Recording::open(&path).unwrap();
+        assert_eq!(recording.next_record().unwrap().unwrap().timestamp, 1001);
+        assert!(recording.next_record().unwrap().is_none());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn not_a_recording() {
+        let path = std::env::temp_dir().join(format!("garbage-{}.rec", std::process::id()));
+        std::fs::write(&path, b"not a recording at all").unwrap();
+        assert!(Recording::open(&path).is_err());
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,154 @@
+//! Plays a session recorded by the robot back to control, which connects to
+//! it as if it was the robot.
+
+use anyhow::Result;
+use borsh::{BorshDeserialize, BorshSerialize};
+use clap::Parser;
+use log::*;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::Duration;
+use tokio::sync::{broadcast, mpsc, watch};
+use tokio::task::spawn_blocking;
+use tokio::time::{sleep_until, Instant};
+
+use common::config::WsConfig;
+use common::init_log;
+use proto::{Command, CommandResult, PacketToMaster, PacketToSlave};
+use recorder::{Direction, Record, Recording};
+use ws::run_ws;
+
+#[derive(Parser, Debug)]
+struct Args {
+    /// Recording made by rchost with `--record`
+    file: PathBuf,
+    /// Playback speed, 2 plays twice as fast
+    #[arg(long, default_value_t = 1.0, value_parser = positive)]
+    speed: f64,
+    /// Address for control to connect to
+    #[arg(long, env = "CAPYBARA_WS_BIND", default_value = "127.0.0.1:8264")]
+    bind: SocketAddr,
+}
+
+fn positive(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(v) if v.is_finite() && v > 0.0 => Ok(v),
+        Ok(_) => Err("must be a positive number".to_string()),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    init_log();
+    let args = Args::parse();
+
+    let mut recording = Recording::open(&args.file)?;
+    let codec = recording.header.codec;
+    info!(
+        "replaying {}, {codec} video, at {}x speed",
+        args.file.display(),
+        args.speed
+    );
+
+    // Read ahead on a separate thread, the file may be large
+    let (record_tx, mut record_rx) = mpsc::channel::<Record>(64);
+    let reader_task = spawn_blocking(move || -> Result<()> {
+        while let Some(record) = recording.next_record()? {
+            if record_tx.blocking_send(record).is_err() {
+                break;
+            }
+        }
+        Ok(())
+    });
+
+    let (up_tx, _) = broadcast::channel(32);
+    let (down_tx, down_rx) = broadcast::channel(32);
+    let config = WsConfig {
+        bind: args.bind,
+        token: None,
+        tls: None,
+    };
+    // Only the recorded codec can be replayed
+    let (codecs_tx, mut codecs_rx) = watch::channel(Vec::new());
+    tokio::spawn(run_ws(
+        config,
+        vec![codec],
+        codecs_tx,
+        up_tx.clone(),
+        down_tx,
+    ));
+    tokio::spawn(ack_commands(down_rx, up_tx.clone()));
+
+    info!("waiting for control to connect to {}", args.bind);
+    // Someone watches the video once connected
+    while codecs_rx.borrow_and_update().is_empty() {
+        codecs_rx.changed().await?;
+    }
+
+    let mut first: Option<(u64, Instant)> = None;
+    while let Some(record) = record_rx.recv().await {
+        let (first_timestamp, started) = *first.get_or_insert((record.timestamp, Instant::now()));
+        let offset = record.timestamp.saturating_sub(first_timestamp) as f64 / args.speed;
+        sleep_until(started + Duration::from_secs_f64(offset / 1000.0)).await;
+
+        match record.direction {
+            Direction::Up => {
+                // Acks and lease changes answered the recorded clients, not
+                // the connected one
+                match PacketToMaster::try_from_slice(&record.packet) {
+                    Ok(PacketToMaster::Ack { .. }) | Ok(PacketToMaster::ControlChanged { .. }) => {}
+                    Ok(_) => {
+                        let _ = up_tx.send(record.packet);
+                    }
+                    Err(e) => warn!("skipping malformed packet: {e}"),
+                }
+            }
+            Direction::Down => {
+                if let Ok(PacketToSlave { command, .. }) =
+                    PacketToSlave::try_from_slice(&record.packet)
+                {
+                    debug!("operator sent {command:?}");
+                    if matches!(command, Command::TakePhoto(_)) {
+                        info!("operator took a photo");
+                    }
+                }
+            }
+        }
+    }
+    reader_task.await??;
+    // Keep the connection, so control doesn't start reconnecting
+    info!("recording finished, Ctrl-C to quit");
+    tokio::signal::ctrl_c().await?;
+    Ok(())
+}
+
+/// Keeps control from retrying commands there is nobody to execute.
+async fn ack_commands(
+    mut down_rx: broadcast::Receiver<Vec<u8>>,
+    up_tx: broadcast::Sender<Vec<u8>>,
+) -> Result<()> {
+    loop {
+        let packet = match down_rx.recv().await {
+            Ok(p) => p,
+            Err(broadcast::error::RecvError::Lagged(l)) => {
+                error!("lagged for {l} commands");
+                continue;
+            }
+            Err(_) => return Ok(()),
+        };
+        let PacketToSlave { id, command } = match PacketToSlave::try_from_slice(&packet) {
+            Ok(p) => p,
+            Err(e) => {
+                warn!("skipping malformed command: {e}");
+                continue;
+            }
+        };
+        debug!("ignoring {command:?} during replay");
+        let ack = PacketToMaster::Ack {
+            id,
+            result: CommandResult::Done,
+        };
+        let _ = up_tx.send(ack.try_to_vec()?);
+    }
+}
@@ -1,80 +1,87 @@
 use anyhow::{bail, Result};
-use log::*;
 use proto::{Odometry, Velocity};
 use tokio::sync::{broadcast, watch};
-use tokio::task::{spawn, spawn_blocking};
+use tokio::task::spawn_blocking;
 
-pub async fn run_ros(
-    odometry_tx: watch::Sender<Odometry>,
-    mut velocity_rx_channel: broadcast::Receiver<Velocity>,
-) -> Result<()> {
-    match rosrust::try_init("capybara") {
-        Ok(_) => {}
-        Err(e) => bail!("failed to init ros: {e}"),
-    }
-
-    let velocity_pub = match rosrust::publish("cmd_vel", 1) {
-        Ok(p) => p,
-        Err(e) => bail!("can't create publisher to cmd_vel: {e}"),
-    };
-    let velocity_rate = rosrust::rate(10.0);
+use common::backend::{run_drive, DriveBase};
+use common::config::RosConfig;
 
-    let _odometry_subscriber = match rosrust::subscribe(
-        "odom_pose2d",
-        1,
-        move |v: rosrust_msg::geometry_msgs::Pose2D| {
-            let _ = odometry_tx.send(Odometry {
-                x: v.x,
-                y: v.y,
-                theta: v.theta,
-            });
-        },
-    ) {
-        Ok(s) => s,
-        Err(e) => bail!("can't create subscriber to odom_pose2d: {e}"),
-    };
+/// Drive base controlled through ROS: velocities are published to
+/// `cmd_vel_topic` and odometry is read from `odometry_topic`.
+pub struct RosDriveBase {
+    velocity_pub: rosrust::Publisher<rosrust_msg::geometry_msgs::Twist>,
+    _odometry_subscriber: rosrust::Subscriber,
+}
 
-    let (velocity_tx, velocity_rx) = watch::channel(Velocity {
-        linear: 0.0,
-        angular: 0.0,
-    });
-    spawn(async move {
-        loop {
-            match velocity_rx_channel.recv().await {
-                Ok(v) => {
-                    let _ = velocity_tx.send(v);
-                }
-                Err(broadcast::error::RecvError::Lagged(l)) => {
-                    warn!("lagged for {l} velocity");
-                    continue;
-                }
-                Err(broadcast::error::RecvError::Closed) => return,
-            }
+impl RosDriveBase {
+    pub fn new(config: &RosConfig, odometry_tx: watch::Sender<Odometry>) -> Result<Self> {
+        match rosrust::try_init(&config.node_name) {
+            Ok(_) => {}
+            Err(e) => bail!("failed to init ros: {e}"),
         }
-    });
 
-    spawn_blocking(move || {
-        while rosrust::is_ok() {
-            let velocity = (*velocity_rx.borrow()).clone();
-            let velocity_msg = rosrust_msg::geometry_msgs::Twist {
-                linear: rosrust_msg::geometry_msgs::Vector3 {
-                    x: velocity.linear,
-                    y: 0.0,
-                    z: 0.0,
-                },
-                angular: rosrust_msg::geometry_msgs::Vector3 {
-                    x: 0.0,
-                    y: 0.0,
-                    z: velocity.angular,
-                },
-            };
+        let velocity_pub = match rosrust::publish(&config.cmd_vel_topic, 1) {
+            Ok(p) => p,
+            Err(e) => bail!("can't create publisher to {}: {e}", config.cmd_vel_topic),
+        };
+
+        let _odometry_subscriber = match rosrust::subscribe(
+            &config.odometry_topic,
+            1,
+            move |v: rosrust_msg::geometry_msgs::Pose2D| {
+                let _ = odometry_tx.send(Odometry {
+                    x: v.x,
+                    y: v.y,
+                    theta: v.theta,
+                });
+            },
+        ) {
+            Ok(s) => s,
+            Err(e) => bail!("can't create subscriber to {}: {e}", config.odometry_topic),
+        };
+
+        Ok(Self {
+            velocity_pub,
+            _odometry_subscriber,
+        })
+    }
+}
+
+impl DriveBase for RosDriveBase {
+    /// Publishing blocks, so it runs off the async runtime.
+    async fn drive(&mut self, velocity: &Velocity) -> Result<()> {
+        let velocity_pub = self.velocity_pub.clone();
+        let velocity_msg = rosrust_msg::geometry_msgs::Twist {
+            linear: rosrust_msg::geometry_msgs::Vector3 {
+                x: velocity.linear,
+                y: 0.0,
+                z: 0.0,
+            },
+            angular: rosrust_msg::geometry_msgs::Vector3 {
+                x: 0.0,
+                y: 0.0,
+                z: velocity.angular,
+            },
+        };
+        spawn_blocking(move || {
+            if !rosrust::is_ok() {
+                bail!("ros is shut down");
+            }
             match velocity_pub.send(velocity_msg) {
-                Ok(_) => {}
+                Ok(_) => Ok(()),
                 Err(e) => bail!("can't send velocity: {e}"),
             }
-            velocity_rate.sleep();
-        }
-        Ok(())
-    })
-    .await?
+        })
+        .await?
+    }
+}
+
+pub async fn run_ros(
+    config: RosConfig,
+    odometry_tx: watch::Sender<Odometry>,
+    velocity_rx: broadcast::Receiver<Velocity>,
+    failsafe_tx: watch::Sender<bool>,
+) -> Result<()> {
+    let base = RosDriveBase::new(&config, odometry_tx)?;
+    run_drive(base, config.command_timeout(), velocity_rx, failsafe_tx).await
 }
@@ -0,0 +1,256 @@
+//! Simulated hardware backends for running the robot software on a laptop.
+
+use anyhow::Result;
+use image::{Rgb, RgbImage};
+use log::*;
+use std::f64::consts::PI;
+use std::time::{Duration, Instant};
+use tokio::sync::watch;
+
+use common::backend::{ArmActuator, CameraSource, DriveBase};
+use common::config::{CameraConfig, SimConfig};
+use proto::{Odometry, Velocity};
+
+/// Horizontal field of view of the fake camera.
+const FOV: f64 = 60.0 * PI / 180.0;
+const TARGET_RADIUS: f64 = 0.05;
+
+const SKY: Rgb<u8> = Rgb([120, 120, 130]);
+const FLOOR: Rgb<u8> = Rgb([70, 60, 50]);
+const TARGET: Rgb<u8> = Rgb([230, 200, 40]);
+
+/// Renders a coloured ball seen from the simulated robot pose.
+pub struct SyntheticCamera {
+    width: u32,
+    height: u32,
+    frame_time: Duration,
+    next_frame: Instant,
+    target: (f64, f64),
+    odometry_rx: watch::Receiver<Odometry>,
+}
+
+impl SyntheticCamera {
+    pub fn new(
+        camera: &CameraConfig,
+        sim: &SimConfig,
+        odometry_rx: watch::Receiver<Odometry>,
+    ) -> Self {
+        Self {
+            width: camera.width,
+            height: camera.height,
+            frame_time: Duration::from_secs_f64(1.0 / camera.fps as f64),
+            next_frame: Instant::now(),
+            target: (sim.target_x, sim.target_y),
+            odometry_rx,
+        }
+    }
+
+    fn render(&self, pose: &Odometry) -> RgbImage {
+        let (w, h) = (self.width as f64, self.height as f64);
+        let mut img = RgbImage::from_fn(self.width, self.height, |_, y| {
+            if y < self.height / 2 {
+                SKY
+            } else {
+                FLOOR
+            }
+        });
+
+        let (dx, dy) = (self.target.0 - pose.x, self.target.1 - pose.y);
+        let distance = dx.hypot(dy);
+        let bearing = normalize_angle(dy.atan2(dx) - pose.theta);
+        if distance < TARGET_RADIUS || bearing.abs() > FOV / 2.0 {
+            return img;
+        }
+
+        let focal = w / 2.0 / (FOV / 2.0).tan();
+        let cx = w / 2.0 - bearing.tan() * focal;
+        let cy = h / 2.0;
+        let r = TARGET_RADIUS * focal / distance;
+        let (x0, x1) = ((cx - r).max(0.0) as u32, ((cx + r) as u32).min(self.width));
+        let (y0, y1) = ((cy - r).max(0.0) as u32, ((cy + r) as u32).min(self.height));
+        for y in y0..y1 {
+            for x in x0..x1 {
+                if (x as f64 - cx).hypot(y as f64 - cy) <= r {
+                    img.put_pixel(x, y, TARGET);
+                }
+            }
+        }
+        img
+    }
+}
+
+impl CameraSource for SyntheticCamera {
+    fn capture(&mut self) -> Result<RgbImage> {
+        let now = Instant::now();
+        if self.next_frame > now {
+            std::thread::sleep(self.next_frame - now);
+        }
+        self.next_frame = self.next_frame.max(now) + self.frame_time;
+
+        let pose = (*self.odometry_rx.borrow()).clone();
+        Ok(self.render(&pose))
+    }
+}
+
+/// Differential drive robot as a kinematic unicycle with perfect odometry.
+pub struct UnicycleDriveBase {
+    pose: Odometry,
+    last_update: Instant,
+    odometry_tx: watch::Sender<Odometry>,
+}
+
+impl UnicycleDriveBase {
+    pub fn new(odometry_tx: watch::Sender<Odometry>) -> Self {
+        let pose = (*odometry_tx.borrow()).clone();
+        Self {
+            pose,
+            last_update: Instant::now(),
+            odometry_tx,
+        }
+    }
+}
+
+impl UnicycleDriveBase {
+    /// Moves for `dt` seconds at `velocity`.
+    fn step(&mut self, velocity: &Velocity, dt: f64) {
+        self.pose.x += velocity.linear * self.pose.theta.cos() * dt;
+        self.pose.y += velocity.linear * self.pose.theta.sin() * dt;
+        self.pose.theta = normalize_angle(self.pose.theta + velocity.angular * dt);
+        let _ = self.odometry_tx.send(self.pose.clone());
+    }
+}
+
+impl DriveBase for UnicycleDriveBase {
+    async fn drive(&mut self, velocity: &Velocity) -> Result<()> {
+        let dt = self.last_update.elapsed().as_secs_f64();
+        self.last_update = Instant::now();
+        self.step(velocity, dt);
+        Ok(())
+    }
+}
+
+/// Servo that reaches any angle instantly and a button pressed once after start.
+pub struct VirtualServo {
+    angle: Option<f64>,
+    press_at: Option<tokio::time::Instant>,
+}
+
+impl VirtualServo {
+    pub fn new(sim: &SimConfig) -> Self {
+        Self {
+            angle: None,
+            press_at: sim
+                .button_delay_ms
+                .map(|ms| tokio::time::Instant::now() + Duration::from_millis(ms)),
+        }
+    }
+}
+
+impl ArmActuator for VirtualServo {
+    async fn set_raw_angle(&mut self, angle: f64) -> Result<()> {
+        if self.angle != Some(angle) {
+            debug!("virtual servo at {angle}");
+            self.angle = Some(angle);
+        }
+        Ok(())
+    }
+
+    async fn button(&mut self) -> Result<()> {
+        match self.press_at {
+            Some(at) => {
+                tokio::time::sleep_until(at).await;
+                info!("virtual button pressed");
+                self.press_at = None;
+                Ok(())
+            }
+            None => std::future::pending().await,
+        }
+    }
+}
+
+fn normalize_angle(a: f64) -> f64 {
+    (a + PI).rem_euclid(2.0 * PI) - PI
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn origin() -> Odometry {
+        Odometry {
+            x: 0.0,
+            y: 0.0,
+            theta: 0.0,
+        }
+    }
+
+    fn assert_pose(odometry_rx: &watch::Receiver<Odometry>, x: f64, y: f64, theta: f64) {
+        let pose = odometry_rx.borrow();
+        assert!(
+            (pose.x - x).abs() < 1e-9 && (pose.y - y).abs() < 1e-9,
+            "at {pose:?}"
+        );
+        assert!((pose.theta - theta).abs() < 1e-9, "at {pose:?}");
+    }
+
+    #[test]
+    fn straight_line() {
+        let (odometry_tx, odometry_rx) = watch::channel(origin());
+        let mut drive = UnicycleDriveBase::new(odometry_tx);
+        let forward = Velocity {
+            linear: 0.5,
+            angular: 0.0,
+        };
+        drive.step(&forward, 2.0);
+        assert_pose(&odometry_rx, 1.0, 0.0, 0.0);
+        drive.step(&forward, 1.0);
+        assert_pose(&odometry_rx, 1.5, 0.0, 0.0);
+    }
+
+    #[test]
+    fn turn_in_place() {
+        let (odometry_tx, odometry_rx) = watch::channel(origin());
+        let mut drive = UnicycleDriveBase::new(odometry_tx);
+        let turn = Velocity {
+            linear: 0.0,
+            angular: PI / 4.0,
+        };
+        drive.step(&turn, 2.0);
+        assert_pose(&odometry_rx, 0.0, 0.0, PI / 2.0);
+        // Past half a turn the heading wraps around
+        drive.step(&turn, 3.0);
+        assert_pose(&odometry_rx, 0.0, 0.0, -3.0 * PI / 4.0);
+
+        drive.step(
+            &Velocity {
+                linear: 1.0,
+                angular: 0.0,
+            },
+            2f64.sqrt(),
+        );
+        assert_pose(&odometry_rx, -1.0, -1.0, -3.0 * PI / 4.0);
+    }
+
+    #[test]
+    fn camera_frame() {
+        let camera_config = CameraConfig {
+            width: 320,
+            height: 240,
+            ..CameraConfig::default()
+        };
+        let sim_config = SimConfig {
+            target_x: 1.0,
+            target_y: 0.0,
+            ..SimConfig::default()
+        };
+        let (_odometry_tx, odometry_rx) = watch::channel(origin());
+        let mut camera = SyntheticCamera::new(&camera_config, &sim_config, odometry_rx);
+
+        let frame = camera.capture().unwrap();
+        assert_eq!(frame.dimensions(), (320, 240));
+        // Straight ahead
+        assert_eq!(*frame.get_pixel(160, 120), TARGET);
+        assert_eq!(*frame.get_pixel(0, 0), SKY);
+        assert_eq!(*frame.get_pixel(0, 239), FLOOR);
+    }
+}
@@ -1,103 +1,560 @@
-use anyhow::Result;
+use anyhow::{bail, Result};
 use axum::{
     extract::{
         ws::{Message, WebSocket},
-        WebSocketUpgrade,
+        ConnectInfo, WebSocketUpgrade,
     },
-    response::IntoResponse,
+    http::{header::AUTHORIZATION, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
     routing::get,
     Extension, Router,
 };
-use futures::{SinkExt, StreamExt};
+use borsh::{BorshDeserialize, BorshSerialize};
+use futures::{SinkExt, Stream, StreamExt};
 use log::*;
+use std::collections::{HashMap, VecDeque};
 use std::net::SocketAddr;
-use std::sync::Arc;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
 use tokio::task::spawn;
-use tokio::{sync::broadcast, task::JoinHandle};
+use tokio::time::{interval, timeout, Duration};
+use tokio::{
+    sync::{broadcast, mpsc, watch},
+    task::JoinHandle,
+};
+
+use common::config::WsConfig;
+use proto::{Command, CommandResult, PacketToMaster, PacketToSlave};
+use proto::{Envelope, Framer, Hello, HelloAck, VideoCodec};
+
+mod tls;
+
+/// Commands remembered to route their acks back to the client.
+const RECENT_COMMANDS: usize = 1024;
+/// Clients answer pings even when they have nothing to send, so silence this
+/// long means the link is dead and the session's lease is given up.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(3);
+const PING_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Gives forwarded commands ids unique among all sessions, as every client
+/// numbers its commands from zero. A resent command keeps its id, so that the
+/// robot still recognizes the retransmission.
+#[derive(Default)]
+struct CommandIds {
+    next: u32,
+    /// `(id, session, client id)`, oldest first.
+    recent: VecDeque<(u32, u32, u32)>,
+}
+
+impl CommandIds {
+    fn forward(&mut self, session: u32, client_id: u32) -> u32 {
+        if let Some(&(id, _, _)) = self
+            .recent
+            .iter()
+            .find(|&&(_, s, c)| s == session && c == client_id)
+        {
+            return id;
+        }
+        let id = self.next;
+        self.next = self.next.wrapping_add(1);
+        if self.recent.len() == RECENT_COMMANDS {
+            self.recent.pop_front();
+        }
+        self.recent.push_back((id, session, client_id));
+        id
+    }
+
+    /// Session and client id of a forwarded command.
+    fn client(&self, id: u32) -> Option<(u32, u32)> {
+        self.recent
+            .iter()
+            .find(|&&(i, _, _)| i == id)
+            .map(|&(_, s, c)| (s, c))
+    }
+}
+
+/// Packet for every client, with the codec of video packets.
+type Broadcast = (Option<VideoCodec>, Vec<u8>);
+
+struct Session {
+    /// Packets meant for this client only.
+    direct_tx: mpsc::Sender<PacketToMaster>,
+    codec: VideoCodec,
+}
 
 struct ChannelsSpawner {
-    up_tx: broadcast::Sender<Vec<u8>>,
+    /// Packets for every client, acks are routed through `sessions` instead.
+    up_tx: broadcast::Sender<Broadcast>,
     down_tx: broadcast::Sender<Vec<u8>>,
+    /// Codecs the robot can stream in, the preferred one first.
+    codecs: Vec<VideoCodec>,
+    /// Codecs clients watch.
+    codecs_tx: watch::Sender<Vec<VideoCodec>>,
+    next_session: AtomicU32,
+    /// Session holding the controller lease.
+    controller: Mutex<Option<u32>>,
+    sessions: Mutex<HashMap<u32, Session>>,
+    commands: Mutex<CommandIds>,
 }
 
 impl ChannelsSpawner {
-    pub fn new(up_tx: broadcast::Sender<Vec<u8>>, down_tx: broadcast::Sender<Vec<u8>>) -> Self {
-        Self { up_tx, down_tx }
+    pub fn new(
+        up_tx: broadcast::Sender<Broadcast>,
+        down_tx: broadcast::Sender<Vec<u8>>,
+        codecs: Vec<VideoCodec>,
+        codecs_tx: watch::Sender<Vec<VideoCodec>>,
+    ) -> Self {
+        Self {
+            up_tx,
+            down_tx,
+            codecs,
+            codecs_tx,
+            next_session: AtomicU32::new(1),
+            controller: Mutex::new(None),
+            sessions: Mutex::new(HashMap::new()),
+            commands: Mutex::new(CommandIds::default()),
+        }
     }
 
-    pub fn get_up_rx(&self) -> broadcast::Receiver<Vec<u8>> {
+    pub fn get_up_rx(&self) -> broadcast::Receiver<Broadcast> {
         self.up_tx.subscribe()
     }
 
     pub fn get_down_tx(&self) -> broadcast::Sender<Vec<u8>> {
         self.down_tx.clone()
     }
+
+    pub fn new_session(&self) -> u32 {
+        self.next_session.fetch_add(1, Ordering::Relaxed)
+    }
+
+    /// Starts sending to a session that agreed on `codec`.
+    pub fn join(&self, session: u32, codec: VideoCodec, direct_tx: mpsc::Sender<PacketToMaster>) {
+        let mut sessions = self.sessions.lock().unwrap();
+        sessions.insert(session, Session { direct_tx, codec });
+        self.update_codecs(&sessions);
+    }
+
+    pub fn end_session(&self, session: u32) -> Result<()> {
+        {
+            let mut sessions = self.sessions.lock().unwrap();
+            sessions.remove(&session);
+            self.update_codecs(&sessions);
+        }
+        self.release_control(session)
+    }
+
+    fn update_codecs(&self, sessions: &HashMap<u32, Session>) {
+        let watched: Vec<VideoCodec> = self
+            .codecs
+            .iter()
+            .copied()
+            .filter(|&codec| sessions.values().any(|s| s.codec == codec))
+            .collect();
+        self.codecs_tx.send_if_modified(|codecs| {
+            let changed = *codecs != watched;
+            *codecs = watched;
+            changed
+        });
+    }
+
+    /// Id to forward the command of `session` with.
+    pub fn forward_id(&self, session: u32, id: u32) -> u32 {
+        self.commands.lock().unwrap().forward(session, id)
+    }
+
+    /// Sends the robot's ack to the session that sent the command.
+    pub fn route_ack(&self, id: u32, result: CommandResult) {
+        let Some((session, id)) = self.commands.lock().unwrap().client(id) else {
+            debug!("ack {id} matches no command");
+            return;
+        };
+        if let Some(s) = self.sessions.lock().unwrap().get(&session) {
+            // The client resends the command if the ack gets lost
+            let _ = s.direct_tx.try_send(PacketToMaster::Ack { id, result });
+        }
+    }
+
+    pub fn controller(&self) -> Option<u32> {
+        *self.controller.lock().unwrap()
+    }
+
+    /// Gives the lease to `session` unless another session holds it.
+    pub fn take_control(&self, session: u32) -> Result<bool> {
+        let mut controller = self.controller.lock().unwrap();
+        match *controller {
+            Some(s) if s == session => Ok(true),
+            Some(_) => Ok(false),
+            None => {
+                *controller = Some(session);
+                info!("session {session} took control");
+                self.notify(*controller)?;
+                Ok(true)
+            }
+        }
+    }
+
+    pub fn release_control(&self, session: u32) -> Result<()> {
+        let mut controller = self.controller.lock().unwrap();
+        if *controller == Some(session) {
+            *controller = None;
+            info!("session {session} released control");
+            self.notify(*controller)?;
+        }
+        Ok(())
+    }
+
+    fn notify(&self, controller: Option<u32>) -> Result<()> {
+        let pkt = PacketToMaster::ControlChanged { controller };
+        let _ = self.up_tx.send((None, pkt.try_to_vec()?));
+        Ok(())
+    }
 }
 
+/// Serves clients over websocket. `codecs` are the codecs of video packets in
+/// `send_tx`, the preferred one first. Every client gets the first one it can
+/// decode, those that can't decode any are turned away. Codecs clients
+/// watch are sent to `codecs_tx`, so that the others needn't be encoded.
 pub async fn run_ws(
+    config: WsConfig,
+    codecs: Vec<VideoCodec>,
+    codecs_tx: watch::Sender<Vec<VideoCodec>>,
     send_tx: broadcast::Sender<Vec<u8>>,
     receive_tx: broadcast::Sender<Vec<u8>>,
 ) -> Result<()> {
-    let channels_spawner = Arc::new(ChannelsSpawner::new(send_tx, receive_tx));
+    let (clients_tx, _) = broadcast::channel(32);
+    let channels_spawner = Arc::new(ChannelsSpawner::new(
+        clients_tx, receive_tx, codecs, codecs_tx,
+    ));
+    let router = spawn(route_uplink(send_tx.subscribe(), channels_spawner.clone()));
+    if config.token.is_none() {
+        warn!("no ws token configured, anyone on the network can connect");
+    } else if config.tls.is_none() {
+        warn!("ws token is sent in plain text, configure tls to keep it secret");
+    }
+    let auth = Arc::new(Auth {
+        token: config.token,
+    });
 
     let app = Router::new()
         .route("/", get(ws_handler))
-        .layer(Extension(channels_spawner));
+        .layer(Extension(channels_spawner))
+        .layer(Extension(auth));
+    let service = app.into_make_service_with_connect_info::<SocketAddr>();
 
-    let addr = SocketAddr::from(([0, 0, 0, 0], 8264));
-    axum::Server::bind(&addr)
-        .serve(app.into_make_service_with_connect_info::<SocketAddr>())
-        .await?;
+    match config.tls {
+        Some(tls) => {
+            let rustls = tls::load_or_generate(&tls).await?;
+            axum_server::bind_rustls(config.bind, rustls)
+                .serve(service)
+                .await?
+        }
+        None => axum::Server::bind(&config.bind).serve(service).await?,
+    }
 
+    router.abort();
     Ok(())
 }
 
+/// Passes packets from the robot on to every client, except acks, which go
+/// only to the session that sent the command.
+async fn route_uplink(
+    mut up_rx: broadcast::Receiver<Vec<u8>>,
+    channels_spawner: Arc<ChannelsSpawner>,
+) {
+    loop {
+        let data = match up_rx.recv().await {
+            Ok(d) => d,
+            Err(broadcast::error::RecvError::Lagged(l)) => {
+                error!("lagged for {l} packets from the robot");
+                continue;
+            }
+            Err(_) => return,
+        };
+        match PacketToMaster::try_from_slice(&data) {
+            Ok(PacketToMaster::Ack { id, result }) => channels_spawner.route_ack(id, result),
+            Ok(PacketToMaster::Video(frame)) => {
+                let _ = channels_spawner.up_tx.send((Some(frame.codec), data));
+            }
+            Ok(_) => {
+                let _ = channels_spawner.up_tx.send((None, data));
+            }
+            Err(e) => error!("dropping malformed packet from the robot: {e}"),
+        }
+    }
+}
+
+/// Bearer token clients have to present in the upgrade request.
+struct Auth {
+    token: Option<String>,
+}
+
+impl Auth {
+    fn check(&self, headers: &HeaderMap) -> bool {
+        let Some(token) = &self.token else {
+            return true;
+        };
+        match headers
+            .get(AUTHORIZATION)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.strip_prefix("Bearer "))
+        {
+            Some(presented) => constant_time_eq(presented.as_bytes(), token.as_bytes()),
+            None => false,
+        }
+    }
+}
+
+/// Doesn't leak how much of the token matched through timing.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
 async fn ws_handler(
     ws: WebSocketUpgrade,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    auth: Extension<Arc<Auth>>,
     channels_spawner: Extension<Arc<ChannelsSpawner>>,
-) -> impl IntoResponse {
+) -> Response {
+    if !auth.check(&headers) {
+        warn!("rejected client {addr}: bad or missing token");
+        return StatusCode::UNAUTHORIZED.into_response();
+    }
     ws.on_upgrade(move |socket| handle_socket(socket, channels_spawner))
 }
 
-async fn handle_socket(socket: WebSocket, channels_spawner: Extension<Arc<ChannelsSpawner>>) {
+async fn handle_socket(mut socket: WebSocket, channels_spawner: Extension<Arc<ChannelsSpawner>>) {
+    let session = channels_spawner.new_session();
+    let mut framer = Framer::default();
+    let codec = match handshake(&mut socket, &mut framer, session, &channels_spawner.codecs).await {
+        Ok((hello, codec)) => {
+            info!(
+                "client {} connected as session {session}, {codec} video",
+                hello.name
+            );
+            codec
+        }
+        Err(e) => {
+            warn!("handshake failed: {e}");
+            return;
+        }
+    };
+
+    let (direct_tx, mut direct_rx) = mpsc::channel::<PacketToMaster>(32);
     let mut up_rx = channels_spawner.get_up_rx();
+    channels_spawner.join(session, codec, direct_tx.clone());
     let down_tx = channels_spawner.get_down_tx();
+    let _ = direct_tx
+        .send(PacketToMaster::ControlChanged {
+            controller: channels_spawner.controller(),
+        })
+        .await;
 
-    let (mut sender, mut receiver) = socket.split();
+    let (mut sender, receiver) = socket.split();
 
-    let reader_task: JoinHandle<Result<()>> = spawn(async move {
-        while let Some(Ok(msg)) = receiver.next().await {
-            match msg {
-                Message::Close(_) => return Ok(()),
-                Message::Binary(bin) => {
-                    debug!("got from ws len = {}", bin.len());
-                    let _ = down_tx.send(bin);
-                }
-                Message::Text(t) => {
-                    info!("got message: {}", t);
-                }
-                _ => return Ok(()),
-            }
-        }
-        Ok(())
-    });
+    let reader_task: JoinHandle<Result<()>> = spawn(read_commands(
+        receiver,
+        session,
+        channels_spawner.0.clone(),
+        down_tx,
+        direct_tx,
+    ));
     let writer_task: JoinHandle<Result<()>> = spawn(async move {
+        let mut ping_interval = interval(PING_INTERVAL);
         loop {
-            let data = match up_rx.recv().await {
-                Ok(d) => d,
-                Err(broadcast::error::RecvError::Lagged(l)) => {
-                    error!("lagged for {l} packets");
+            let data = tokio::select! {
+                data = up_rx.recv() => match data {
+                    Ok((Some(c), _)) if c != codec => continue,
+                    Ok((_, d)) => d,
+                    Err(broadcast::error::RecvError::Lagged(l)) => {
+                        error!("lagged for {l} packets");
+                        continue;
+                    }
+                    Err(_) => return Ok(()),
+                },
+                Some(pkt) = direct_rx.recv() => pkt.try_to_vec()?,
+                _ = ping_interval.tick() => {
+                    if sender.send(Message::Ping(Vec::new())).await.is_err() {
+                        return Ok(());
+                    }
                     continue;
                 }
-                Err(_) => return Ok(()),
             };
             debug!("sending {} bytes to ws", data.len());
-            if sender.send(Message::Binary(data)).await.is_err() {
+            if sender
+                .send(Message::Binary(framer.frame(data)?))
+                .await
+                .is_err()
+            {
                 return Ok(());
             };
         }
     });
 
-    reader_task.await.unwrap().unwrap();
-    writer_task.await.unwrap().unwrap();
+    match reader_task.await {
+        Ok(Ok(())) => info!("session {session} disconnected"),
+        Ok(Err(e)) => error!("session {session} failed: {e}"),
+        Err(e) => error!("session {session} panicked: {e}"),
+    }
+    writer_task.abort();
+    if let Err(e) = channels_spawner.end_session(session) {
+        error!("failed to end session {session}: {e}");
+    }
+}
+
+/// Handles commands of `session` until the client disconnects or goes silent
+/// for [`IDLE_TIMEOUT`].
+async fn read_commands(
+    mut receiver: impl Stream<Item = Result<Message, axum::Error>> + Unpin,
+    session: u32,
+    sessions: Arc<ChannelsSpawner>,
+    down_tx: broadcast::Sender<Vec<u8>>,
+    direct_tx: mpsc::Sender<PacketToMaster>,
+) -> Result<()> {
+    loop {
+        let msg = match timeout(IDLE_TIMEOUT, receiver.next()).await {
+            Ok(Some(Ok(msg))) => msg,
+            Ok(_) => break,
+            Err(_) => bail!("nothing received for {IDLE_TIMEOUT:?}"),
+        };
+        match msg {
+            Message::Close(_) => break,
+            Message::Binary(bin) => {
+                debug!("got from ws len = {}", bin.len());
+                let decoded = Envelope::decode(&bin).and_then(|e| e.packet::<PacketToSlave>());
+                let PacketToSlave { id, command } = match decoded {
+                    Ok(d) => d,
+                    Err(e) => {
+                        error!("dropping session {session}: {e}");
+                        break;
+                    }
+                };
+                let result = match command {
+                    Command::TakeControl => match sessions.take_control(session)? {
+                        true => CommandResult::Done,
+                        false => CommandResult::Busy,
+                    },
+                    Command::ReleaseControl => {
+                        sessions.release_control(session)?;
+                        CommandResult::Done
+                    }
+                    command if sessions.controller() == Some(session) => {
+                        let id = match command {
+                            // Never answered, so there is no ack to route
+                            Command::AckPhotoChunk { .. } => id,
+                            _ => sessions.forward_id(session, id),
+                        };
+                        let _ = down_tx.send(PacketToSlave { id, command }.try_to_vec()?);
+                        continue;
+                    }
+                    _ => CommandResult::Failed("not in control".to_string()),
+                };
+                let _ = direct_tx.send(PacketToMaster::Ack { id, result }).await;
+            }
+            Message::Text(t) => {
+                info!("got message: {}", t);
+            }
+            // Answers to our pings, axum answers the client's by itself
+            Message::Ping(_) | Message::Pong(_) => {}
+        }
+    }
+    Ok(())
+}
+
+/// Our most preferred codec the client can decode.
+fn negotiate(ours: &[VideoCodec], theirs: &[VideoCodec]) -> Option<VideoCodec> {
+    ours.iter().copied().find(|codec| theirs.contains(codec))
+}
+
+/// Waits for the client's [`Hello`] and answers it with the codec picked
+/// from `codecs`, rejecting clients speaking another protocol version or
+/// unable to decode any of them.
+async fn handshake(
+    socket: &mut WebSocket,
+    framer: &mut Framer,
+    session: u32,
+    codecs: &[VideoCodec],
+) -> Result<(Hello, VideoCodec)> {
+    let bin = match socket.recv().await {
+        Some(Ok(Message::Binary(bin))) => bin,
+        Some(Ok(msg)) => bail!("expected hello, got {msg:?}"),
+        Some(Err(e)) => bail!(e),
+        None => bail!("connection closed before hello"),
+    };
+
+    let hello = Envelope::decode(&bin).and_then(|e| e.packet::<Hello>());
+    let codec = hello
+        .as_ref()
+        .ok()
+        .and_then(|h| negotiate(codecs, &h.codecs));
+    let ack = framer.frame_packet(&HelloAck {
+        accepted: codec.is_some(),
+        session,
+        codec: codec.unwrap_or(codecs[0]),
+    })?;
+    let _ = socket.send(Message::Binary(ack)).await;
+    if codec.is_none() {
+        let _ = socket.close().await;
+    }
+
+    let hello = hello?;
+    let Some(codec) = codec else {
+        bail!("client {} can't decode {codecs:?} video", hello.name);
+    };
+    Ok((hello, codec))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn command_ids() {
+        let mut ids = CommandIds::default();
+        let first = ids.forward(1, 0);
+        let other = ids.forward(2, 0);
+        assert_ne!(first, other);
+        // Retransmission
+        assert_eq!(ids.forward(1, 0), first);
+        assert_eq!(ids.client(first), Some((1, 0)));
+        assert_eq!(ids.client(other), Some((2, 0)));
+        assert_eq!(ids.client(other + 1), None);
+    }
+
+    #[test]
+    fn negotiation() {
+        use VideoCodec::*;
+        assert_eq!(negotiate(&[Av1, Mjpeg], &[Mjpeg, Av1]), Some(Av1));
+        assert_eq!(negotiate(&[Av1, Mjpeg], &[Mjpeg]), Some(Mjpeg));
+        assert_eq!(negotiate(&[Av1], &[Mjpeg]), None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn idle_session_frees_the_lease() {
+        let (up_tx, _) = broadcast::channel(8);
+        let (down_tx, _) = broadcast::channel(8);
+        let (codecs_tx, _) = watch::channel(Vec::new());
+        let sessions = Arc::new(ChannelsSpawner::new(
+            up_tx,
+            down_tx.clone(),
+            vec![VideoCodec::Mjpeg],
+            codecs_tx,
+        ));
+        let (direct_tx, _direct_rx) = mpsc::channel(8);
+        let dead = sessions.new_session();
+        sessions.join(dead, VideoCodec::Mjpeg, direct_tx.clone());
+        assert!(sessions.take_control(dead).unwrap());
+
+        // The link drops without closing the socket
+        let silent = futures::stream::pending();
+        let read = read_commands(silent, dead, sessions.clone(), down_tx, direct_tx.clone());
+        assert!(timeout(IDLE_TIMEOUT * 2, read).await.unwrap().is_err());
+        sessions.end_session(dead).unwrap();
+
+        let reconnected = sessions.new_session();
+        sessions.join(reconnected, VideoCodec::Mjpeg, direct_tx);
+        assert!(sessions.take_control(reconnected).unwrap());
+        assert_eq!(sessions.controller(), Some(reconnected));
+    }
 }
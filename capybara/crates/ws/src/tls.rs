@@ -0,0 +1,40 @@
+use anyhow::{Context, Result};
+use axum_server::tls_rustls::RustlsConfig;
+use log::*;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+
+use common::config::TlsConfig;
+
+/// Loads the certificate and key, generating a self-signed pair on first run.
+pub async fn load_or_generate(config: &TlsConfig) -> Result<RustlsConfig> {
+    if !config.cert.exists() {
+        generate(config)?;
+    }
+    RustlsConfig::from_pem_file(&config.cert, &config.key)
+        .await
+        .with_context(|| format!("can't load certificate {}", config.cert.display()))
+}
+
+fn generate(config: &TlsConfig) -> Result<()> {
+    let cert = rcgen::generate_simple_self_signed(config.names.clone())?;
+
+    OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(&config.key)
+        .and_then(|mut f| f.write_all(cert.serialize_private_key_pem().as_bytes()))
+        .with_context(|| format!("can't write key {}", config.key.display()))?;
+    fs::write(&config.cert, cert.serialize_pem()?)
+        .with_context(|| format!("can't write certificate {}", config.cert.display()))?;
+
+    info!(
+        "generated self-signed certificate {} for {:?}, copy it to the control laptop",
+        config.cert.display(),
+        config.names
+    );
+    Ok(())
+}
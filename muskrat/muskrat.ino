@@ -1,35 +1,100 @@
 #define SERVO_PIN 44
 #define BUTTON_PIN A8
 
+// Frame: START, command, payload length, payload..., crc8 of everything
+// between START and crc. Must match capybara/crates/muskrat/src/protocol.rs
+#define START 0xAA
+#define CMD_SET_SERVO 0x01
+#define CMD_BUTTON 0x02
+#define MAX_PAYLOAD 8
+
 #include <Servo.h>
 
 Servo claw;
 
+byte frame[MAX_PAYLOAD + 4];
+byte frameLen = 0;
+
 void setup() {
   pinMode(BUTTON_PIN, INPUT_PULLUP);
   claw.attach(SERVO_PIN);
   Serial.begin(115200);
 }
 
-union ArrayToInteger {
-  byte array[4];
-  uint32_t integer;
-};
+byte crc8(const byte *data, byte len) {
+  byte crc = 0;
+  for (byte i = 0; i < len; i++) {
+    crc ^= data[i];
+    for (byte j = 0; j < 8; j++) {
+      crc = crc & 0x80 ? (crc << 1) ^ 0x07 : crc << 1;
+    }
+  }
+  return crc;
+}
+
+void sendFrame(byte cmd, const byte *payload, byte len) {
+  byte body[MAX_PAYLOAD + 2];
+  body[0] = cmd;
+  body[1] = len;
+  for (byte i = 0; i < len; i++) {
+    body[i + 2] = payload[i];
+  }
+  byte crc = crc8(body, len + 2);
+  Serial.write(START);
+  Serial.write(body, len + 2);
+  Serial.write(crc);
+}
+
+void handleFrame(byte cmd, const byte *payload, byte len) {
+  if (cmd == CMD_SET_SERVO && len == 2) {
+    uint16_t us = payload[0] | ((uint16_t)payload[1] << 8);
+    claw.writeMicroseconds(us);
+  }
+}
+
+// Drops the first byte of the buffer and restarts from the next START
+void resync() {
+  byte i = 1;
+  while (i < frameLen && frame[i] != START) {
+    i++;
+  }
+  memmove(frame, frame + i, frameLen - i);
+  frameLen -= i;
+}
+
+void readByte(byte b) {
+  if (frameLen == 0 && b != START) {
+    return;
+  }
+  frame[frameLen++] = b;
+
+  while (frameLen >= 3) {
+    byte len = frame[2];
+    if (len > MAX_PAYLOAD) {
+      resync();
+      continue;
+    }
+    if (frameLen < len + 4) {
+      return;
+    }
+    if (crc8(frame + 1, len + 2) == frame[len + 3]) {
+      handleFrame(frame[1], frame + 3, len);
+      frameLen = 0;
+      return;
+    }
+    resync();
+  }
+}
 
 void loop() {
   if (!digitalRead(BUTTON_PIN)) {
     while (!digitalRead(BUTTON_PIN)) {
       delay(100);
     }
-    Serial.write(0x07);
+    sendFrame(CMD_BUTTON, NULL, 0);
   }
 
-  if (Serial.available()) {
-    byte buf[5];
-    Serial.readBytes(buf, 5);
-    ArrayToInteger converter = {buf[1], buf[2], buf[3], buf[4]};
-    if (buf[0] == 0x03) {
-      claw.writeMicroseconds(converter.integer);
-    }
+  while (Serial.available()) {
+    readByte(Serial.read());
   }
 }